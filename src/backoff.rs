@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Jittered exponential backoff between reconnect attempts.
+///
+/// The n-th delay is drawn uniformly from `[d / 2, d]` where `d = min(base * 2^n, max)`,
+/// so clients that lost their connection at the same time do not retry in lockstep.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max: max.max(base),
+            attempt: 0,
+        }
+    }
+
+    /// Number of delays handed out since the last [Backoff::reset].
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Returns the delay before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .base
+            .checked_mul(2_u32.saturating_pow(self.attempt))
+            .map_or(self.max, |delay| delay.min(self.max));
+        self.attempt = self.attempt.saturating_add(1);
+
+        rand::thread_rng().gen_range(delay / 2..=delay)
+    }
+
+    /// Starts over from the base delay, e.g. after a successful connection.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let base = Duration::from_millis(500);
+        let max = Duration::from_secs(10);
+        let mut backoff = Backoff::new(base, max);
+
+        for n in 0..64 {
+            let cap = (base * 2_u32.saturating_pow(n.min(31))).min(max);
+            let delay = backoff.next_delay();
+            assert!(
+                delay >= cap / 2 && delay <= cap,
+                "{delay:?} not within {cap:?}"
+            );
+        }
+        assert_eq!(backoff.attempt(), 64);
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= base);
+    }
+}
@@ -1,12 +1,17 @@
 use std::io;
 use std::path::Path;
 use std::str::FromStr;
-use std::{net::IpAddr, path::PathBuf};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+};
 
 use anyhow::{self, Context};
-use serde::{de, Deserialize, Deserializer};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use url::Url;
 
+use crate::core::{self, Identity};
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Conf {
     pub network: Network,
@@ -18,6 +23,13 @@ impl Conf {
         let conf = std::fs::read_to_string(path).with_context(|| "failed to read config file")?;
         let mut conf = Self::parse_from(&conf).with_context(|| "failed to parse config file")?;
         conf.tls.update_relative_paths(path)?;
+        if let Network::Server {
+            lease_file: Some(lease_file),
+            ..
+        } = &mut conf.network
+        {
+            Tls::update_relative_path(path, lease_file)?;
+        }
 
         Ok(conf)
     }
@@ -25,20 +37,111 @@ impl Conf {
     pub fn parse_from(s: &str) -> Result<Self, toml::de::Error> {
         toml::from_str(s)
     }
+
+    /// Reads the configuration of every tunnel at `path`, which is either a single
+    /// configuration file or a directory with one `*.toml` file per tunnel.
+    pub fn read_all(path: &Path) -> anyhow::Result<Vec<(PathBuf, Self)>> {
+        let paths = if path.is_dir() {
+            let mut paths = std::fs::read_dir(path)
+                .with_context(|| format!("failed to read {}", path.display()))?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .filter(|path| match path {
+                    Ok(path) => path.extension().is_some_and(|ext| ext == "toml"),
+                    Err(_) => true,
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            paths.sort();
+            anyhow::ensure!(!paths.is_empty(), "no *.toml files in {}", path.display());
+            paths
+        } else {
+            vec![path.to_path_buf()]
+        };
+
+        let confs = paths
+            .into_iter()
+            .map(|path| {
+                let conf = Self::read(&path).with_context(|| path.display().to_string())?;
+                Ok((path, conf))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        check_tunnels(confs.iter().map(|(_, conf)| conf))?;
+
+        Ok(confs)
+    }
+}
+
+/// Checks that tunnels running side by side use distinct interfaces, firewall marks,
+/// routing tables and policy rules.
+pub fn check_tunnels<'a>(confs: impl IntoIterator<Item = &'a Conf>) -> anyhow::Result<()> {
+    let networks: Vec<_> = confs.into_iter().map(|conf| &conf.network).collect();
+
+    for (i, a) in networks.iter().enumerate() {
+        for b in &networks[i + 1..] {
+            let name = a.name().unwrap_or(DEFAULT_TUN_NAME);
+            let other = b.name().unwrap_or(DEFAULT_TUN_NAME);
+            anyhow::ensure!(name != other, "{name} is configured more than once");
+            anyhow::ensure!(
+                a.fwmark() != b.fwmark(),
+                "{name} and {other} use the same fwmark {}",
+                a.fwmark()
+            );
+            anyhow::ensure!(
+                a.table() != b.table(),
+                "{name} and {other} use the same routing table {}",
+                a.table()
+            );
+            // each tunnel installs rules at its priority and the one after it
+            anyhow::ensure!(
+                a.rule_priority().abs_diff(b.rule_priority()) > 1,
+                "the rule priorities of {name} and {other} overlap"
+            );
+        }
+    }
+
+    Ok(())
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Tls {
     pub key: PathBuf,
-    pub cert: PathBuf,
-    pub ca_cert: PathBuf,
+    /// Certificate chain presented to peers. Without it, a self-signed certificate is
+    /// made from `key`, which peers can only recognize by its key fingerprint.
+    pub cert: Option<PathBuf>,
+    /// Certificate authority peers' certificates must be signed by. Without it, peers are
+    /// authenticated by their key fingerprint alone: a server only accepts clients listed
+    /// by `client_cert` or an `spki-sha256:` `identity`, and a client needs the server's
+    /// `pin`.
+    pub ca_cert: Option<PathBuf>,
+    /// File holding the passphrase of an encrypted `key`.
+    #[serde(default)]
+    pub key_passphrase_file: Option<PathBuf>,
+    /// Days before the expiry of a configured certificate to warn about it, by default
+    /// 30, 7 and 1.
+    pub expiry_warn_days: Option<Vec<u32>>,
+    /// Certificate revocation lists, in PEM or DER, that a server checks client
+    /// certificates against.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub crl: Vec<PathBuf>,
 }
 
 impl Tls {
     fn update_relative_paths(&mut self, base: &Path) -> io::Result<()> {
         Self::update_relative_path(base, &mut self.key)?;
-        Self::update_relative_path(base, &mut self.cert)?;
-        Self::update_relative_path(base, &mut self.ca_cert)
+        for file in [
+            &mut self.cert,
+            &mut self.ca_cert,
+            &mut self.key_passphrase_file,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            Self::update_relative_path(base, file)?;
+        }
+        for crl in &mut self.crl {
+            Self::update_relative_path(base, crl)?;
+        }
+
+        Ok(())
     }
 
     fn update_relative_path(config: &Path, file: &mut PathBuf) -> io::Result<()> {
@@ -60,36 +163,64 @@ impl Tls {
     }
 }
 
+pub const DEFAULT_TUN_NAME: &str = "tun0";
+
+// fwmark and table of `tun0`
+const DEFAULT_ID: u32 = 19988;
+// rule priority of `tun0`, right before the main table at 32766
+const DEFAULT_RULE_PRIORITY: u32 = 32764;
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "role")]
 pub enum Network {
     #[serde(rename = "server")]
     Server {
         name: Option<String>,
-        address: Cidr,
+        address: Address,
+        /// Addresses assigned to clients, at most one range per address family.
+        pool: Option<Address>,
+        /// Where the addresses assigned out of `pool` are recorded, by default
+        /// `/var/lib/vqn/<name>.leases`.
+        lease_file: Option<PathBuf>,
         mtu: Option<usize>,
         port: Option<u16>,
+        listen: Option<Vec<SocketAddr>>,
         client: Vec<ClientPeer>,
         fwmark: Option<u32>,
+        table: Option<u32>,
+        rule_priority: Option<u32>,
         dns: Option<String>,
+        dns_search: Option<Vec<String>>,
+        duplicate: Option<Duplicate>,
+        client_to_client: Option<ClientToClient>,
+        /// Configuration pushed to clients.
+        push: Option<Push>,
+        /// Masquerading of client traffic leaving the server.
+        nat: Option<Nat>,
     },
 
     #[serde(rename = "client")]
     Client {
         name: Option<String>,
-        address: Cidr,
+        /// Addresses of the tun interface, assigned by the server if left out.
+        address: Option<Address>,
         mtu: Option<usize>,
         server: ServerPeer,
         fwmark: Option<u32>,
+        table: Option<u32>,
+        rule_priority: Option<u32>,
+        /// DNS servers, used instead of the ones the server pushes.
         dns: Option<String>,
+        /// DNS search domains, used instead of the ones the server pushes.
+        dns_search: Option<Vec<String>>,
     },
 }
 
 impl Network {
-    pub fn address(&self) -> Cidr {
+    pub fn address(&self) -> Option<&Address> {
         match self {
-            Network::Server { address, .. } => *address,
-            Network::Client { address, .. } => *address,
+            Network::Server { address, .. } => Some(address),
+            Network::Client { address, .. } => address.as_ref(),
         }
     }
 
@@ -100,10 +231,52 @@ impl Network {
         }
     }
 
-    pub fn fwmark(&self) -> Option<u32> {
-        match self {
+    /// Firewall mark of the tunnel traffic, derived from the interface name by default.
+    pub fn fwmark(&self) -> u32 {
+        let fwmark = match self {
             Network::Server { fwmark, .. } => *fwmark,
             Network::Client { fwmark, .. } => *fwmark,
+        };
+
+        fwmark.unwrap_or(DEFAULT_ID + self.index())
+    }
+
+    /// Routing table of the routes to the tun interface, derived from the interface name
+    /// by default.
+    pub fn table(&self) -> u32 {
+        let table = match self {
+            Network::Server { table, .. } => *table,
+            Network::Client { table, .. } => *table,
+        };
+
+        table.unwrap_or(DEFAULT_ID + self.index())
+    }
+
+    /// Priority of the first of the two policy rules, the second one follows right
+    /// after it. Derived from the interface name by default.
+    pub fn rule_priority(&self) -> u32 {
+        let priority = match self {
+            Network::Server { rule_priority, .. } => *rule_priority,
+            Network::Client { rule_priority, .. } => *rule_priority,
+        };
+
+        priority.unwrap_or(DEFAULT_RULE_PRIORITY - 2 * self.index())
+    }
+
+    // a small number telling tunnels apart: the numeric suffix of names like `tun0`,
+    // or a hash of other names
+    fn index(&self) -> u32 {
+        let name = self.name().unwrap_or(DEFAULT_TUN_NAME);
+        let suffix = name.trim_start_matches(|c: char| !c.is_ascii_digit());
+        match suffix.parse::<u32>() {
+            Ok(n) if n < 1000 && name.ends_with(suffix) => n,
+            _ => {
+                // FNV-1a, which unlike `DefaultHasher` is stable across releases
+                let hash = name.bytes().fold(0x811c9dc5_u32, |hash, b| {
+                    (hash ^ b as u32).wrapping_mul(0x01000193)
+                });
+                1000 + hash % 1000
+            }
         }
     }
 
@@ -120,23 +293,235 @@ impl Network {
             Network::Client { dns, .. } => dns.as_deref(),
         }
     }
+
+    pub fn dns_search(&self) -> &[String] {
+        let dns_search = match self {
+            Network::Server { dns_search, .. } => dns_search,
+            Network::Client { dns_search, .. } => dns_search,
+        };
+
+        dns_search.as_deref().unwrap_or_default()
+    }
+}
+
+/// Whether clients may send traffic to each other through the server. Unset, their
+/// traffic passes through the tun interface and the host decides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientToClient {
+    /// Forward traffic between clients inside the server.
+    Allow,
+    /// Drop traffic between clients, even if the host forwards it.
+    Deny,
+}
+
+impl From<Option<ClientToClient>> for core::ClientToClient {
+    fn from(setting: Option<ClientToClient>) -> Self {
+        match setting {
+            None => core::ClientToClient::Kernel,
+            Some(ClientToClient::Allow) => core::ClientToClient::Allow,
+            Some(ClientToClient::Deny) => core::ClientToClient::Deny,
+        }
+    }
+}
+
+/// Configuration a server pushes to its clients when they connect and whenever it
+/// changes, which clients may override or restrict locally.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Push {
+    /// Traffic clients route through the tunnel.
+    pub routes: Option<AllowedIps>,
+    /// DNS servers of the clients' tun interface.
+    pub dns: Option<String>,
+    /// DNS search domains of the clients' tun interface.
+    pub dns_search: Option<Vec<String>>,
+    /// MTU recommended to clients.
+    pub mtu: Option<u16>,
 }
 
+/// Masquerading of client traffic the server forwards to other networks, which makes the
+/// server an internet gateway for its clients.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Nat {
+    /// Interface traffic is masqueraded on leaving through, any but the tun interface by
+    /// default.
+    pub egress: Option<String>,
+    /// Source ranges masqueraded, by default the networks of the server's `address` and
+    /// `pool`.
+    pub source: Option<AllowedIps>,
+}
+
+/// Parses a list of DNS servers separated by commas or whitespace, as in `dns`.
+pub fn dns_servers(dns: &str) -> Result<Vec<IpAddr>, String> {
+    dns.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse().map_err(|_| format!("invalid DNS server: {s}")))
+        .collect()
+}
+
+/// What to do when a client connects while it already has a live connection.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Duplicate {
+    /// Close the existing connection in favor of the new one.
+    NewestWins,
+    /// Reject the new connection.
+    Reject,
+}
+
+/// A client of a server, recognized either by its whole certificate chain or by an
+/// [Identity] its certificate carries.
 #[derive(Debug, Clone, Deserialize)]
 pub struct ClientPeer {
-    pub client_cert: PathBuf,
+    pub client_cert: Option<PathBuf>,
 
+    pub identity: Option<Identity>,
+
+    /// Addresses the client may use besides the ones assigned out of the server's `pool`.
+    #[serde(default)]
     pub allowed_ips: AllowedIps,
+
+    /// Rules filtering the client's traffic in both directions, the first matching one
+    /// applies.
+    #[serde(default)]
+    pub acl: Vec<AclRule>,
+
+    /// What happens to traffic no `acl` rule matches, `allow` by default.
+    pub acl_default: Option<AclAction>,
+
+    /// Ports of the server relayed to services of the client.
+    #[serde(default)]
+    pub forward: Vec<Forward>,
+}
+
+/// A rule of a client's `acl`, matching traffic by the address, protocol and port on the
+/// far side of the tunnel: where the client sends it, or where replies come from.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AclRule {
+    pub action: AclAction,
+    pub dst: Option<Cidr>,
+    pub protocol: Option<Protocol>,
+    /// A TCP or UDP port, or an inclusive range of them such as `"8000-8999"`.
+    pub port: Option<PortRange>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AclAction {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    /// ICMP or ICMPv6.
+    Icmp,
+}
+
+/// A port of the server whose connections, or datagrams, are relayed to a service of a
+/// client through the tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Forward {
+    /// The address and port the server accepts on, such as `"0.0.0.0:8443"`.
+    pub public: SocketAddr,
+    /// The address of the client, and the port, traffic is relayed to.
+    pub target: SocketAddr,
+    /// `tcp` or `udp`, `tcp` by default.
+    pub proto: Option<Protocol>,
+}
+
+/// An inclusive range of ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange(pub u16, pub u16);
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let port = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("invalid port: {port}"))
+        };
+        let (first, last) = match s.split_once('-') {
+            Some((first, last)) => (port(first)?, port(last)?),
+            None => (port(s)?, port(s)?),
+        };
+        if first > last {
+            return Err(format!("invalid port range: {s}"));
+        }
+
+        Ok(PortRange(first, last))
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum PortOrRange {
+            Port(u16),
+            Range(String),
+        }
+
+        match PortOrRange::deserialize(deserializer)? {
+            PortOrRange::Port(port) => Ok(PortRange(port, port)),
+            PortOrRange::Range(range) => FromStr::from_str(&range).map_err(de::Error::custom),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ServerPeer {
-    pub url: Url,
+    /// Server endpoints in order of preference.
+    #[serde(alias = "urls", deserialize_with = "one_or_many")]
+    pub url: Vec<Url>,
     pub server_name: Option<String>,
-    pub allowed_ips: AllowedIps,
+    /// Fingerprint of the server's key, `spki-sha256:<hex>`, checked instead of its
+    /// certificate when there is no `ca_cert`.
+    pub pin: Option<Identity>,
+    /// Traffic to route through the tunnel, used instead of the routes the server pushes.
+    pub allowed_ips: Option<AllowedIps>,
+    /// Ranges pushed routes must fall within to be accepted, all of them by default.
+    pub accept_routes: Option<AllowedIps>,
+    /// Whether to use the DNS servers and search domains the server pushes, `true` by
+    /// default.
+    pub accept_dns: Option<bool>,
+    /// Upper bound of the delay between reconnect attempts, in seconds.
+    pub max_backoff: Option<u64>,
 }
 
-#[derive(Debug, Copy, Clone)]
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => Ok(vec![one]),
+        OneOrMany::Many(many) if many.is_empty() => {
+            Err(de::Error::invalid_length(0, &"at least one element"))
+        }
+        OneOrMany::Many(many) => Ok(many),
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Cidr(pub IpAddr, pub u8);
 
 impl std::fmt::Display for Cidr {
@@ -152,23 +537,75 @@ impl Cidr {
         self.0
     }
 
-    pub fn netmask(self) -> (u8, u8, u8, u8) {
-        let mask = if self.1 >= 32 {
-            return (255, 255, 255, 255);
-        } else {
-            ((1u64 << self.1) - 1) << (32 - self.1)
+    pub fn netmask(self) -> IpAddr {
+        // a shift by the full width overflows, which is the all zero mask of a /0
+        match self.0 {
+            IpAddr::V4(_) => {
+                let mask = u32::MAX.checked_shl(32 - self.1 as u32).unwrap_or(0);
+                Ipv4Addr::from(mask).into()
+            }
+            IpAddr::V6(_) => {
+                let mask = u128::MAX.checked_shl(128 - self.1 as u32).unwrap_or(0);
+                Ipv6Addr::from(mask).into()
+            }
+        }
+    }
+
+    /// The range with the host bits of its address cleared.
+    pub fn network(self) -> Cidr {
+        let ip = match (self.0, self.netmask()) {
+            (IpAddr::V4(ip), IpAddr::V4(mask)) => IpAddr::V4(ip & mask),
+            (IpAddr::V6(ip), IpAddr::V6(mask)) => IpAddr::V6(ip & mask),
+            _ => unreachable!("the netmask is of the same family"),
         };
 
-        (
-            ((mask >> 24) & 0xFF) as u8,
-            ((mask >> 16) & 0xFF) as u8,
-            ((mask >> 8) & 0xFF) as u8,
-            (mask & 0xFF) as u8,
-        )
+        Cidr(ip, self.1)
+    }
+
+    /// Returns `true` if `other` lies within this range.
+    pub fn contains(self, other: Cidr) -> bool {
+        self.0.is_ipv4() == other.0.is_ipv4()
+            && self.1 <= other.1
+            && Cidr(other.0, self.1).network() == self.network()
+    }
+
+    fn max_prefix_len(ip: IpAddr) -> u8 {
+        match ip {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+}
+
+/// Addresses of the tun interface, at most one per address family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address {
+    pub v4: Option<Cidr>,
+    pub v6: Option<Cidr>,
+}
+
+impl Address {
+    pub fn iter(&self) -> impl Iterator<Item = Cidr> {
+        self.v4.into_iter().chain(self.v6)
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut first = true;
+        for cidr in self.iter() {
+            if !first {
+                write!(f, ", ")?;
+            } else {
+                first = false;
+            }
+            write!(f, "{cidr}")?;
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct AllowedIps {
     pub values: Vec<Cidr>,
 }
@@ -194,8 +631,11 @@ impl std::fmt::Display for AllowedIps {
     }
 }
 
+#[derive(Debug)]
 pub struct ParseCidrError(String);
 
+impl std::error::Error for ParseCidrError {}
+
 impl std::fmt::Display for ParseCidrError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", &self.0)
@@ -218,9 +658,10 @@ impl FromStr for Cidr {
             .parse::<u8>()
             .map_err(|_| ParseCidrError(format!("Invalid subnet mask: {cidr}")))?;
 
-        if subnet > 32 {
+        let max = Cidr::max_prefix_len(ip);
+        if subnet > max {
             return Err(ParseCidrError(format!(
-                "Subnet mask must be in the range 0-32: {cidr}"
+                "Subnet mask must be in the range 0-{max}: {cidr}"
             )));
         }
 
@@ -228,6 +669,32 @@ impl FromStr for Cidr {
     }
 }
 
+impl FromStr for Address {
+    type Err = ParseCidrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut address = Address { v4: None, v6: None };
+        for cidr in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let cidr = Cidr::from_str(cidr)?;
+            let slot = match cidr.ip() {
+                IpAddr::V4(_) => &mut address.v4,
+                IpAddr::V6(_) => &mut address.v6,
+            };
+            if slot.replace(cidr).is_some() {
+                return Err(ParseCidrError(format!(
+                    "At most one address per address family allowed: {s}"
+                )));
+            }
+        }
+
+        if address.v4.is_none() && address.v6.is_none() {
+            return Err(ParseCidrError(format!("No address specified: {s}")));
+        }
+
+        Ok(address)
+    }
+}
+
 impl FromStr for AllowedIps {
     type Err = ParseCidrError;
 
@@ -252,6 +719,25 @@ impl<'de> Deserialize<'de> for Cidr {
     }
 }
 
+impl Serialize for Cidr {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
 impl<'de> Deserialize<'de> for AllowedIps {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -262,6 +748,15 @@ impl<'de> Deserialize<'de> for AllowedIps {
     }
 }
 
+impl Serialize for AllowedIps {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -289,7 +784,72 @@ allowed_ips = "10.10.0.2/32"
 "#;
 
         let conf: Result<Conf, _> = toml::from_str(input);
-        assert!(conf.is_ok());
+        let Network::Server { port, listen, .. } = conf.unwrap().network else {
+            panic!("not a server");
+        };
+        assert_eq!(port, Some(10086));
+        assert_eq!(listen, None);
+    }
+
+    #[test]
+    fn test_client_to_client() {
+        let client_to_client = |setting: &str| {
+            let input = format!(
+                r#"
+[tls]
+key = "./key.pem"
+
+[network]
+role = "server"
+address = "10.10.0.1/24"
+client = []
+{setting}
+"#
+            );
+            match Conf::parse_from(&input).unwrap().network {
+                Network::Server {
+                    client_to_client, ..
+                } => core::ClientToClient::from(client_to_client),
+                Network::Client { .. } => unreachable!(),
+            }
+        };
+
+        // unset, the host decides
+        assert_eq!(client_to_client(""), core::ClientToClient::Kernel);
+        assert_eq!(
+            client_to_client(r#"client_to_client = "allow""#),
+            core::ClientToClient::Allow
+        );
+        assert_eq!(
+            client_to_client(r#"client_to_client = "deny""#),
+            core::ClientToClient::Deny
+        );
+    }
+
+    #[test]
+    fn test_listen() {
+        let input = r#"
+[tls]
+key = "./key.pem"
+
+[network]
+role = "server"
+address = "10.10.0.3/24"
+listen = ["0.0.0.0:10086", "[::]:10086"]
+client = []
+"#;
+        let conf = Conf::parse_from(input).unwrap();
+        let Network::Server { port, listen, .. } = conf.network else {
+            panic!("not a server");
+        };
+        assert_eq!(port, None);
+        assert_eq!(
+            listen,
+            Some(vec![
+                "0.0.0.0:10086".parse().unwrap(),
+                "[::]:10086".parse().unwrap()
+            ])
+        );
     }
 
     #[test]
@@ -315,23 +875,435 @@ allowed_ips = "0.0.0.0/0, ::/0"
     }
 
     #[test]
-    fn test_cidr_netmask() {
+    fn test_max_backoff() {
+        let max_backoff = |extra: &str| {
+            let input = format!(
+                r#"
+[tls]
+key = "./key.pem"
+
+[network]
+role = "client"
+
+[network.server]
+url = "https://example.org"
+{extra}
+"#
+            );
+            match Conf::parse_from(&input).unwrap().network {
+                Network::Client { server, .. } => server.max_backoff,
+                Network::Server { .. } => unreachable!(),
+            }
+        };
+
+        assert_eq!(max_backoff(""), None);
+        assert_eq!(max_backoff("max_backoff = 60"), Some(60));
+    }
+
+    #[test]
+    fn test_pool() {
+        let input = r#"
+[tls]
+key = "./key.pem"
+
+[network]
+role = "server"
+address = "10.10.0.1/24"
+pool = "10.10.0.0/24, fd00::/64"
+
+[[network.client]]
+identity = "cn:alice"
+"#;
+        let conf = Conf::parse_from(input).unwrap();
+        let Network::Server { pool, client, .. } = conf.network else {
+            panic!("not a server");
+        };
+        assert_eq!(pool.unwrap().to_string(), "10.10.0.0/24, fd00::/64");
+        assert!(client[0].allowed_ips.values.is_empty());
+
+        // clients without an address are assigned one by the server
+        let input = r#"
+[tls]
+key = "./key.pem"
+
+[network]
+role = "client"
+
+[network.server]
+url = "https://example.org"
+allowed_ips = "10.10.0.0/24"
+"#;
+        let conf = Conf::parse_from(input).unwrap();
+        assert_eq!(conf.network.address(), None);
+    }
+
+    #[test]
+    fn test_acl() {
+        let input = r#"
+[tls]
+key = "./key.pem"
+
+[network]
+role = "server"
+address = "10.10.0.1/24"
+
+[[network.client]]
+identity = "cn:contractor"
+allowed_ips = "10.10.0.5/32"
+acl_default = "deny"
+acl = [
+  { action = "allow", dst = "10.20.0.0/16", protocol = "tcp", port = 443 },
+  { action = "allow", dst = "10.20.0.0/16", protocol = "udp", port = "8000-8999" },
+  { action = "deny", protocol = "icmp" },
+]
+"#;
+        let conf = Conf::parse_from(input).unwrap();
+        let Network::Server { client, .. } = conf.network else {
+            panic!("not a server");
+        };
+        let client = &client[0];
+        assert_eq!(client.acl_default, Some(AclAction::Deny));
+        assert_eq!(
+            client.acl[0],
+            AclRule {
+                action: AclAction::Allow,
+                dst: Some("10.20.0.0/16".parse().unwrap()),
+                protocol: Some(Protocol::Tcp),
+                port: Some(PortRange(443, 443)),
+            }
+        );
+        assert_eq!(client.acl[1].port, Some(PortRange(8000, 8999)));
+        assert_eq!(client.acl[2].dst, None);
+
+        assert!("9000-8000".parse::<PortRange>().is_err());
+        assert!("http".parse::<PortRange>().is_err());
+    }
+
+    #[test]
+    fn test_forward() {
+        let input = r#"
+[tls]
+key = "./key.pem"
+
+[network]
+role = "server"
+address = "10.10.0.1/24"
+
+[[network.client]]
+identity = "cn:web"
+allowed_ips = "10.10.0.5/32"
+
+[[network.client.forward]]
+public = "0.0.0.0:8443"
+target = "10.10.0.5:443"
+
+[[network.client.forward]]
+public = "[::]:5353"
+target = "10.10.0.5:53"
+proto = "udp"
+"#;
+        let conf = Conf::parse_from(input).unwrap();
+        let Network::Server { client, .. } = conf.network else {
+            panic!("not a server");
+        };
+        assert_eq!(
+            client[0].forward,
+            [
+                Forward {
+                    public: "0.0.0.0:8443".parse().unwrap(),
+                    target: "10.10.0.5:443".parse().unwrap(),
+                    proto: None,
+                },
+                Forward {
+                    public: "[::]:5353".parse().unwrap(),
+                    target: "10.10.0.5:53".parse().unwrap(),
+                    proto: Some(Protocol::Udp),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nat() {
+        let input = r#"
+[tls]
+key = "./key.pem"
+
+[network]
+role = "server"
+address = "10.10.0.1/24"
+client = []
+
+[network.nat]
+egress = "eth0"
+source = "10.10.0.0/24, fd00::/64"
+"#;
+        let conf = Conf::parse_from(input).unwrap();
+        let Network::Server { nat: Some(nat), .. } = conf.network else {
+            panic!("no [network.nat]");
+        };
+        assert_eq!(nat.egress.as_deref(), Some("eth0"));
+        assert_eq!(nat.source.unwrap().to_string(), "10.10.0.0/24, fd00::/64");
+
+        let input = input.replace("egress", "egres");
+        assert!(Conf::parse_from(&input).is_err());
+    }
+
+    #[test]
+    fn test_push() {
+        let input = r#"
+[tls]
+key = "./key.pem"
+
+[network]
+role = "server"
+address = "10.10.0.1/24"
+client = []
+
+[network.push]
+routes = "10.20.0.0/16, fd20::/48"
+dns = "10.10.0.1, fd00::1"
+dns_search = ["corp.example.org"]
+mtu = 1380
+"#;
+        let conf = Conf::parse_from(input).unwrap();
+        let Network::Server {
+            push: Some(push), ..
+        } = conf.network
+        else {
+            panic!("no [network.push]");
+        };
+        assert_eq!(push.routes.unwrap().to_string(), "10.20.0.0/16, fd20::/48");
         assert_eq!(
-            Cidr("0.0.0.0".parse().unwrap(), 32).netmask(),
-            (255, 255, 255, 255)
+            dns_servers(push.dns.as_deref().unwrap()).unwrap(),
+            [
+                "10.10.0.1".parse::<IpAddr>().unwrap(),
+                "fd00::1".parse().unwrap()
+            ]
         );
+        assert_eq!(push.dns_search.unwrap(), ["corp.example.org"]);
+        assert_eq!(push.mtu, Some(1380));
+        assert!(dns_servers("10.10.0.1 bogus").is_err());
+
+        // routes and DNS are taken from the server unless configured
+        let input = r#"
+[tls]
+key = "./key.pem"
+
+[network]
+role = "client"
+dns_search = ["example.org"]
+
+[network.server]
+url = "https://example.org"
+accept_routes = "10.0.0.0/8"
+accept_dns = false
+"#;
+        let conf = Conf::parse_from(input).unwrap();
+        assert_eq!(conf.network.dns_search(), ["example.org"]);
+        let Network::Client { server, .. } = conf.network else {
+            panic!("not a client");
+        };
+        assert_eq!(server.allowed_ips, None);
+        assert_eq!(server.accept_routes.unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!(server.accept_dns, Some(false));
+    }
+
+    #[test]
+    fn test_client_urls() {
+        let server = |urls: &str| {
+            let input = format!(
+                r#"
+[tls]
+key = "./key.pem"
+cert = "./cert.pem"
+ca_cert = "./ca_cert.pem"
+
+[network]
+role = "client"
+address = "10.10.0.3/24"
+
+[network.server]
+{urls}
+allowed_ips = "0.0.0.0/0"
+"#
+            );
+            match toml::from_str::<Conf>(&input).map(|conf| conf.network) {
+                Ok(Network::Client { server, .. }) => Ok(server
+                    .url
+                    .iter()
+                    .map(|url| url.host_str().unwrap().to_owned())
+                    .collect::<Vec<_>>()),
+                Ok(_) => unreachable!(),
+                Err(e) => Err(e),
+            }
+        };
+
+        assert_eq!(server(r#"url = "https://a.org""#).unwrap(), ["a.org"]);
         assert_eq!(
-            Cidr("0.0.0.0".parse().unwrap(), 24).netmask(),
-            (255, 255, 255, 0)
+            server(r#"urls = ["https://a.org", "https://b.org:10086"]"#).unwrap(),
+            ["a.org", "b.org"]
         );
+        assert!(server("urls = []").is_err());
+    }
+
+    #[test]
+    fn test_crl() {
+        let tls = |crl: &str| {
+            toml::from_str::<Tls>(&format!(
+                r#"
+key = "./key.pem"
+cert = "./cert.pem"
+ca_cert = "./ca_cert.pem"
+{crl}
+"#
+            ))
+            .map(|tls| tls.crl)
+        };
+
+        assert!(tls("").unwrap().is_empty());
         assert_eq!(
-            Cidr("0.0.0.0".parse().unwrap(), 16).netmask(),
-            (255, 255, 0, 0)
+            tls(r#"crl = "./crl.pem""#).unwrap(),
+            [Path::new("./crl.pem")]
         );
         assert_eq!(
-            Cidr("0.0.0.0".parse().unwrap(), 8).netmask(),
-            (255, 0, 0, 0)
+            tls(r#"crl = ["./a.pem", "./b.der"]"#).unwrap(),
+            [Path::new("./a.pem"), Path::new("./b.der")]
         );
-        assert_eq!(Cidr("0.0.0.0".parse().unwrap(), 0).netmask(), (0, 0, 0, 0));
+        assert!(tls("crl = []").is_err());
+    }
+
+    #[test]
+    fn test_derived_ids() {
+        let network = |name: &str, extra: &str| {
+            let input = format!(
+                r#"
+role = "client"
+{name}
+address = "10.10.0.3/24"
+{extra}
+
+[server]
+url = "https://example.org"
+allowed_ips = "0.0.0.0/0"
+"#
+            );
+            toml::from_str::<Network>(&input).unwrap()
+        };
+        let ids = |network: Network| (network.fwmark(), network.table(), network.rule_priority());
+
+        assert_eq!(ids(network("", "")), (19988, 19988, 32764));
+        assert_eq!(ids(network(r#"name = "tun3""#, "")), (19991, 19991, 32758));
+        assert_eq!(
+            ids(network(
+                r#"name = "tun3""#,
+                "fwmark = 1\ntable = 2\nrule_priority = 3"
+            )),
+            (1, 2, 3)
+        );
+
+        let (fwmark, table, priority) = ids(network(r#"name = "wg-office""#, ""));
+        assert!((20988..21988).contains(&fwmark));
+        assert_eq!(fwmark, table);
+        assert_eq!(priority, 32764 - 2 * (fwmark - 19988));
+        assert_ne!(ids(network(r#"name = "wg-home""#, "")).0, fwmark);
+    }
+
+    #[test]
+    fn test_check_tunnels() {
+        let conf = |network: &str| {
+            let input = format!(
+                r#"
+[tls]
+key = "./key.pem"
+cert = "./cert.pem"
+ca_cert = "./ca_cert.pem"
+
+[network]
+role = "server"
+address = "10.10.0.1/24"
+client = []
+{network}
+"#
+            );
+            Conf::parse_from(&input).unwrap()
+        };
+        let check =
+            |a: &str, b: &str| check_tunnels([&conf(a), &conf(b)]).map_err(|e| e.to_string());
+
+        assert!(check("", r#"name = "tun1""#).is_ok());
+        assert_eq!(
+            check("", r#"name = "tun0""#),
+            Err("tun0 is configured more than once".into())
+        );
+        assert_eq!(
+            check("", "name = \"tun1\"\nfwmark = 19988"),
+            Err("tun0 and tun1 use the same fwmark 19988".into())
+        );
+        assert_eq!(
+            check("", "name = \"tun1\"\ntable = 19988"),
+            Err("tun0 and tun1 use the same routing table 19988".into())
+        );
+        assert_eq!(
+            check("", "name = \"tun1\"\nrule_priority = 32765"),
+            Err("the rule priorities of tun0 and tun1 overlap".into())
+        );
+    }
+
+    #[test]
+    fn test_cidr_netmask() {
+        let netmask = |cidr: &str| Cidr::from_str(cidr).unwrap().netmask().to_string();
+
+        assert_eq!(netmask("0.0.0.0/32"), "255.255.255.255");
+        assert_eq!(netmask("0.0.0.0/24"), "255.255.255.0");
+        assert_eq!(netmask("0.0.0.0/16"), "255.255.0.0");
+        assert_eq!(netmask("0.0.0.0/8"), "255.0.0.0");
+        assert_eq!(netmask("0.0.0.0/0"), "0.0.0.0");
+
+        assert_eq!(netmask("::/128"), "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff");
+        assert_eq!(netmask("::/64"), "ffff:ffff:ffff:ffff::");
+        assert_eq!(netmask("::/0"), "::");
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let contains = |range: &str, cidr: &str| {
+            Cidr::from_str(range)
+                .unwrap()
+                .contains(Cidr::from_str(cidr).unwrap())
+        };
+
+        assert!(contains("10.0.0.0/8", "10.20.0.0/16"));
+        assert!(contains("10.0.0.0/8", "10.0.0.0/8"));
+        assert!(contains("0.0.0.0/0", "192.168.1.1/32"));
+        assert!(!contains("10.0.0.0/8", "0.0.0.0/0"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.0/16"));
+        assert!(!contains("::/0", "10.0.0.0/8"));
+        assert!(contains("fd00::/8", "fd20::/48"));
+    }
+
+    #[test]
+    fn test_cidr_prefix_len() {
+        assert!(Cidr::from_str("10.0.0.0/32").is_ok());
+        assert!(Cidr::from_str("10.0.0.0/33").is_err());
+        assert!(Cidr::from_str("fd00::/64").is_ok());
+        assert!(Cidr::from_str("fd00::/128").is_ok());
+        assert!(Cidr::from_str("fd00::/129").is_err());
+        assert!(AllowedIps::from_str("10.0.0.0/8, fd00::/48").is_ok());
+        assert!(AllowedIps::from_str("10.0.0.0/8, fd00::/130").is_err());
+    }
+
+    #[test]
+    fn test_address() {
+        let address = Address::from_str("10.10.0.1/24, fd00::1/64").unwrap();
+        assert_eq!(address.v4, Some(Cidr::from_str("10.10.0.1/24").unwrap()));
+        assert_eq!(address.v6, Some(Cidr::from_str("fd00::1/64").unwrap()));
+        assert_eq!(address.to_string(), "10.10.0.1/24, fd00::1/64");
+
+        let address = Address::from_str("fd00::1/64").unwrap();
+        assert_eq!(address.v4, None);
+
+        assert!(Address::from_str("10.10.0.1/24, 10.10.0.2/24").is_err());
+        assert!(Address::from_str("").is_err());
     }
 }
@@ -0,0 +1,159 @@
+//! Establishes the client connection to one of the configured server endpoints.
+//!
+//! Endpoints are tried in order. All addresses a hostname resolves to are raced
+//! Happy-Eyeballs style (RFC 8305): attempts alternate between IPv6 and IPv4 and a new
+//! attempt starts every [ATTEMPT_DELAY] until one of them completes a handshake.
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use futures::stream::{FuturesUnordered, StreamExt};
+use quinn::{Connection, Endpoint};
+use url::Url;
+
+/// Delay before racing the next address while earlier attempts are still pending.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// An unanswered handshake would otherwise only fail after the idle timeout.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const DEFAULT_PORT: u16 = 443;
+
+/// Connects to the first reachable server in `urls`.
+///
+/// Hostnames are resolved anew on every call, so a server that changed its address is
+/// found again. `server_name` overrides the hostname of each url for TLS verification.
+pub async fn connect(
+    endpoint: &Endpoint,
+    urls: &[Url],
+    server_name: Option<&str>,
+) -> anyhow::Result<Connection> {
+    let mut error = anyhow!("no server url configured");
+
+    for url in urls {
+        match connect_url(endpoint, url, server_name).await {
+            Ok(conn) => return Ok(conn),
+            Err(e) => {
+                if urls.len() > 1 {
+                    tracing::warn!("{e:#}, trying the next server");
+                }
+                error = e;
+            }
+        }
+    }
+
+    Err(error)
+}
+
+async fn connect_url(
+    endpoint: &Endpoint,
+    url: &Url,
+    server_name: Option<&str>,
+) -> anyhow::Result<Connection> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("no hostname in {url}"))?;
+    let server_name = server_name.unwrap_or(host);
+    let port = url.port().unwrap_or(DEFAULT_PORT);
+
+    // `lookup_host` does not understand the brackets around IPv6 literals
+    let addrs = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+        .await
+        .with_context(|| format!("failed to resolve {url}"))?
+        .collect::<Vec<_>>();
+    tracing::debug!("{url} resolved to {addrs:?}");
+
+    race(endpoint, interleave(addrs), server_name)
+        .await
+        .with_context(|| format!("failed to connect to {url}"))
+}
+
+async fn race(
+    endpoint: &Endpoint,
+    addrs: Vec<SocketAddr>,
+    server_name: &str,
+) -> anyhow::Result<Connection> {
+    let mut addrs = addrs.into_iter().peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut error = anyhow!("hostname resolved to no addresses");
+
+    loop {
+        if let Some(addr) = addrs.next() {
+            tracing::debug!("connecting to {addr}");
+            attempts.push(attempt(endpoint, addr, server_name));
+        }
+        if attempts.is_empty() {
+            return Err(error);
+        }
+
+        // a failed attempt starts the next one right away
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(conn) => return Ok(conn),
+                Err(e) => {
+                    tracing::debug!("{e:#}");
+                    error = e;
+                }
+            },
+            _ = tokio::time::sleep(ATTEMPT_DELAY), if addrs.peek().is_some() => (),
+        }
+    }
+}
+
+async fn attempt(
+    endpoint: &Endpoint,
+    addr: SocketAddr,
+    server_name: &str,
+) -> anyhow::Result<Connection> {
+    let connecting = endpoint
+        .connect(addr, server_name)
+        .with_context(|| addr.to_string())?;
+
+    match tokio::time::timeout(CONNECT_TIMEOUT, connecting).await {
+        Ok(conn) => conn.with_context(|| addr.to_string()),
+        Err(_) => Err(anyhow!("{addr}: timed out")),
+    }
+}
+
+// alternates address families, starting with the family of the first address
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let first_family = first.is_ipv6();
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_family);
+
+    let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+    let mut other = other.into_iter();
+    for addr in preferred {
+        interleaved.push(addr);
+        interleaved.extend(other.next());
+    }
+    interleaved.extend(other);
+
+    interleaved
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_interleave() {
+        let addrs = |s: &str| -> Vec<SocketAddr> {
+            s.split_whitespace().map(|a| a.parse().unwrap()).collect()
+        };
+
+        assert_eq!(interleave(vec![]), vec![]);
+        assert_eq!(
+            interleave(addrs("[::1]:1 [::2]:1 [::3]:1 1.1.1.1:1")),
+            addrs("[::1]:1 1.1.1.1:1 [::2]:1 [::3]:1")
+        );
+        assert_eq!(
+            interleave(addrs("1.1.1.1:1 1.1.1.2:1 [::1]:1 [::2]:1 [::3]:1")),
+            addrs("1.1.1.1:1 [::1]:1 1.1.1.2:1 [::2]:1 [::3]:1")
+        );
+    }
+}
@@ -0,0 +1,935 @@
+//! A Unix domain socket exposing the state of a running `vqn` to the `vqn show` command,
+//! and allowing the clients of a running server to be managed with `vqn peer`.
+//!
+//! A single socket serves all tunnels of a process. Requests name the interface of the
+//! tunnel they are about, which may be left out if there is only one.
+//!
+//! Peer changes are applied to the live configuration, the same way a configuration reloaded
+//! on `SIGHUP` is, but are not persisted to the configuration file.
+//!
+//! The protocol is line-delimited JSON: a client writes a single [Request] and reads back a
+//! single [Response] before the connection is closed.
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use rustls::Certificate;
+use serde::{Deserialize, Serialize};
+use tokio::io::Lines;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::conf::{AllowedIps, ClientPeer, Conf, Network};
+use crate::core::{self, Identity, LinkStatus, PeerEvent, PeerStatus};
+use crate::expiry::Validity;
+use crate::forward::Forwards;
+use crate::supervisor::{Supervisor, TunnelState};
+
+const SOCKET_DIR: &str = "/run/vqn";
+
+/// Default control socket path of the tun interface `name`, or of the configuration
+/// directory `name`.
+pub fn socket_path(name: &str) -> PathBuf {
+    Path::new(SOCKET_DIR).join(format!("{name}.sock"))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Show,
+    Events,
+    Restart,
+    #[serde(untagged)]
+    Peer(PeerRequest),
+}
+
+/// A request about the peers of a single tunnel, handled by that [Tunnel].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command")]
+pub enum PeerRequest {
+    #[serde(rename = "peer_add")]
+    Add {
+        cert: Option<PathBuf>,
+        identity: Option<Identity>,
+        allowed_ips: AllowedIps,
+    },
+    #[serde(rename = "peer_remove")]
+    Remove {
+        cert: Option<PathBuf>,
+        identity: Option<Identity>,
+    },
+    #[serde(rename = "peer_kick")]
+    Kick {
+        cert: Option<PathBuf>,
+        identity: Option<Identity>,
+        reason: Option<String>,
+    },
+}
+
+// a request for the tunnel on `interface`
+#[derive(Debug, Serialize, Deserialize)]
+struct Message {
+    interface: Option<String>,
+    #[serde(flatten)]
+    request: Request,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Tunnels(Vec<TunnelInfo>),
+    Event(EventInfo),
+    Error(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventInfo {
+    Connected {
+        subject: Option<String>,
+        remote: SocketAddr,
+    },
+    Disconnected {
+        subject: Option<String>,
+        remote: SocketAddr,
+        reason: String,
+    },
+}
+
+impl std::fmt::Display for EventInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventInfo::Connected { subject, remote } => {
+                let subject = subject.as_deref().unwrap_or("(unknown)");
+                write!(f, "peer {subject} connected from {remote}")
+            }
+            EventInfo::Disconnected {
+                subject,
+                remote,
+                reason,
+            } => {
+                let subject = subject.as_deref().unwrap_or("(unknown)");
+                write!(f, "peer {subject} disconnected from {remote}: {reason}")
+            }
+        }
+    }
+}
+
+impl From<PeerEvent> for EventInfo {
+    fn from(event: PeerEvent) -> Self {
+        match event {
+            PeerEvent::Connected { cert_chain, remote } => EventInfo::Connected {
+                subject: cert_chain.first().and_then(subject),
+                remote,
+            },
+            PeerEvent::Disconnected {
+                cert_chain,
+                remote,
+                reason,
+            } => EventInfo::Disconnected {
+                subject: cert_chain.first().and_then(subject),
+                remote,
+                reason,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TunnelInfo {
+    pub interface: String,
+    pub state: TunnelState,
+    pub peers: Vec<PeerInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub subject: Option<String>,
+    /// What the peer is recognized by, unless it is its whole certificate chain.
+    pub identity: Option<String>,
+    pub allowed_ips: Vec<String>,
+    /// Addresses assigned to the peer out of the server's pool.
+    #[serde(default)]
+    pub address: Vec<String>,
+    pub connected: bool,
+    pub remote: Option<SocketAddr>,
+    pub rtt: Option<Duration>,
+    pub rx_bytes: u64,
+    pub rx_datagrams: u64,
+    pub tx_bytes: u64,
+    pub tx_datagrams: u64,
+    pub last_handshake: Option<SystemTime>,
+    pub dropped: u64,
+    /// Expiry of the peer's certificate.
+    #[serde(default)]
+    pub not_after: Option<SystemTime>,
+    /// Rules of the peer's ACL, and its default action last, with their hit counts.
+    #[serde(default)]
+    pub acl: Vec<(String, u64)>,
+    /// Ports of the server relayed to the peer.
+    #[serde(default)]
+    pub forward: Vec<ForwardInfo>,
+}
+
+/// A port of the server relayed to a peer, and the traffic relayed so far.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForwardInfo {
+    pub forward: String,
+    /// Open TCP connections or UDP sessions.
+    pub active: u64,
+    pub total: u64,
+    pub bytes: u64,
+}
+
+/// The tunnel a control socket reports on.
+pub enum Tunnel {
+    Server {
+        router: Arc<core::Router>,
+        conf: Arc<watch::Sender<Conf>>,
+        forwards: Arc<Forwards>,
+    },
+    Client {
+        link: Arc<core::Link>,
+        /// The routes in effect, the configured ones or those pushed by the server.
+        allowed_ips: watch::Receiver<AllowedIps>,
+    },
+}
+
+impl Tunnel {
+    fn handle(&self, request: PeerRequest) -> Response {
+        let result = match request {
+            PeerRequest::Add {
+                cert,
+                identity,
+                allowed_ips,
+            } => self.add_peer(cert, identity, allowed_ips),
+            PeerRequest::Remove { cert, identity } => {
+                crate::peer_identity(cert.as_deref(), identity.as_ref())
+                    .and_then(|identity| self.remove_peer(&identity))
+            }
+            PeerRequest::Kick {
+                cert,
+                identity,
+                reason,
+            } => crate::peer_identity(cert.as_deref(), identity.as_ref())
+                .and_then(|identity| self.kick_peer(&identity, reason.as_deref())),
+        };
+
+        match result {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error(format!("{e:#}")),
+        }
+    }
+
+    fn server(&self) -> anyhow::Result<(&core::Router, &watch::Sender<Conf>)> {
+        match self {
+            Tunnel::Server { router, conf, .. } => Ok((router, conf)),
+            Tunnel::Client { .. } => anyhow::bail!("peers can only be managed on a server"),
+        }
+    }
+
+    fn add_peer(
+        &self,
+        cert: Option<PathBuf>,
+        identity: Option<Identity>,
+        allowed_ips: AllowedIps,
+    ) -> anyhow::Result<()> {
+        let (_, conf) = self.server()?;
+        let peer = crate::peer_identity(cert.as_deref(), identity.as_ref())?;
+
+        crate::update_conf(conf, |current| {
+            let mut new = current.clone();
+            let clients = clients_mut(&mut new)?;
+            anyhow::ensure!(
+                !clients.iter().any(|c| is_peer(c, &peer)),
+                "peer is already configured"
+            );
+            clients.push(ClientPeer {
+                client_cert: cert.clone(),
+                identity: identity.clone(),
+                allowed_ips: allowed_ips.clone(),
+                acl: vec![],
+                acl_default: None,
+                forward: vec![],
+            });
+            Ok(new)
+        })?;
+        tracing::info!("added peer {peer} with allowed ips: {allowed_ips}");
+
+        Ok(())
+    }
+
+    fn remove_peer(&self, peer: &Identity) -> anyhow::Result<()> {
+        let (_, conf) = self.server()?;
+
+        crate::update_conf(conf, |current| {
+            let mut new = current.clone();
+            let clients = clients_mut(&mut new)?;
+            let n = clients.len();
+            clients.retain(|c| !is_peer(c, peer));
+            anyhow::ensure!(clients.len() < n, "peer is not configured");
+            Ok(new)
+        })?;
+        tracing::info!("removed peer {peer}");
+
+        Ok(())
+    }
+
+    fn kick_peer(&self, peer: &Identity, reason: Option<&str>) -> anyhow::Result<()> {
+        let (router, _) = self.server()?;
+
+        let reason = reason.unwrap_or("kicked");
+        anyhow::ensure!(
+            router.disconnect(peer, core::PEER_KICKED, reason),
+            "peer is not connected"
+        );
+        tracing::info!("kicked peer {peer}: {reason}");
+
+        Ok(())
+    }
+
+    fn peers(&self) -> Vec<PeerInfo> {
+        match self {
+            Tunnel::Server {
+                router, forwards, ..
+            } => {
+                let mut forwards = forwards.status();
+                forwards.sort_by_key(|status| (status.forward.public, status.forward.proto));
+                router
+                    .status()
+                    .into_iter()
+                    .map(|status| {
+                        let forward = forwards
+                            .iter()
+                            .filter(|forward| forward.peer == status.identity)
+                            .map(|forward| ForwardInfo {
+                                forward: forward.forward.to_string(),
+                                active: forward.active,
+                                total: forward.total,
+                                bytes: forward.bytes,
+                            })
+                            .collect();
+                        PeerInfo {
+                            forward,
+                            ..PeerInfo::from(status)
+                        }
+                    })
+                    .collect()
+            }
+            Tunnel::Client { link, allowed_ips } => {
+                let status = link.status();
+                let cert = status.cert_chain.as_deref().and_then(|chain| chain.first());
+                vec![PeerInfo::new(
+                    cert,
+                    None,
+                    allowed_ips
+                        .borrow()
+                        .values
+                        .iter()
+                        .map(|cidr| cidr.to_string()),
+                    &status,
+                    0,
+                )]
+            }
+        }
+    }
+}
+
+impl PeerInfo {
+    fn new(
+        cert: Option<&Certificate>,
+        identity: Option<String>,
+        allowed_ips: impl Iterator<Item = String>,
+        link: &LinkStatus,
+        dropped: u64,
+    ) -> Self {
+        let validity = cert.and_then(Validity::of);
+        PeerInfo {
+            subject: validity.as_ref().map(|validity| validity.subject.clone()),
+            identity,
+            allowed_ips: allowed_ips.collect(),
+            address: vec![],
+            connected: link.connected(),
+            remote: link.remote,
+            rtt: link.rtt,
+            rx_bytes: link.transfer.rx_bytes,
+            rx_datagrams: link.transfer.rx_datagrams,
+            tx_bytes: link.transfer.tx_bytes,
+            tx_datagrams: link.transfer.tx_datagrams,
+            last_handshake: link.last_handshake,
+            dropped,
+            not_after: validity.map(|validity| validity.not_after.into()),
+            acl: vec![],
+            forward: vec![],
+        }
+    }
+}
+
+impl From<PeerStatus> for PeerInfo {
+    fn from(status: PeerStatus) -> Self {
+        // peers recognized by a name or key are shown with the certificate they connected with
+        let (cert, identity) = match &status.identity {
+            Identity::Chain(chain) => (chain.first(), None),
+            identity => (
+                status
+                    .link
+                    .cert_chain
+                    .as_ref()
+                    .and_then(|chain| chain.first()),
+                Some(identity.to_string()),
+            ),
+        };
+        PeerInfo {
+            address: status.address.iter().map(IpAddr::to_string).collect(),
+            acl: status.acl,
+            ..PeerInfo::new(
+                cert,
+                identity,
+                status
+                    .allowed_ips
+                    .iter()
+                    .map(|(ip, cidr)| format!("{ip}/{cidr}")),
+                &status.link,
+                status.dropped,
+            )
+        }
+    }
+}
+
+fn clients_mut(conf: &mut Conf) -> anyhow::Result<&mut Vec<ClientPeer>> {
+    match &mut conf.network {
+        Network::Server { client, .. } => Ok(client),
+        Network::Client { .. } => anyhow::bail!("peers can only be managed on a server"),
+    }
+}
+
+fn is_peer(client: &ClientPeer, peer: &Identity) -> bool {
+    crate::peer_identity(client.client_cert.as_deref(), client.identity.as_ref())
+        .is_ok_and(|identity| identity == *peer)
+}
+
+fn subject(cert: &Certificate) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    Some(cert.subject().to_string())
+}
+
+/// A listening control socket, removed from the filesystem when dropped.
+pub struct ControlSocket {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Binds a control socket at `path` and serves requests about the tunnels of `supervisor`
+/// in the background.
+pub fn serve(path: &Path, supervisor: Arc<Supervisor>) -> io::Result<ControlSocket> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // a socket left behind by a previous run is replaced, one still served by another
+    // vqn is not
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("another vqn is serving {}", path.display()),
+        ));
+    }
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+    let task = tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let supervisor = Arc::clone(&supervisor);
+            tokio::spawn(async move {
+                if let Err(e) = handle(stream, &supervisor).await {
+                    tracing::debug!("control connection error: {e}");
+                }
+            });
+        }
+    });
+
+    Ok(ControlSocket {
+        path: path.to_path_buf(),
+        task,
+    })
+}
+
+async fn handle(stream: UnixStream, supervisor: &Arc<Supervisor>) -> io::Result<()> {
+    let (r, mut w) = stream.into_split();
+    let Some(line) = BufReader::new(r).lines().next_line().await? else {
+        return Ok(());
+    };
+
+    let Message { interface, request } = match serde_json::from_str(&line) {
+        Ok(message) => message,
+        Err(e) => return write(&mut w, &Response::Error(format!("invalid request: {e}"))).await,
+    };
+    let interface = interface.as_deref();
+
+    let response = match request {
+        Request::Show => match tunnels(supervisor, interface) {
+            Ok(tunnels) => Response::Tunnels(tunnels),
+            Err(e) => Response::Error(format!("{e:#}")),
+        },
+        Request::Restart => match supervisor.restart(interface).await {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error(format!("{e:#}")),
+        },
+        Request::Events => match supervisor.tunnel(interface) {
+            Ok(tunnel) => return stream_events(&mut w, supervisor, &tunnel).await,
+            Err(e) => Response::Error(format!("{e:#}")),
+        },
+        Request::Peer(request) => match supervisor.tunnel(interface) {
+            // peer changes read certificates and reconcile the firewall
+            Ok(tunnel) => tokio::task::spawn_blocking(move || tunnel.handle(request))
+                .await
+                .unwrap_or_else(|e| Response::Error(format!("request failed: {e}"))),
+            Err(e) => Response::Error(format!("{e:#}")),
+        },
+    };
+
+    write(&mut w, &response).await
+}
+
+fn tunnels(supervisor: &Supervisor, interface: Option<&str>) -> anyhow::Result<Vec<TunnelInfo>> {
+    let tunnels: Vec<_> = supervisor
+        .tunnels()
+        .into_iter()
+        .filter(|(name, ..)| interface.is_none_or(|interface| name == interface))
+        .map(|(interface, state, tunnel)| TunnelInfo {
+            interface,
+            state,
+            peers: tunnel.map(|tunnel| tunnel.peers()).unwrap_or_default(),
+        })
+        .collect();
+    if let Some(interface) = interface {
+        anyhow::ensure!(!tunnels.is_empty(), "no tunnel on {interface}");
+    }
+
+    Ok(tunnels)
+}
+
+// streams the events of `tunnel` until it stops, after which a restarted tunnel has
+// events of its own to subscribe to
+async fn stream_events(
+    w: &mut OwnedWriteHalf,
+    supervisor: &Supervisor,
+    tunnel: &Arc<Tunnel>,
+) -> io::Result<()> {
+    let Tunnel::Server { router, .. } = &**tunnel else {
+        let e = Response::Error("events are only available on a server".to_owned());
+        return write(w, &e).await;
+    };
+
+    let mut events = router.subscribe();
+    let stopped = supervisor.stopped(tunnel);
+    tokio::pin!(stopped);
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            () = &mut stopped => return Ok(()),
+        };
+        match event {
+            Ok(event) => write(w, &Response::Event(event.into())).await?,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+async fn write(w: &mut OwnedWriteHalf, response: &Response) -> io::Result<()> {
+    let mut response = serde_json::to_vec(response)?;
+    response.push(b'\n');
+
+    w.write_all(&response).await
+}
+
+async fn send(
+    path: &Path,
+    interface: Option<&str>,
+    request: Request,
+) -> anyhow::Result<Lines<BufReader<OwnedReadHalf>>> {
+    let stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("failed to connect to {}", path.display()))?;
+    let (r, mut w) = stream.into_split();
+
+    let message = Message {
+        interface: interface.map(str::to_owned),
+        request,
+    };
+    let mut line = serde_json::to_vec(&message)?;
+    line.push(b'\n');
+    w.write_all(&line).await?;
+
+    Ok(BufReader::new(r).lines())
+}
+
+/// Sends `request` for the tunnel on `interface` to the control socket at `path` and
+/// waits for the response.
+pub async fn request(
+    path: &Path,
+    interface: Option<&str>,
+    request: Request,
+) -> anyhow::Result<Response> {
+    let line = send(path, interface, request)
+        .await?
+        .next_line()
+        .await?
+        .context("control socket closed without a response")?;
+
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Subscribes to peer events of the tunnel on `interface` from the control socket at
+/// `path`, calling `f` on each event until the socket is closed.
+pub async fn events(
+    path: &Path,
+    interface: Option<&str>,
+    mut f: impl FnMut(EventInfo),
+) -> anyhow::Result<()> {
+    let mut lines = send(path, interface, Request::Events).await?;
+    while let Some(line) = lines.next_line().await? {
+        match serde_json::from_str(&line)? {
+            Response::Event(event) => f(event),
+            Response::Error(e) => anyhow::bail!(e),
+            response => anyhow::bail!("unexpected response: {response:?}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints tunnels and their peers in a format similar to `wg show`.
+pub fn print_tunnels(tunnels: &[TunnelInfo]) {
+    for (i, tunnel) in tunnels.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!("interface: {}", tunnel.interface);
+        println!("  state: {}", tunnel.state);
+        print_peers(&tunnel.peers);
+    }
+}
+
+fn print_peers(peers: &[PeerInfo]) {
+    for peer in peers {
+        println!();
+        match (&peer.identity, &peer.subject) {
+            (Some(identity), subject) => {
+                println!("peer: {identity}");
+                if let Some(subject) = subject {
+                    println!("  subject: {subject}");
+                }
+            }
+            (None, subject) => println!("peer: {}", subject.as_deref().unwrap_or("(unknown)")),
+        }
+        if !peer.address.is_empty() {
+            println!("  address: {}", peer.address.join(", "));
+        }
+        println!("  allowed ips: {}", peer.allowed_ips.join(", "));
+        if peer.connected {
+            println!("  status: connected");
+        } else {
+            println!("  status: disconnected");
+        }
+        if let Some(remote) = peer.remote {
+            println!("  endpoint: {remote}");
+        }
+        if let Some(rtt) = peer.rtt {
+            println!("  rtt: {:.1}ms", rtt.as_secs_f64() * 1000.0);
+        }
+        if let Some(t) = peer.last_handshake {
+            let ago = SystemTime::now().duration_since(t).unwrap_or_default();
+            println!("  latest handshake: {} ago", human_duration(ago));
+        }
+        if peer.connected {
+            println!(
+                "  transfer: {} received ({} datagrams), {} sent ({} datagrams)",
+                human_bytes(peer.rx_bytes),
+                peer.rx_datagrams,
+                human_bytes(peer.tx_bytes),
+                peer.tx_datagrams
+            );
+        }
+        if peer.dropped > 0 {
+            println!(
+                "  dropped: {} datagrams with disallowed source",
+                peer.dropped
+            );
+        }
+        if let Some(not_after) = peer.not_after {
+            println!(
+                "  certificate: {}",
+                human_expiry(not_after, SystemTime::now())
+            );
+        }
+        if !peer.acl.is_empty() {
+            println!("  acl:");
+            for (rule, hits) in &peer.acl {
+                println!("    {rule}: {hits} packets");
+            }
+        }
+        if !peer.forward.is_empty() {
+            println!("  forward:");
+            for forward in &peer.forward {
+                println!(
+                    "    {}: {} active, {} total, {} relayed",
+                    forward.forward,
+                    forward.active,
+                    forward.total,
+                    human_bytes(forward.bytes)
+                );
+            }
+        }
+    }
+}
+
+fn human_expiry(not_after: SystemTime, now: SystemTime) -> String {
+    const DAY: u64 = 24 * 60 * 60;
+    match not_after.duration_since(now) {
+        Ok(left) => match left.as_secs() / DAY {
+            0 => "expires today".to_owned(),
+            1 => "expires in 1 day".to_owned(),
+            days => format!("expires in {days} days"),
+        },
+        Err(e) => match e.duration().as_secs() / DAY {
+            0 => "expired today".to_owned(),
+            1 => "expired 1 day ago".to_owned(),
+            days => format!("expired {days} days ago"),
+        },
+    }
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.2} {}", UNITS[unit])
+    }
+}
+
+fn human_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match secs {
+        0..=59 => format!("{secs} seconds"),
+        60..=3599 => format!("{} minutes, {} seconds", secs / 60, secs % 60),
+        _ => format!("{} hours, {} minutes", secs / 3600, (secs % 3600) / 60),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use quinn::ConnectionError;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    // a server tunnel without clients, with its router and live configuration
+    fn server() -> (Arc<core::Router>, Arc<watch::Sender<Conf>>, Tunnel) {
+        let conf = Conf::parse_from(
+            r#"
+[tls]
+key = "./key.pem"
+ca_cert = "./ca_cert.pem"
+
+[network]
+role = "server"
+address = "10.10.0.1/24"
+client = []
+"#,
+        )
+        .unwrap();
+        let router = Arc::new(core::Router::default());
+        let conf = Arc::new(watch::channel(conf).0);
+        let tunnel = Tunnel::Server {
+            router: Arc::clone(&router),
+            conf: Arc::clone(&conf),
+            forwards: Arc::new(Forwards::new(Arc::clone(&router))),
+        };
+
+        (router, conf, tunnel)
+    }
+
+    #[test]
+    fn test_messages() {
+        let message = Message {
+            interface: Some("tun0".to_owned()),
+            request: Request::Show,
+        };
+        let line = serde_json::to_string(&message).unwrap();
+        assert_eq!(line, r#"{"interface":"tun0","command":"show"}"#);
+        let message: Message = serde_json::from_str(&line).unwrap();
+        assert_eq!(message.interface.as_deref(), Some("tun0"));
+        assert!(matches!(message.request, Request::Show));
+
+        let message: Message = serde_json::from_str(r#"{"command":"events"}"#).unwrap();
+        assert_eq!(message.interface, None);
+        assert!(matches!(message.request, Request::Events));
+
+        let line = r#"{"command":"peer_kick","identity":"cn:alice","reason":"bye"}"#;
+        let message: Message = serde_json::from_str(line).unwrap();
+        let Request::Peer(PeerRequest::Kick {
+            cert,
+            identity,
+            reason,
+        }) = message.request
+        else {
+            panic!("not a kick: {:?}", message.request);
+        };
+        assert_eq!(cert, None);
+        assert_eq!(identity, Some(Identity::CommonName("alice".to_owned())));
+        assert_eq!(reason.as_deref(), Some("bye"));
+
+        assert!(serde_json::from_str::<Message>(r#"{"command":"bogus"}"#).is_err());
+    }
+
+    #[test]
+    fn test_responses() {
+        let response = Response::Tunnels(vec![TunnelInfo {
+            interface: "tun0".to_owned(),
+            state: TunnelState::Failed("no route".to_owned()),
+            peers: vec![],
+        }]);
+        let line = serde_json::to_string(&response).unwrap();
+        let Response::Tunnels(tunnels) = serde_json::from_str(&line).unwrap() else {
+            panic!("not tunnels: {line}");
+        };
+        assert_eq!(tunnels[0].interface, "tun0");
+        assert_eq!(tunnels[0].state, TunnelState::Failed("no route".to_owned()));
+
+        let line = serde_json::to_string(&Response::Error("no tunnel on tun1".to_owned()));
+        assert_eq!(line.unwrap(), r#"{"error":"no tunnel on tun1"}"#);
+        let line = serde_json::to_string(&Response::Ok).unwrap();
+        assert!(matches!(serde_json::from_str(&line).unwrap(), Response::Ok));
+
+        let event = Response::Event(EventInfo::Disconnected {
+            subject: Some("CN=alice".to_owned()),
+            remote: "192.0.2.1:10086".parse().unwrap(),
+            reason: "timed out".to_owned(),
+        });
+        let line = serde_json::to_string(&event).unwrap();
+        let Response::Event(event) = serde_json::from_str(&line).unwrap() else {
+            panic!("not an event: {line}");
+        };
+        assert_eq!(
+            event.to_string(),
+            "peer CN=alice disconnected from 192.0.2.1:10086: timed out"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_peer_requests() {
+        let (router, conf, tunnel) = server();
+        let alice = Identity::CommonName("alice".to_owned());
+        let add = || PeerRequest::Add {
+            cert: None,
+            identity: Some(alice.clone()),
+            allowed_ips: AllowedIps::default(),
+        };
+        let remove = || PeerRequest::Remove {
+            cert: None,
+            identity: Some(alice.clone()),
+        };
+        let kick = |identity: &Identity| PeerRequest::Kick {
+            cert: None,
+            identity: Some(identity.clone()),
+            reason: None,
+        };
+        let error = |response| match response {
+            Response::Error(e) => e,
+            response => panic!("not an error: {response:?}"),
+        };
+        let clients = || match &conf.borrow().network {
+            Network::Server { client, .. } => client.len(),
+            Network::Client { .. } => unreachable!(),
+        };
+
+        assert!(matches!(tunnel.handle(add()), Response::Ok));
+        assert_eq!(clients(), 1);
+        assert_eq!(error(tunnel.handle(add())), "peer is already configured");
+        assert_eq!(error(tunnel.handle(kick(&alice))), "peer is not connected");
+        assert!(matches!(tunnel.handle(remove()), Response::Ok));
+        assert_eq!(clients(), 0);
+        assert_eq!(error(tunnel.handle(remove())), "peer is not configured");
+
+        let peers = core::connect_peers(&router, &[("10.10.0.2".parse().unwrap(), 32)]).await;
+        let (bob, client, _) = &peers[0];
+        assert!(matches!(tunnel.handle(kick(bob)), Response::Ok));
+        assert!(matches!(
+            client.closed().await,
+            ConnectionError::ApplicationClosed(close) if close.error_code == core::PEER_KICKED
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let supervisor = Supervisor::new();
+        let (router, conf, tunnel) = server();
+        let running = supervisor.add_running(conf, tunnel);
+        let peers = core::connect_peers(&router, &[("10.10.0.2".parse().unwrap(), 32)]).await;
+        let (_, _, conn) = &peers[0];
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let task = tokio::spawn({
+            let supervisor = Arc::clone(&supervisor);
+            async move { handle(server, &supervisor).await }
+        });
+        let (r, mut w) = client.into_split();
+        w.write_all(b"{\"command\":\"events\"}\n").await.unwrap();
+        let mut lines = BufReader::new(r).lines();
+
+        // events sent before the stream subscribed are missed, so keep sending them
+        let line = loop {
+            router.disconnected(conn).await;
+            if let Ok(line) = timeout(Duration::from_millis(10), lines.next_line()).await {
+                break line.unwrap().unwrap();
+            }
+        };
+        let Response::Event(EventInfo::Disconnected { remote, .. }) =
+            serde_json::from_str(&line).unwrap()
+        else {
+            panic!("not a disconnection: {line}");
+        };
+        assert_eq!(remote, conn.remote_address());
+
+        // the stream ends with the tunnel, which a restart replaces by a new one
+        running.stop();
+        timeout(Duration::from_secs(5), async {
+            while let Some(line) = lines.next_line().await.unwrap() {
+                assert!(line.starts_with(r#"{"event":"#), "{line}");
+            }
+        })
+        .await
+        .unwrap();
+        task.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,333 @@
+//! Packet filters applied to the traffic of a peer.
+//!
+//! The rules of an [Acl] describe the far side of the tunnel: the address, protocol
+//! and port a peer sends traffic to, which are the ones replies to the peer come from.
+//! Rules are evaluated in order, the first one matching a packet decides its fate.
+//!
+//! Only the first fragment of a TCP or UDP packet carries its ports. Later fragments
+//! are matched by the allow rules their first fragment could have been allowed by, and
+//! never by deny rules with ports: they cannot be reassembled once their first fragment
+//! has been dropped. Other packets without ports are only matched by rules without them.
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::pool::contains;
+
+const IPPROTO_HOPOPTS: u8 = 0;
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_ROUTING: u8 = 43;
+const IPPROTO_FRAGMENT: u8 = 44;
+const IPPROTO_ICMPV6: u8 = 58;
+const IPPROTO_DSTOPTS: u8 = 60;
+
+/// What happens to a packet matched by a [Rule].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// The protocols a [Rule] can match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    /// ICMP or ICMPv6.
+    Icmp,
+}
+
+/// Which way a packet travels through the tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the peer.
+    FromPeer,
+    /// Sent to the peer.
+    ToPeer,
+}
+
+/// A rule matching packets by the address, protocol and port range on the far side of
+/// the tunnel. Conditions left out match any packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub action: Action,
+    pub dst: Option<(IpAddr, u8)>,
+    pub protocol: Option<Protocol>,
+    /// Inclusive range of TCP or UDP ports.
+    pub ports: Option<(u16, u16)>,
+}
+
+impl Rule {
+    fn matches(&self, header: &Header) -> bool {
+        self.dst
+            .is_none_or(|(net, prefix)| contains(net, prefix, header.remote))
+            && self.protocol.is_none_or(|protocol| match protocol {
+                Protocol::Tcp => header.protocol == IPPROTO_TCP,
+                Protocol::Udp => header.protocol == IPPROTO_UDP,
+                Protocol::Icmp => matches!(header.protocol, IPPROTO_ICMP | IPPROTO_ICMPV6),
+            })
+            && self.ports.is_none_or(|(first, last)| match header.port {
+                Some(port) => (first..=last).contains(&port),
+                None => header.later_fragment && self.action == Action::Allow,
+            })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.action {
+            Action::Allow => "allow",
+            Action::Deny => "deny",
+        })?;
+        match self.protocol {
+            Some(Protocol::Tcp) => f.write_str(" tcp")?,
+            Some(Protocol::Udp) => f.write_str(" udp")?,
+            Some(Protocol::Icmp) => f.write_str(" icmp")?,
+            None => (),
+        }
+        match self.dst {
+            Some((ip, prefix)) => write!(f, " to {ip}/{prefix}")?,
+            None => f.write_str(" to any")?,
+        }
+        match self.ports {
+            Some((first, last)) if first == last => write!(f, " port {first}"),
+            Some((first, last)) => write!(f, " port {first}-{last}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// An ordered list of [Rule]s with a default action for packets none of them match,
+/// counting the packets each of them matched.
+#[derive(Debug)]
+pub struct Acl {
+    rules: Vec<Rule>,
+    default: Action,
+    // one per rule, followed by the default action's
+    hits: Vec<AtomicU64>,
+}
+
+impl Acl {
+    pub fn new(rules: Vec<Rule>, default: Action) -> Self {
+        let hits = (0..=rules.len()).map(|_| AtomicU64::new(0)).collect();
+
+        Acl {
+            rules,
+            default,
+            hits,
+        }
+    }
+
+    /// Returns `true` if `packet`, travelling in `direction`, is allowed, counting the
+    /// hit of the rule that decided it.
+    pub fn allows(&self, packet: &[u8], direction: Direction) -> bool {
+        let matched = Header::parse(packet, direction)
+            .and_then(|header| self.rules.iter().position(|rule| rule.matches(&header)));
+        let (i, action) = match matched {
+            Some(i) => (i, self.rules[i].action),
+            None => (self.rules.len(), self.default),
+        };
+        self.hits[i].fetch_add(1, Ordering::Relaxed);
+
+        action == Action::Allow
+    }
+
+    /// Returns `true` if this ACL has the same rules and default action as `other`.
+    pub fn same_rules(&self, other: &Acl) -> bool {
+        self.rules == other.rules && self.default == other.default
+    }
+
+    /// Returns every rule, and the default action last, with the number of packets it
+    /// matched.
+    pub fn hits(&self) -> Vec<(String, u64)> {
+        let default = match self.default {
+            Action::Allow => "default allow".to_owned(),
+            Action::Deny => "default deny".to_owned(),
+        };
+
+        self.rules
+            .iter()
+            .map(Rule::to_string)
+            .chain([default])
+            .zip(&self.hits)
+            .map(|(rule, hits)| (rule, hits.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+// the far side of a packet: its destination if the peer sent it, its source otherwise
+struct Header {
+    remote: IpAddr,
+    protocol: u8,
+    // the TCP or UDP port, unless the packet is a later fragment
+    port: Option<u16>,
+    // whether the packet is a later fragment of a TCP or UDP packet
+    later_fragment: bool,
+}
+
+impl Header {
+    fn parse(packet: &[u8], direction: Direction) -> Option<Self> {
+        let (src, dst, mut protocol, mut offset, mut fragment) = match packet.first()? >> 4 {
+            4 if packet.len() >= 20 => {
+                let src: [u8; 4] = packet[12..16].try_into().unwrap();
+                let dst: [u8; 4] = packet[16..20].try_into().unwrap();
+                let fragment = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff != 0;
+                let ihl = (packet[0] & 0x0f) as usize * 4;
+                (
+                    IpAddr::from(src),
+                    IpAddr::from(dst),
+                    packet[9],
+                    ihl,
+                    fragment,
+                )
+            }
+            6 if packet.len() >= 40 => {
+                let src: [u8; 16] = packet[8..24].try_into().unwrap();
+                let dst: [u8; 16] = packet[24..40].try_into().unwrap();
+                (IpAddr::from(src), IpAddr::from(dst), packet[6], 40, false)
+            }
+            _ => return None,
+        };
+
+        // the upper layer protocol follows IPv6 extension headers
+        while src.is_ipv6() {
+            let ext = packet.get(offset..offset + 8);
+            match (protocol, ext) {
+                (IPPROTO_HOPOPTS | IPPROTO_ROUTING | IPPROTO_DSTOPTS, Some(ext)) => {
+                    protocol = ext[0];
+                    offset += (ext[1] as usize + 1) * 8;
+                }
+                (IPPROTO_FRAGMENT, Some(ext)) => {
+                    protocol = ext[0];
+                    fragment |= u16::from_be_bytes([ext[2], ext[3]]) >> 3 != 0;
+                    offset += 8;
+                }
+                _ => break,
+            }
+        }
+
+        let ports = match protocol {
+            IPPROTO_TCP | IPPROTO_UDP if !fragment => packet.get(offset..offset + 4),
+            _ => None,
+        };
+        let (remote, port) = match direction {
+            Direction::FromPeer => (dst, ports.map(|p| u16::from_be_bytes([p[2], p[3]]))),
+            Direction::ToPeer => (src, ports.map(|p| u16::from_be_bytes([p[0], p[1]]))),
+        };
+
+        Some(Header {
+            remote,
+            protocol,
+            port,
+            later_fragment: fragment && matches!(protocol, IPPROTO_TCP | IPPROTO_UDP),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // an IPv4 TCP packet from 10.10.0.2:40000 to `dst`:`port`
+    fn tcp(dst: [u8; 4], port: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; 40];
+        packet[0] = 0x45;
+        packet[9] = IPPROTO_TCP;
+        packet[12..16].copy_from_slice(&[10, 10, 0, 2]);
+        packet[16..20].copy_from_slice(&dst);
+        packet[20..22].copy_from_slice(&40000_u16.to_be_bytes());
+        packet[22..24].copy_from_slice(&port.to_be_bytes());
+        packet
+    }
+
+    // the reply to `packet`
+    fn reply(packet: &[u8]) -> Vec<u8> {
+        let mut reply = packet.to_vec();
+        reply[12..16].copy_from_slice(&packet[16..20]);
+        reply[16..20].copy_from_slice(&packet[12..16]);
+        reply[20..22].copy_from_slice(&packet[22..24]);
+        reply[22..24].copy_from_slice(&packet[20..22]);
+        reply
+    }
+
+    #[test]
+    fn test_acl() {
+        let allow_tcp = |ports| Rule {
+            action: Action::Allow,
+            dst: Some(("10.20.0.0".parse().unwrap(), 16)),
+            protocol: Some(Protocol::Tcp),
+            ports: Some(ports),
+        };
+        let acl = Acl::new(
+            vec![allow_tcp((443, 443)), allow_tcp((22, 22))],
+            Action::Deny,
+        );
+
+        let https = tcp([10, 20, 1, 1], 443);
+        assert!(acl.allows(&https, Direction::FromPeer));
+        assert!(acl.allows(&reply(&https), Direction::ToPeer));
+        assert!(acl.allows(&tcp([10, 20, 1, 1], 22), Direction::FromPeer));
+        assert!(!acl.allows(&tcp([10, 20, 1, 1], 80), Direction::FromPeer));
+        assert!(!acl.allows(&tcp([10, 30, 1, 1], 443), Direction::FromPeer));
+        // a connection to the peer's port 443 is no reply
+        let mut inbound = reply(&tcp([10, 20, 1, 1], 40000));
+        inbound[22..24].copy_from_slice(&443_u16.to_be_bytes());
+        assert!(!acl.allows(&inbound, Direction::ToPeer));
+
+        let mut udp = tcp([10, 20, 1, 1], 443);
+        udp[9] = IPPROTO_UDP;
+        assert!(!acl.allows(&udp, Direction::FromPeer));
+
+        // later fragments pass the allow rules but no others
+        let mut fragment = tcp([10, 20, 1, 1], 0);
+        fragment[7] = 1;
+        assert!(acl.allows(&fragment, Direction::FromPeer));
+        fragment[16..20].copy_from_slice(&[10, 30, 1, 1]);
+        assert!(!acl.allows(&fragment, Direction::FromPeer));
+
+        assert_eq!(
+            acl.hits(),
+            [
+                ("allow tcp to 10.20.0.0/16 port 443".to_owned(), 3),
+                ("allow tcp to 10.20.0.0/16 port 22".to_owned(), 1),
+                ("default deny".to_owned(), 5),
+            ]
+        );
+
+        // deny rules with ports leave later fragments to the rules after them
+        let deny_ssh = Rule {
+            action: Action::Deny,
+            ..allow_tcp((22, 22))
+        };
+        let acl = Acl::new(vec![deny_ssh], Action::Allow);
+        fragment[16..20].copy_from_slice(&[10, 20, 1, 1]);
+        assert!(acl.allows(&fragment, Direction::FromPeer));
+        assert!(!acl.allows(&tcp([10, 20, 1, 1], 22), Direction::FromPeer));
+    }
+
+    #[test]
+    fn test_header() {
+        let mut packet = vec![0u8; 60];
+        packet[0] = 0x60;
+        packet[6] = IPPROTO_HOPOPTS;
+        packet[8..24].copy_from_slice(&"fd00::2".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        packet[24..40].copy_from_slice(&"fd20::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        // a hop-by-hop options header followed by UDP to port 53
+        packet[40] = IPPROTO_UDP;
+        packet[50..52].copy_from_slice(&53_u16.to_be_bytes());
+
+        let header = Header::parse(&packet, Direction::FromPeer).unwrap();
+        assert_eq!(header.remote, "fd20::1".parse::<IpAddr>().unwrap());
+        assert_eq!((header.protocol, header.port), (IPPROTO_UDP, Some(53)));
+
+        // later fragments carry no ports
+        let mut fragment = tcp([10, 20, 1, 1], 443);
+        fragment[7] = 1;
+        let header = Header::parse(&fragment, Direction::FromPeer).unwrap();
+        assert_eq!((header.protocol, header.port), (IPPROTO_TCP, None));
+
+        assert!(Header::parse(&[0x45; 10], Direction::FromPeer).is_none());
+    }
+}
@@ -46,6 +46,14 @@ impl<D> AllowedIps<D> {
         )
     }
 
+    /// Retains only the entries for which the predicate returns `true`.
+    ///
+    /// # Arguments
+    /// * `f` - A predicate called with a reference to the data of each entry.
+    pub fn retain(&mut self, mut f: impl FnMut(&D) -> bool) {
+        self.ips.retain(|_net, data| f(data))
+    }
+
     /// Retrieves the data associated with the longest matching IP address.
     ///
     /// # Arguments
@@ -61,7 +69,7 @@ impl<D> AllowedIps<D> {
     ///
     /// # Returns
     /// An iterator that yields tuples of a data reference, IP address, and CIDR notation
-    pub fn iter(&self) -> Iter<D> {
+    pub fn iter(&self) -> Iter<'_, D> {
         Iter(
             self.ips
                 .iter()
@@ -0,0 +1,238 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use rustls::Certificate;
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+use x509_parser::extensions::GeneralName;
+
+/// What a configured client is recognized by in the certificate chain it presents.
+///
+/// All but [Identity::Chain] are written as `spki-sha256:<hex>`, `cn:<name>` and
+/// `san:<name>` in configuration files and on the command line.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Identity {
+    /// The whole certificate chain, byte for byte.
+    Chain(Vec<Certificate>),
+    /// SHA-256 of the end-entity certificate's SubjectPublicKeyInfo, which stays the same
+    /// when the certificate is renewed with the same key.
+    SpkiSha256([u8; 32]),
+    /// A common name in the end-entity certificate's subject.
+    CommonName(String),
+    /// A DNS name, email address, URI or IP address among the end-entity certificate's
+    /// subject alternative names.
+    SubjectAltName(String),
+}
+
+impl Identity {
+    /// Returns every identity `cert_chain` can be recognized by, most specific first.
+    pub fn of(cert_chain: &[Certificate]) -> Vec<Identity> {
+        let mut identities = vec![Identity::Chain(cert_chain.to_vec())];
+        let Some(Ok((_, cert))) = cert_chain
+            .first()
+            .map(|cert| x509_parser::parse_x509_certificate(&cert.0))
+        else {
+            return identities;
+        };
+
+        identities.push(Identity::SpkiSha256(digest(cert.public_key().raw)));
+        identities.extend(
+            cert.subject()
+                .iter_common_name()
+                .filter_map(|cn| cn.as_str().ok())
+                .map(|cn| Identity::CommonName(cn.to_owned())),
+        );
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            identities.extend(san.value.general_names.iter().filter_map(|name| {
+                let name = match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => name.to_string(),
+                    GeneralName::IPAddress(ip) => match ip.len() {
+                        4 => IpAddr::from(<[u8; 4]>::try_from(*ip).ok()?).to_string(),
+                        16 => IpAddr::from(<[u8; 16]>::try_from(*ip).ok()?).to_string(),
+                        _ => return None,
+                    },
+                    _ => return None,
+                };
+                Some(Identity::SubjectAltName(name))
+            }));
+        }
+
+        identities
+    }
+}
+
+/// SHA-256 of the SubjectPublicKeyInfo of `cert`, or `None` if it cannot be parsed.
+pub fn spki_sha256(cert: &Certificate) -> Option<[u8; 32]> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    Some(digest(cert.public_key().raw))
+}
+
+/// SHA-256 of a DER-encoded SubjectPublicKeyInfo.
+pub fn digest(spki: &[u8]) -> [u8; 32] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, spki);
+    digest.as_ref().try_into().unwrap()
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Identity::Chain(chain) => {
+                let subject = chain.first().and_then(|cert| {
+                    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+                    Some(cert.subject().to_string())
+                });
+                f.write_str(subject.as_deref().unwrap_or("(unknown)"))
+            }
+            Identity::SpkiSha256(hash) => {
+                f.write_str("spki-sha256:")?;
+                hash.iter().try_for_each(|b| write!(f, "{b:02x}"))
+            }
+            Identity::CommonName(cn) => write!(f, "cn:{cn}"),
+            Identity::SubjectAltName(san) => write!(f, "san:{san}"),
+        }
+    }
+}
+
+impl FromStr for Identity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = s
+            .split_once(':')
+            .filter(|(_, value)| !value.is_empty())
+            .ok_or_else(|| format!("expected spki-sha256:<hex>, cn:<name> or san:<name>: {s}"))?;
+
+        match kind {
+            "spki-sha256" => {
+                // accepts the `AB:CD:...` form printed by openssl as well
+                let hex: String = value.chars().filter(|c| *c != ':').collect();
+                let hash = (0..hex.len())
+                    .step_by(2)
+                    .map(|i| {
+                        hex.get(i..i + 2)
+                            .and_then(|b| u8::from_str_radix(b, 16).ok())
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .and_then(|hash| <[u8; 32]>::try_from(hash).ok())
+                    .ok_or_else(|| format!("invalid SHA-256 fingerprint: {value}"))?;
+                Ok(Identity::SpkiSha256(hash))
+            }
+            "cn" => Ok(Identity::CommonName(value.to_owned())),
+            "san" => Ok(Identity::SubjectAltName(value.to_owned())),
+            _ => Err(format!(
+                "unknown identity {kind:?}, expected spki-sha256, cn or san"
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Identity {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for Identity {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Identity::Chain(_) => Err(ser::Error::custom(
+                "a certificate chain cannot be written as an identity",
+            )),
+            identity => serializer.collect_str(identity),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_identities() {
+        let key = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let key_der = key.serialize_der();
+        let cert = |serial: u64| {
+            let mut params = rcgen::CertificateParams::new(["alice.example.org".to_owned()]);
+            params
+                .subject_alt_names
+                .push(rcgen::SanType::IpAddress("10.10.0.2".parse().unwrap()));
+            params.distinguished_name = rcgen::DistinguishedName::new();
+            params
+                .distinguished_name
+                .push(rcgen::DnType::CommonName, "alice");
+            params.serial_number = Some(serial.into());
+            params.key_pair = Some(rcgen::KeyPair::from_der(&key_der).unwrap());
+            let cert = rcgen::Certificate::from_params(params).unwrap();
+            vec![Certificate(cert.serialize_der().unwrap())]
+        };
+
+        let chain = cert(1);
+        let identities = Identity::of(&chain);
+        let spki = ring::digest::digest(&ring::digest::SHA256, &key.public_key_der());
+        assert_eq!(
+            identities,
+            [
+                Identity::Chain(chain),
+                Identity::SpkiSha256(spki.as_ref().try_into().unwrap()),
+                Identity::CommonName("alice".to_owned()),
+                Identity::SubjectAltName("alice.example.org".to_owned()),
+                Identity::SubjectAltName("10.10.0.2".to_owned()),
+            ]
+        );
+
+        // a renewed certificate keeps all but the chain
+        let renewed = Identity::of(&cert(2));
+        assert_ne!(identities[0], renewed[0]);
+        assert_eq!(identities[1..], renewed[1..]);
+
+        assert_eq!(
+            Identity::of(&[Certificate(b"garbage".to_vec())]),
+            [Identity::Chain(vec![Certificate(b"garbage".to_vec())])]
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let hash = "3b".repeat(32);
+        assert_eq!(
+            format!("spki-sha256:{hash}").parse::<Identity>().unwrap(),
+            Identity::SpkiSha256([0x3b; 32])
+        );
+        let openssl = vec!["3B"; 32].join(":");
+        assert_eq!(
+            format!("spki-sha256:{openssl}")
+                .parse::<Identity>()
+                .unwrap(),
+            Identity::SpkiSha256([0x3b; 32])
+        );
+        assert_eq!(
+            Identity::SpkiSha256([0x3b; 32]).to_string(),
+            format!("spki-sha256:{hash}")
+        );
+        assert_eq!(
+            "cn:alice".parse::<Identity>().unwrap(),
+            Identity::CommonName("alice".to_owned())
+        );
+        assert_eq!(
+            "san:alice@example.org".parse::<Identity>().unwrap(),
+            Identity::SubjectAltName("alice@example.org".to_owned())
+        );
+
+        assert!("spki-sha256:3b3b".parse::<Identity>().is_err());
+        assert!(format!("spki-sha256:{}", "zz".repeat(32))
+            .parse::<Identity>()
+            .is_err());
+        assert!("cn:".parse::<Identity>().is_err());
+        assert!("alice".parse::<Identity>().is_err());
+        assert!("dn:alice".parse::<Identity>().is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime};
+
+use quinn::Connection;
+use rustls::Certificate;
+
+/// Tracks the current QUIC connection to a peer, if any.
+#[derive(Default)]
+pub struct Link {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    conn: Weak<Connection>,
+    last_handshake: Option<SystemTime>,
+}
+
+impl State {
+    fn live(&self) -> Option<Arc<Connection>> {
+        self.conn
+            .upgrade()
+            .filter(|conn| conn.close_reason().is_none())
+    }
+}
+
+/// A point-in-time snapshot of a [Link].
+#[derive(Debug, Clone, Default)]
+pub struct LinkStatus {
+    /// Certificate chain presented by the peer, if connected.
+    pub cert_chain: Option<Vec<Certificate>>,
+    pub remote: Option<SocketAddr>,
+    pub rtt: Option<Duration>,
+    pub transfer: Transfer,
+    pub last_handshake: Option<SystemTime>,
+}
+
+/// UDP traffic counters of the current connection.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Transfer {
+    pub rx_bytes: u64,
+    pub rx_datagrams: u64,
+    pub tx_bytes: u64,
+    pub tx_datagrams: u64,
+}
+
+impl LinkStatus {
+    pub fn connected(&self) -> bool {
+        self.remote.is_some()
+    }
+}
+
+impl Link {
+    /// Records `conn` as the current connection of this link, returning the previous
+    /// connection if it is still alive.
+    pub fn connected(&self, conn: &Arc<Connection>) -> Option<Arc<Connection>> {
+        let mut state = self.state.lock().unwrap();
+        let previous = state.live();
+        state.conn = Arc::downgrade(conn);
+        state.last_handshake = Some(SystemTime::now());
+
+        previous
+    }
+
+    /// Records `conn` as the current connection of this link, unless the link already has
+    /// a live connection, which is returned as an error instead.
+    pub fn try_connected(&self, conn: &Arc<Connection>) -> Result<(), Arc<Connection>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(existing) = state.live() {
+            return Err(existing);
+        }
+        state.conn = Arc::downgrade(conn);
+        state.last_handshake = Some(SystemTime::now());
+
+        Ok(())
+    }
+
+    /// Returns the current connection, if it is still alive.
+    pub fn connection(&self) -> Option<Arc<Connection>> {
+        self.state.lock().unwrap().live()
+    }
+
+    pub fn status(&self) -> LinkStatus {
+        let last_handshake = self.state.lock().unwrap().last_handshake;
+        let Some(conn) = self.connection() else {
+            return LinkStatus {
+                last_handshake,
+                ..Default::default()
+            };
+        };
+
+        let stats = conn.stats();
+        LinkStatus {
+            cert_chain: conn
+                .peer_identity()
+                .and_then(|ident| ident.downcast::<Vec<Certificate>>().ok())
+                .map(|certs| *certs),
+            remote: Some(conn.remote_address()),
+            rtt: Some(conn.rtt()),
+            transfer: Transfer {
+                rx_bytes: stats.udp_rx.bytes,
+                rx_datagrams: stats.udp_rx.datagrams,
+                tx_bytes: stats.udp_tx.bytes,
+                tx_datagrams: stats.udp_tx.datagrams,
+            },
+            last_handshake,
+        }
+    }
+}
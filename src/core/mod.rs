@@ -1,3 +1,4 @@
+use std::future::Future;
 use std::io;
 use std::time::Duration;
 use std::{net::IpAddr, sync::Arc};
@@ -5,22 +6,39 @@ use std::{net::IpAddr, sync::Arc};
 use bytes::Bytes;
 use futures::{SinkExt, StreamExt};
 use quinn::{Connection, ConnectionError, Endpoint, SendDatagramError};
-use rustls::Certificate;
 use thiserror::Error;
 use tokio::select;
 use tokio::sync::mpsc::{self, Receiver};
+use tokio::task::JoinSet;
 
+pub mod acl;
 mod allowed_ips;
 mod async_tun;
+mod identity;
+mod link;
+mod pool;
 mod router;
+pub mod session;
 
 pub mod rt;
 pub use async_tun::Iface;
 pub use tun;
 
+use acl::Direction;
 use async_tun::TunPacketCodec;
-use router::Router;
+pub use identity::{digest, spki_sha256, Identity};
+pub use link::{Link, LinkStatus};
+pub use pool::Pool;
+#[cfg(test)]
+pub(crate) use router::test::connect_peers;
+use router::Peer;
+pub use router::{
+    ClientToClient, DuplicatePolicy, PeerEvent, PeerIps, PeerStatus, Router, PEER_KICKED,
+    PEER_REVOKED,
+};
+use session::SessionConfig;
 use tokio_util::codec::Framed;
+use tracing::Instrument;
 use tun::Device;
 
 #[derive(Error, Debug)]
@@ -37,7 +55,9 @@ pub enum Error {
 /// The server uses one QUIC connection per client.
 pub struct Server {
     tun: Iface,
-    router: Router,
+    router: Arc<Router>,
+    duplicate: DuplicatePolicy,
+    client_to_client: ClientToClient,
 }
 
 impl Server {
@@ -45,62 +65,236 @@ impl Server {
     pub fn new(tun: Iface) -> Self {
         Self {
             tun,
-            router: Router::default(),
+            router: Arc::default(),
+            duplicate: DuplicatePolicy::default(),
+            client_to_client: ClientToClient::default(),
         }
     }
 
-    /// Configures a client, including their TLS certificate chain and permitted IP ranges.
-    /// Client connections are identified identified by their TLS certificates.
+    /// Sets what happens when a client connects while it already has a live connection.
+    pub fn set_duplicate_policy(&mut self, duplicate: DuplicatePolicy) {
+        self.duplicate = duplicate;
+    }
+
+    /// Sets whether clients may send traffic to each other.
+    pub fn set_client_to_client(&mut self, client_to_client: ClientToClient) {
+        self.client_to_client = client_to_client;
+    }
+
+    /// Returns a shared handle to the server's [Router], which can be used to update
+    /// the configured clients while the server is running.
+    pub fn router(&self) -> Arc<Router> {
+        Arc::clone(&self.router)
+    }
+
+    /// Assigns clients addresses out of `pool`, which they are sent when they connect.
+    pub fn set_pool(&mut self, pool: Pool) {
+        self.router.set_pool(pool);
+    }
+
+    /// Configures a client and its permitted IP ranges. Client connections are recognized
+    /// by the [Identity] of the certificate chain they present.
     pub fn add_client(
         &mut self,
-        cert_chain: Vec<Certificate>,
+        identity: Identity,
         allowed_ips: impl IntoIterator<Item = (IpAddr, u8)>,
     ) {
-        self.router.add_peer(cert_chain, allowed_ips);
+        self.router.add_peer(identity, allowed_ips);
     }
 
-    /// Asynchronously runs the server using a specified QUIC [Endpoint]
-    /// The function handles incoming connections and routes traffic through the tun interface.
-    pub async fn run(self, endpoint: Endpoint) -> Result<(), Error> {
+    /// Asynchronously runs the server using the specified QUIC [Endpoint]s until `shutdown`
+    /// completes.
+    /// The function handles incoming connections on every endpoint and routes traffic through
+    /// the tun interface. Once it returns, the tun interface has been closed.
+    pub async fn run(
+        self,
+        endpoints: impl IntoIterator<Item = Endpoint>,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), Error> {
         let (tx, rx) = mpsc::channel::<Bytes>(32);
 
-        let Server { tun, router } = self;
-        let router = Arc::new(router);
-        let r = Arc::clone(&router);
+        let Server {
+            tun,
+            router,
+            duplicate,
+            client_to_client,
+        } = self;
+
+        let mut tasks = JoinSet::new();
+        for endpoint in endpoints {
+            tasks.spawn(
+                accept_loop(
+                    endpoint,
+                    Arc::clone(&router),
+                    duplicate,
+                    client_to_client,
+                    tx.clone(),
+                )
+                .in_current_span(),
+            );
+        }
+        tasks.spawn(tun_loop(tun, router, client_to_client, rx).in_current_span());
+
+        tokio::pin!(shutdown);
+        let result = loop {
+            select! {
+                biased;
+                _ = &mut shutdown => break Ok(()),
+                result = tasks.join_next() => match result {
+                    Some(Ok(Ok(()))) => (),
+                    Some(result) => break result.unwrap(),
+                    None => break Ok(()),
+                },
+            }
+        };
+        // waits for the tasks to be dropped, releasing the tun interface
+        tasks.shutdown().await;
 
-        tokio::spawn(async move {
-            while let Some(conn) = endpoint.accept().await {
-                tracing::info!("incoming connection: {}", conn.remote_address());
+        result
+    }
+}
 
-                let router = Arc::clone(&router);
-                let tx = tx.clone();
-                tokio::spawn(async move {
-                    match conn.await {
-                        Ok(conn) => {
-                            let conn = router.connect(conn).await;
+async fn accept_loop(
+    endpoint: Endpoint,
+    router: Arc<Router>,
+    duplicate: DuplicatePolicy,
+    client_to_client: ClientToClient,
+    tx: mpsc::Sender<Bytes>,
+) -> Result<(), Error> {
+    let mut connections = JoinSet::new();
+    while let Some(conn) = endpoint.accept().await {
+        tracing::info!("incoming connection: {}", conn.remote_address());
+        while connections.try_join_next().is_some() {}
+
+        let router = Arc::clone(&router);
+        let tx = tx.clone();
+        let connection = async move {
+            match conn.await {
+                Ok(conn) => {
+                    let remote = conn.remote_address();
+                    let (conn, peer) = match router.connect(conn, duplicate).await {
+                        Ok(connected) => connected,
+                        Err(e) => {
+                            tracing::info!("rejected connection from {remote}: {e}");
+                            return;
+                        }
+                    };
+                    // the client is sent the pushed configuration with its own address
+                    let mut pushed = router.pushed();
+                    let config = |pushed: &SessionConfig| SessionConfig {
+                        address: peer.address(),
+                        ..pushed.clone()
+                    };
+                    let mut control = match session::Sender::open(&conn).await {
+                        Ok(control) => Some(control),
+                        Err(e) => {
+                            tracing::info!("failed to open control stream to {remote}: {e}");
+                            None
+                        }
+                    };
 
-                            while let Ok(dgram) = conn.read_datagram().await {
-                                let _ = tx.send(dgram).await;
+                    let mut update = Some(config(&pushed.borrow_and_update()));
+                    loop {
+                        if let (Some(config), Some(sender)) = (update.take(), &mut control) {
+                            if let Err(e) = sender.send(&config).await {
+                                tracing::info!("failed to configure {remote}: {e}");
+                                control = None;
                             }
                         }
-                        Err(err) => {
-                            tracing::trace!("Accept connection error: {err}");
+
+                        select! {
+                            dgram = conn.read_datagram() => {
+                                let Ok(dgram) = dgram else {
+                                    break;
+                                };
+                                match ip_src_address(&dgram) {
+                                    Some(src_ip)
+                                        if peer.allows(src_ip)
+                                            && !peer.filter(&dgram, Direction::FromPeer) =>
+                                    {
+                                        tracing::trace!("dropping packet from {remote}, denied by its acl");
+                                    }
+                                    Some(src_ip) if peer.allows(src_ip) => {
+                                        match next_hop(&router, &conn, client_to_client, &dgram).await {
+                                            Hop::Tun => {
+                                                let _ = tx.send(dgram).await;
+                                            }
+                                            Hop::Peer(other, other_peer) => {
+                                                if other_peer.filter(&dgram, Direction::ToPeer) {
+                                                    let _ = other.send_datagram(dgram);
+                                                }
+                                            }
+                                            Hop::Drop => {
+                                                tracing::trace!(
+                                                    "dropping packet from {remote}, client to client denied"
+                                                );
+                                            }
+                                        }
+                                    }
+                                    src_ip => {
+                                        let dropped = peer.record_dropped();
+                                        tracing::debug!(
+                                            "dropping packet from {remote}, source {src_ip:?} not allowed ({dropped} dropped)"
+                                        );
+                                    }
+                                }
+                            }
+                            Ok(()) = pushed.changed() => {
+                                update = Some(config(&pushed.borrow_and_update()));
+                            }
                         }
                     }
-                });
+
+                    router.disconnected(&conn).await;
+                }
+                Err(err) => {
+                    tracing::trace!("Accept connection error: {err}");
+                }
             }
-        });
+        };
+        connections.spawn(connection.in_current_span());
+    }
+
+    Ok(())
+}
 
-        let jh = tokio::spawn(async move { tun_loop(tun, r, rx).await });
-        jh.await.unwrap()?;
+// where a packet sent by the peer of `conn` goes next
+enum Hop {
+    // the tun interface, and from there wherever the host routes it
+    Tun,
+    // another peer, hairpinned right here instead of being routed back by the kernel
+    Peer(Arc<Connection>, Arc<Peer>),
+    Drop,
+}
 
-        Ok(())
+async fn next_hop(
+    router: &Router,
+    conn: &Arc<Connection>,
+    client_to_client: ClientToClient,
+    packet: &[u8],
+) -> Hop {
+    if client_to_client == ClientToClient::Kernel {
+        return Hop::Tun;
+    }
+    let other = match ip_dst_address(packet) {
+        Some(dst_ip) => router
+            .lookup(dst_ip)
+            .await
+            .filter(|(other, _)| !Arc::ptr_eq(other, conn)),
+        None => None,
+    };
+
+    match (other, client_to_client) {
+        (None, _) | (Some(_), ClientToClient::Kernel) => Hop::Tun,
+        (Some((other, other_peer)), ClientToClient::Allow) => Hop::Peer(other, other_peer),
+        (Some(_), ClientToClient::Deny) => Hop::Drop,
     }
 }
 
 async fn tun_loop(
     tun: Iface,
     router: Arc<Router>,
+    client_to_client: ClientToClient,
     mut peer_packets: Receiver<Bytes>,
 ) -> Result<(), Error> {
     let mtu = tun.mtu().unwrap();
@@ -120,7 +314,24 @@ async fn tun_loop(
                 };
 
 
-                if let Some(conn) = router.lookup(dst_ip).await {
+                let conn = router.lookup(dst_ip).await;
+                // traffic between clients the kernel forwarded anyway
+                if conn.is_some() && client_to_client == ClientToClient::Deny {
+                    let from_client = match ip_src_address(&ip_pkt) {
+                        Some(src_ip) => router.lookup(src_ip).await.is_some(),
+                        None => false,
+                    };
+                    if from_client {
+                        tracing::trace!("dropping packet to {dst_ip}, client to client denied");
+                        continue;
+                    }
+                }
+
+                if let Some((conn, peer)) = conn {
+                    if !peer.filter(&ip_pkt, Direction::ToPeer) {
+                        tracing::trace!("dropping packet to {dst_ip}, denied by its acl");
+                        continue;
+                    }
                     tracing::trace!("sening {} to {dst_ip}", ip_pkt.len());
 
                     if let Err(SendDatagramError::ConnectionLost(err)) = conn.send_datagram(ip_pkt) {
@@ -141,6 +352,12 @@ async fn tun_loop(
 /// a local interface and a VPN connection.
 pub struct Client {
     tun: Framed<Iface, TunPacketCodec>,
+    link: Arc<Link>,
+    // whether the MTU of `tun` is configured locally rather than by the server
+    keep_mtu: bool,
+    // the MTU `tun` was created with, which its codec's buffers and the connection's
+    // initial MTU are sized for
+    max_mtu: u16,
 }
 
 impl Client {
@@ -152,9 +369,47 @@ impl Client {
 
         Ok(Self {
             tun: tun.into_framed(mtu as usize),
+            link: Arc::default(),
+            keep_mtu: false,
+            max_mtu: mtu as u16,
         })
     }
 
+    /// Keeps the MTU of the tun interface rather than applying the one the server
+    /// recommends.
+    pub fn keep_mtu(&mut self) {
+        self.keep_mtu = true;
+    }
+
+    /// Applies the MTU of a [SessionConfig] to the tun interface, the rest of it is left
+    /// to the caller. The MTU can only be lowered below the one the interface was
+    /// created with.
+    pub fn configure(&mut self, config: &SessionConfig) {
+        let Some(mut mtu) = config.mtu.filter(|_| !self.keep_mtu) else {
+            return;
+        };
+        if mtu > self.max_mtu {
+            tracing::warn!(
+                "the server recommends MTU {mtu}, keeping it at {} instead",
+                self.max_mtu
+            );
+            mtu = self.max_mtu;
+        }
+        let tun = self.tun.get_mut();
+        if tun.mtu().is_ok_and(|current| current != mtu as i32) {
+            match tun.set_mtu(mtu as i32) {
+                Ok(()) => tracing::info!("set MTU to {mtu}"),
+                Err(e) => tracing::warn!("failed to set MTU to {mtu}: {e}"),
+            }
+        }
+    }
+
+    /// Returns a shared handle to the client's [Link], which reports the state of the
+    /// current connection to the server.
+    pub fn link(&self) -> Arc<Link> {
+        Arc::clone(&self.link)
+    }
+
     /// Asynchronously runs the client, managing the transmission of packets between the local tun interface and
     ///  the VPN connection.
     ///
@@ -163,14 +418,41 @@ impl Client {
     /// as required.
     ///
     /// - `conn`: The VPN connection used for sending and receiving datagrams.
+    /// - `control`: The control stream the server pushes configuration updates on, each
+    ///   of which is [configured](Self::configure) and passed to `on_update`.
     /// - Returns: A result indicating success (`Ok`) or an error (`Err`).
     ///
     /// The method will run indefinitely until an error occurs or the connection is lost.
-    pub async fn run(&mut self, conn: Connection) -> Result<(), Error> {
+    pub async fn run(
+        &mut self,
+        conn: Connection,
+        mut control: session::Receiver,
+        mut on_update: impl FnMut(&SessionConfig),
+    ) -> Result<(), Error> {
+        let conn = Arc::new(conn);
+        self.link.connected(&conn);
+
         let mut interval = tokio::time::interval(Duration::from_secs(60));
+        let mut version = None;
+        let mut updates = true;
 
         loop {
             select! {
+                update = control.recv(), if updates => match update {
+                    // versions only ever increase on a connection, so stale ones are dropped
+                    Ok(config) if version.is_some_and(|version| config.version <= version) => (),
+                    Ok(config) => {
+                        tracing::info!("received configuration version {}", config.version);
+                        version = Some(config.version);
+                        self.configure(&config);
+                        on_update(&config);
+                    }
+                    Err(session::SessionError::Conn(e)) => return Err(e.into()),
+                    Err(e) => {
+                        tracing::warn!("no more configuration updates: {e}");
+                        updates = false;
+                    }
+                },
                 Some(ip_pkt) = self.tun.next() => {
                     let ip_pkt = ip_pkt.unwrap();
                     tracing::trace!("packet size ->: {}", ip_pkt.len());
@@ -201,29 +483,37 @@ impl Client {
 }
 
 const IPV4_MIN_HEADER_SIZE: usize = 20;
+const IPV4_SRC_IP_OFF: usize = 12;
 const IPV4_DST_IP_OFF: usize = 16;
 const IPV4_IP_SIZE: usize = 4;
 
 const IPV6_MIN_HEADER_SIZE: usize = 40;
+const IPV6_SRC_IP_OFF: usize = 8;
 const IPV6_DST_IP_OFF: usize = 24;
 const IPV6_IP_SIZE: usize = 16;
 
+fn ip_src_address(packet: &[u8]) -> Option<IpAddr> {
+    ip_address(packet, IPV4_SRC_IP_OFF, IPV6_SRC_IP_OFF)
+}
+
 fn ip_dst_address(packet: &[u8]) -> Option<IpAddr> {
+    ip_address(packet, IPV4_DST_IP_OFF, IPV6_DST_IP_OFF)
+}
+
+fn ip_address(packet: &[u8], ipv4_off: usize, ipv6_off: usize) -> Option<IpAddr> {
     if packet.is_empty() {
         return None;
     }
 
     match packet[0] >> 4 {
         4 if packet.len() >= IPV4_MIN_HEADER_SIZE => {
-            let addr_bytes: [u8; IPV4_IP_SIZE] = packet
-                [IPV4_DST_IP_OFF..IPV4_DST_IP_OFF + IPV4_IP_SIZE]
+            let addr_bytes: [u8; IPV4_IP_SIZE] = packet[ipv4_off..ipv4_off + IPV4_IP_SIZE]
                 .try_into()
                 .unwrap();
             Some(IpAddr::from(addr_bytes))
         }
         6 if packet.len() >= IPV6_MIN_HEADER_SIZE => {
-            let addr_bytes: [u8; IPV6_IP_SIZE] = packet
-                [IPV6_DST_IP_OFF..IPV6_DST_IP_OFF + IPV6_IP_SIZE]
+            let addr_bytes: [u8; IPV6_IP_SIZE] = packet[ipv6_off..ipv6_off + IPV6_IP_SIZE]
                 .try_into()
                 .unwrap();
             Some(IpAddr::from(addr_bytes))
@@ -231,3 +521,74 @@ fn ip_dst_address(packet: &[u8]) -> Option<IpAddr> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ip_addresses() {
+        let mut ipv4 = [0u8; IPV4_MIN_HEADER_SIZE];
+        ipv4[0] = 0x45;
+        ipv4[IPV4_SRC_IP_OFF..IPV4_SRC_IP_OFF + 4].copy_from_slice(&[10, 10, 0, 3]);
+        ipv4[IPV4_DST_IP_OFF..IPV4_DST_IP_OFF + 4].copy_from_slice(&[1, 1, 1, 1]);
+        assert_eq!(ip_src_address(&ipv4), Some("10.10.0.3".parse().unwrap()));
+        assert_eq!(ip_dst_address(&ipv4), Some("1.1.1.1".parse().unwrap()));
+
+        let src: std::net::Ipv6Addr = "fd00::3".parse().unwrap();
+        let dst: std::net::Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut ipv6 = [0u8; IPV6_MIN_HEADER_SIZE];
+        ipv6[0] = 0x60;
+        ipv6[IPV6_SRC_IP_OFF..IPV6_SRC_IP_OFF + 16].copy_from_slice(&src.octets());
+        ipv6[IPV6_DST_IP_OFF..IPV6_DST_IP_OFF + 16].copy_from_slice(&dst.octets());
+        assert_eq!(ip_src_address(&ipv6), Some(src.into()));
+        assert_eq!(ip_dst_address(&ipv6), Some(dst.into()));
+
+        assert_eq!(ip_src_address(&ipv4[..10]), None);
+        assert_eq!(ip_src_address(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn test_next_hop() {
+        let router = Arc::new(Router::default());
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let peers = connect_peers(&router, &[(ip("10.10.0.2"), 32), (ip("10.10.0.3"), 32)]).await;
+        let (alice, bob) = (&peers[0].2, &peers[1].2);
+        let packet = |dst: [u8; 4]| {
+            let mut ipv4 = [0u8; IPV4_MIN_HEADER_SIZE];
+            ipv4[0] = 0x45;
+            ipv4[IPV4_SRC_IP_OFF..IPV4_SRC_IP_OFF + 4].copy_from_slice(&[10, 10, 0, 2]);
+            ipv4[IPV4_DST_IP_OFF..IPV4_DST_IP_OFF + 4].copy_from_slice(&dst);
+            ipv4
+        };
+        let to_bob = packet([10, 10, 0, 3]);
+
+        // traffic to another client is left to the kernel unless explicitly allowed
+        assert!(matches!(
+            next_hop(&router, alice, ClientToClient::Kernel, &to_bob).await,
+            Hop::Tun
+        ));
+        assert!(matches!(
+            next_hop(&router, alice, ClientToClient::Allow, &to_bob).await,
+            Hop::Peer(conn, _) if Arc::ptr_eq(&conn, bob)
+        ));
+        assert!(matches!(
+            next_hop(&router, alice, ClientToClient::Deny, &to_bob).await,
+            Hop::Drop
+        ));
+
+        // anything else goes to the tun interface, including traffic to the sender itself
+        for client_to_client in [ClientToClient::Allow, ClientToClient::Deny] {
+            let elsewhere = packet([1, 1, 1, 1]);
+            assert!(matches!(
+                next_hop(&router, alice, client_to_client, &elsewhere).await,
+                Hop::Tun
+            ));
+            let to_alice = packet([10, 10, 0, 2]);
+            assert!(matches!(
+                next_hop(&router, alice, client_to_client, &to_alice).await,
+                Hop::Tun
+            ));
+        }
+    }
+}
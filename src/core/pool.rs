@@ -0,0 +1,351 @@
+//! Addresses a server assigns to its clients out of a pool.
+//!
+//! Leases are keyed by the identity a client is configured by and persisted to a lease
+//! file, so a client keeps its address across reconnects, restarts and renewals of its
+//! certificate.
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+
+use super::identity::{spki_sha256, Identity};
+
+// bounds the search for a free address in ranges mostly covered by configured clients
+const MAX_PROBES: u32 = 1 << 16;
+
+#[derive(Error, Debug)]
+#[error("no free address left in {0}/{1}")]
+pub struct Exhausted(IpAddr, u8);
+
+/// Ranges of addresses, at most one per address family, leased to clients.
+pub struct Pool {
+    ranges: Vec<(IpAddr, u8)>,
+    reserved: Vec<IpAddr>,
+    // addresses leased to each client, keyed by `lease_key`
+    leases: BTreeMap<String, Vec<IpAddr>>,
+    // incremented with every change of `leases`
+    version: u64,
+    // the version last handed out to be saved
+    unsaved: u64,
+    lease_file: Arc<LeaseFile>,
+}
+
+struct LeaseFile {
+    path: PathBuf,
+    // the version last written, so that an older one never replaces it
+    saved: Mutex<u64>,
+}
+
+/// The leases of a [Pool] as of a change, to be written to its lease file off the async
+/// runtime.
+pub struct Unsaved {
+    lease_file: Arc<LeaseFile>,
+    version: u64,
+    leases: Vec<u8>,
+}
+
+impl Pool {
+    /// Creates a pool of the addresses in `ranges` but the `reserved` ones, with the leases
+    /// recorded in `lease_file` if it exists.
+    pub fn new(
+        ranges: impl IntoIterator<Item = (IpAddr, u8)>,
+        reserved: impl IntoIterator<Item = IpAddr>,
+        lease_file: PathBuf,
+    ) -> io::Result<Self> {
+        let leases = match fs::read(&lease_file) {
+            Ok(leases) => serde_json::from_slice(&leases)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Pool {
+            ranges: ranges
+                .into_iter()
+                .map(|(ip, prefix)| (network(ip, prefix), prefix))
+                .collect(),
+            reserved: reserved.into_iter().collect(),
+            leases,
+            version: 0,
+            unsaved: 0,
+            lease_file: Arc::new(LeaseFile {
+                path: lease_file,
+                saved: Mutex::new(0),
+            }),
+        })
+    }
+
+    /// Returns the addresses of the client configured by `identity`, one from each range
+    /// with the range's prefix length, leasing new ones if it has none yet.
+    ///
+    /// Addresses for which `in_use` returns `true`, e.g. ones routed to other clients, are
+    /// not leased, and a client whose lease has become one of them is given another one.
+    pub fn lease(
+        &mut self,
+        identity: &Identity,
+        in_use: impl Fn(IpAddr) -> bool,
+    ) -> Result<Vec<(IpAddr, u8)>, Exhausted> {
+        let key = lease_key(identity);
+        let current = self.leases.get(&key).cloned().unwrap_or_default();
+        let taken: HashSet<IpAddr> = self
+            .leases
+            .iter()
+            .filter(|(other, _)| **other != key)
+            .flat_map(|(_, ips)| ips.iter().copied())
+            .chain(self.reserved.iter().copied())
+            .collect();
+        let free = |ip: &IpAddr| !taken.contains(ip) && !in_use(*ip);
+
+        let mut leased = Vec::with_capacity(self.ranges.len());
+        for &(network, prefix) in &self.ranges {
+            let kept = current
+                .iter()
+                .copied()
+                .find(|&ip| contains(network, prefix, ip) && free(&ip));
+            let ip = match kept {
+                Some(ip) => ip,
+                None => hosts(network, prefix)
+                    .take(MAX_PROBES as usize)
+                    .find(free)
+                    .ok_or(Exhausted(network, prefix))?,
+            };
+            leased.push((ip, prefix));
+        }
+
+        let ips: Vec<_> = leased.iter().map(|&(ip, _)| ip).collect();
+        if ips != current {
+            tracing::info!("leased {ips:?} to {identity}");
+            self.leases.insert(key, ips);
+            self.version += 1;
+        }
+
+        Ok(leased)
+    }
+
+    /// Gives up the leases of clients other than the `configured` ones, so that their
+    /// addresses can be leased again.
+    pub fn retain<'a>(&mut self, configured: impl IntoIterator<Item = &'a Identity>) {
+        let configured: HashSet<_> = configured.into_iter().map(lease_key).collect();
+        let before = self.leases.len();
+        self.leases.retain(|key, ips| {
+            let keep = configured.contains(key);
+            if !keep {
+                tracing::info!("released {ips:?} of {key}, which is no longer configured");
+            }
+            keep
+        });
+        if self.leases.len() != before {
+            self.version += 1;
+        }
+    }
+
+    /// Returns the leases to be saved if they changed since last time.
+    pub fn unsaved(&mut self) -> Option<Unsaved> {
+        if self.unsaved == self.version {
+            return None;
+        }
+        self.unsaved = self.version;
+
+        Some(Unsaved {
+            lease_file: Arc::clone(&self.lease_file),
+            version: self.version,
+            leases: serde_json::to_vec_pretty(&self.leases).expect("leases are serializable"),
+        })
+    }
+}
+
+impl Unsaved {
+    /// Writes the leases to the lease file, unless newer ones have been written already.
+    /// Blocks, errors are logged.
+    pub fn save(self) {
+        let mut saved = self.lease_file.saved.lock().unwrap();
+        if *saved >= self.version {
+            return;
+        }
+        match write_synced(&self.lease_file.path, &self.leases) {
+            Ok(()) => *saved = self.version,
+            Err(e) => tracing::error!(
+                "failed to save leases to {}: {e}",
+                self.lease_file.path.display()
+            ),
+        }
+    }
+}
+
+// replaces the file atomically and syncs it and its directory, so a crash never loses
+// the leases handed out before
+fn write_synced(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+
+    let tmp = tmp_path(path);
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)?;
+    File::open(dir)?.sync_all()
+}
+
+// what a lease is recorded by: the key fingerprint of a client configured by its
+// certificate chain, so that it survives the certificate's renewal
+fn lease_key(identity: &Identity) -> String {
+    match identity {
+        Identity::Chain(chain) => match chain.first().and_then(spki_sha256) {
+            Some(spki) => Identity::SpkiSha256(spki).to_string(),
+            None => identity.to_string(),
+        },
+        identity => identity.to_string(),
+    }
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tmp.into()
+}
+
+fn network(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            Ipv4Addr::from(u32::from(ip) & mask).into()
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            Ipv6Addr::from(u128::from(ip) & mask).into()
+        }
+    }
+}
+
+/// Returns `true` if `ip` is in the range `net`/`prefix`.
+pub(super) fn contains(net: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    net.is_ipv4() == ip.is_ipv4() && network(net, prefix) == network(ip, prefix)
+}
+
+// the addresses of a range that can be assigned to a host, i.e. all but the network and,
+// for IPv4, the broadcast address of ranges larger than a /31
+fn hosts(network: IpAddr, prefix: u8) -> Box<dyn Iterator<Item = IpAddr>> {
+    match network {
+        IpAddr::V4(network) => {
+            let first = u32::from(network);
+            let last = first | u32::MAX.checked_shr(prefix as u32).unwrap_or(0);
+            let range = if prefix < 31 {
+                first + 1..=last - 1
+            } else {
+                first..=last
+            };
+            Box::new(range.map(|ip| Ipv4Addr::from(ip).into()))
+        }
+        IpAddr::V6(network) => {
+            let first = u128::from(network);
+            let last = first | u128::MAX.checked_shr(prefix as u32).unwrap_or(0);
+            let first = if prefix < 127 { first + 1 } else { first };
+            Box::new((first..=last).map(|ip| Ipv6Addr::from(ip).into()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lease() {
+        let lease_file =
+            std::env::temp_dir().join(format!("vqn-test-{}.leases", std::process::id()));
+        let _ = fs::remove_file(&lease_file);
+        let pool = || {
+            Pool::new(
+                [
+                    ("10.10.0.0".parse().unwrap(), 29),
+                    ("fd00::".parse().unwrap(), 64),
+                ],
+                ["10.10.0.1".parse().unwrap(), "fd00::1".parse().unwrap()],
+                lease_file.clone(),
+            )
+            .unwrap()
+        };
+        let alice = Identity::CommonName("alice".to_owned());
+        let bob = Identity::SpkiSha256([0xb0; 32]);
+        let none = |_| false;
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        let mut leases = pool();
+        assert_eq!(
+            leases.lease(&alice, none).unwrap(),
+            [(ip("10.10.0.2"), 29), (ip("fd00::2"), 64)]
+        );
+        // addresses routed to configured clients are skipped
+        let static_ip = |candidate| candidate == ip("10.10.0.3");
+        assert_eq!(
+            leases.lease(&bob, static_ip).unwrap(),
+            [(ip("10.10.0.4"), 29), (ip("fd00::3"), 64)]
+        );
+
+        // leases outlive the pool they were handed out by once saved
+        leases.unsaved().unwrap().save();
+        assert!(leases.unsaved().is_none());
+        let mut leases = pool();
+        assert_eq!(leases.lease(&bob, none).unwrap()[0].0, ip("10.10.0.4"));
+        assert_eq!(leases.lease(&alice, none).unwrap()[0].0, ip("10.10.0.2"));
+
+        // a lease that has been configured for another client since is replaced
+        let alice_ip = |candidate| candidate == ip("10.10.0.2");
+        assert_eq!(
+            leases.lease(&alice, alice_ip).unwrap()[0].0,
+            ip("10.10.0.3")
+        );
+
+        // 10.10.0.2, which alice gave up, .5 and .6 are left, .7 is the broadcast address
+        for i in 0..3 {
+            leases
+                .lease(&Identity::CommonName(i.to_string()), none)
+                .unwrap();
+        }
+        let err = leases
+            .lease(&Identity::CommonName("carol".to_owned()), none)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "no free address left in 10.10.0.0/29");
+
+        // the leases of clients no longer configured are given up
+        let unsaved = leases.unsaved().unwrap();
+        leases.retain([&alice, &bob]);
+        leases.unsaved().unwrap().save();
+        // an older version saved late does not replace a newer one
+        unsaved.save();
+        let mut leases = pool();
+        assert_eq!(
+            leases
+                .lease(&Identity::CommonName("carol".to_owned()), none)
+                .unwrap()[0]
+                .0,
+            ip("10.10.0.2")
+        );
+
+        fs::remove_file(&lease_file).unwrap();
+    }
+
+    #[test]
+    fn test_hosts() {
+        let hosts = |ip: &str, prefix| {
+            hosts(ip.parse().unwrap(), prefix)
+                .map(|ip| ip.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(hosts("10.0.0.0", 30), ["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(hosts("10.0.0.0", 31), ["10.0.0.0", "10.0.0.1"]);
+        assert_eq!(hosts("10.0.0.1", 32), ["10.0.0.1"]);
+        assert_eq!(hosts("fd00::", 126), ["fd00::1", "fd00::2", "fd00::3"]);
+        assert_eq!(
+            network("10.10.0.7".parse().unwrap(), 24).to_string(),
+            "10.10.0.0"
+        );
+    }
+}
@@ -1,59 +1,755 @@
-use std::sync::{Arc, Weak};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock as SyncRwLock, Weak};
 use std::{collections::HashMap, net::IpAddr};
 
+use super::acl::{Acl, Direction};
 use super::allowed_ips::AllowedIps;
-use quinn::Connection;
+use super::identity::Identity;
+use super::link::{Link, LinkStatus};
+use super::pool::{Exhausted, Pool, Unsaved};
+use super::session::SessionConfig;
+use quinn::{Connection, VarInt};
 use rustls::Certificate;
-use tokio::sync::RwLock;
+use thiserror::Error;
+use tokio::sync::{broadcast, watch, RwLock};
 
+/// A configured client and the IP ranges it is allowed to use.
 #[derive(Default)]
+pub struct Peer {
+    allowed_ips: SyncRwLock<AllowedIps<()>>,
+    // addresses leased from the router's pool, with the pool's prefix length
+    leased: SyncRwLock<Vec<(IpAddr, u8)>>,
+    // filters the peer's traffic in both directions, if configured
+    acl: SyncRwLock<Option<Arc<Acl>>>,
+    link: Link,
+    dropped: AtomicU64,
+}
+
+/// A point-in-time snapshot of a configured [Peer].
+#[derive(Debug, Clone)]
+pub struct PeerStatus {
+    pub identity: Identity,
+    pub allowed_ips: Vec<(IpAddr, u8)>,
+    /// Addresses leased from the router's pool.
+    pub address: Vec<IpAddr>,
+    pub link: LinkStatus,
+    pub dropped: u64,
+    /// Rules of the peer's ACL, and its default action last, with their hit counts.
+    pub acl: Vec<(String, u64)>,
+}
+
+impl Peer {
+    /// Returns `true` if `ip` is covered by one of this peer's allowed IP ranges or has
+    /// been leased to it.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.allowed_ips.read().unwrap().get(ip).is_some()
+            || self
+                .leased
+                .read()
+                .unwrap()
+                .iter()
+                .any(|&(leased, _)| leased == ip)
+    }
+
+    /// Returns `true` if the peer's ACL, if any, lets `packet` pass in `direction`.
+    pub fn filter(&self, packet: &[u8], direction: Direction) -> bool {
+        let acl = self.acl.read().unwrap().clone();
+        acl.is_none_or(|acl| acl.allows(packet, direction))
+    }
+
+    /// Returns the addresses leased to this peer, with the prefix length of their pool.
+    pub fn address(&self) -> Vec<(IpAddr, u8)> {
+        self.leased.read().unwrap().clone()
+    }
+
+    /// Records a datagram dropped by source address validation, returning the updated count.
+    pub fn record_dropped(&self) -> u64 {
+        self.dropped.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn routes(&self) -> Vec<(IpAddr, u8)> {
+        let mut routes = self.configured_routes();
+        routes.extend(
+            self.leased
+                .read()
+                .unwrap()
+                .iter()
+                .map(|&(ip, _)| (ip, if ip.is_ipv4() { 32 } else { 128 })),
+        );
+
+        routes
+    }
+
+    fn configured_routes(&self) -> Vec<(IpAddr, u8)> {
+        self.allowed_ips
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(_, ip, cidr)| (ip, cidr))
+            .collect()
+    }
+
+    fn connection(&self) -> Option<Arc<Connection>> {
+        self.link.connection()
+    }
+
+    /// Replaces this peer's allowed IPs, returning `true` if they changed.
+    fn set_allowed_ips(&self, ips: AllowedIps<()>) -> bool {
+        let mut allowed_ips = self.allowed_ips.write().unwrap();
+        let changed = !allowed_ips
+            .iter()
+            .map(|(_, ip, cidr)| (ip, cidr))
+            .eq(ips.iter().map(|(_, ip, cidr)| (ip, cidr)));
+        *allowed_ips = ips;
+
+        changed
+    }
+
+    // replaces this peer's ACL, keeping the hit counts if its rules did not change
+    fn set_acl(&self, acl: Option<Acl>) {
+        let mut current = self.acl.write().unwrap();
+        match (&*current, acl) {
+            (Some(current), Some(acl)) if current.same_rules(&acl) => (),
+            (_, acl) => *current = acl.map(Arc::new),
+        }
+    }
+}
+
+/// Application error code sent when a peer is removed from the configuration.
+pub const PEER_REMOVED: VarInt = VarInt::from_u32(1);
+/// Application error code sent when a peer is kicked.
+pub const PEER_KICKED: VarInt = VarInt::from_u32(2);
+/// Application error code sent when a connection is replaced by a newer one from the same peer.
+pub const PEER_REPLACED: VarInt = VarInt::from_u32(3);
+/// Application error code sent when a peer connects while it already has a live connection.
+pub const PEER_DUPLICATE: VarInt = VarInt::from_u32(4);
+/// Application error code sent when the certificate of a connected peer has been revoked.
+pub const PEER_REVOKED: VarInt = VarInt::from_u32(5);
+/// Application error code sent when no address is left in the pool for a connecting peer.
+pub const POOL_EXHAUSTED: VarInt = VarInt::from_u32(6);
+
+/// Allowed IP ranges and ACL of each peer, keyed by the peer's identity.
+pub type PeerIps = HashMap<Identity, (Vec<(IpAddr, u8)>, Option<Acl>)>;
+
+/// What to do when a peer connects while it already has a live connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Close the existing connection and route the peer's traffic to the new one.
+    #[default]
+    NewestWins,
+    /// Keep the existing connection and close the new one.
+    Reject,
+}
+
+/// Whether peers may send traffic to each other through the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientToClient {
+    /// Pass traffic between peers to the tun interface like any other, leaving it to the
+    /// host whether to forward it back.
+    #[default]
+    Kernel,
+    /// Forward traffic between peers directly, without passing it through the tun
+    /// interface.
+    Allow,
+    /// Drop traffic between peers, whether or not the host forwards it.
+    Deny,
+}
+
+/// Connection lifecycle events of configured peers.
+#[derive(Debug, Clone)]
+pub enum PeerEvent {
+    Connected {
+        cert_chain: Vec<Certificate>,
+        remote: SocketAddr,
+    },
+    Disconnected {
+        cert_chain: Vec<Certificate>,
+        remote: SocketAddr,
+        reason: String,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum ConnectError {
+    #[error("unknown peer {0}")]
+    UnknownPeer(Identity),
+
+    #[error("peer already connected from {0}")]
+    Duplicate(SocketAddr),
+
+    #[error(transparent)]
+    Exhausted(#[from] Exhausted),
+}
+
 pub struct Router {
-    // map cert_chain -> AllowedIps
-    allowed_ips: HashMap<Vec<Certificate>, AllowedIps<()>>,
+    // map identity -> Peer
+    peers: SyncRwLock<HashMap<Identity, Arc<Peer>>>,
     // lookup Connection by IP
-    connections: RwLock<AllowedIps<Weak<Connection>>>,
+    connections: RwLock<AllowedIps<Route>>,
+    events: broadcast::Sender<PeerEvent>,
+    pool: Mutex<Option<Pool>>,
+    // the configuration pushed to every client, but their address
+    pushed: watch::Sender<SessionConfig>,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router {
+            peers: Default::default(),
+            connections: Default::default(),
+            events: broadcast::channel(64).0,
+            pool: Mutex::default(),
+            pushed: watch::channel(SessionConfig::default()).0,
+        }
+    }
 }
 
 impl Router {
-    pub fn add_peer(
-        &mut self,
-        key: Vec<Certificate>,
-        iter: impl IntoIterator<Item = (IpAddr, u8)>,
-    ) {
-        self.allowed_ips
-            .entry(key)
-            .or_default()
+    /// Leases addresses out of `pool` to connecting peers.
+    pub fn set_pool(&self, pool: Pool) {
+        *self.pool.lock().unwrap() = Some(pool);
+    }
+
+    pub fn add_peer(&self, key: Identity, iter: impl IntoIterator<Item = (IpAddr, u8)>) {
+        let mut peers = self.peers.write().unwrap();
+        let peer = peers.entry(key).or_default();
+
+        peer.allowed_ips
+            .write()
+            .unwrap()
             .extend(iter.into_iter().map(|(ip, cidr)| (ip, cidr, ())));
     }
 
-    pub async fn connect(&self, conn: Connection) -> Arc<Connection> {
-        let certs = conn
-            .peer_identity()
-            .and_then(|ident| ident.downcast::<Vec<Certificate>>().ok());
+    /// Filters the traffic of the peer configured by `key` with `acl`, or lets all of it
+    /// pass if `None`. Hit counts are kept if the rules did not change.
+    pub fn set_acl(&self, key: &Identity, acl: Option<Acl>) {
+        if let Some(peer) = self.peers.read().unwrap().get(key) {
+            peer.set_acl(acl);
+        }
+    }
 
-        let conn = Arc::new(conn);
+    /// Reconciles the configured peers with `peers`, applying changes to live connections.
+    ///
+    /// Peers missing from `peers` are removed and their connections closed, new peers are
+    /// added, and peers whose allowed IPs changed have their routes updated. A peer's
+    /// ACL is installed together with its allowed IPs, so its traffic is never let
+    /// through unfiltered in between.
+    pub async fn update_peers(&self, peers: PeerIps) {
+        let mut removed = vec![];
+        let mut changed = vec![];
+        let mut added = 0;
+        if let Some(pool) = self.pool.lock().unwrap().as_mut() {
+            pool.retain(peers.keys());
+            save(pool.unsaved());
+        }
+        {
+            let mut current = self.peers.write().unwrap();
+            current.retain(|key, peer| {
+                let keep = peers.contains_key(key);
+                if !keep {
+                    removed.push(Arc::clone(peer));
+                }
+                keep
+            });
+
+            for (key, (ips, acl)) in peers {
+                let mut allowed_ips = AllowedIps::default();
+                allowed_ips.extend(ips.into_iter().map(|(ip, cidr)| (ip, cidr, ())));
+
+                match current.get(&key) {
+                    Some(peer) => {
+                        peer.set_acl(acl);
+                        if peer.set_allowed_ips(allowed_ips) {
+                            changed.push(Arc::clone(peer));
+                        }
+                    }
+                    None => {
+                        let peer = Peer {
+                            allowed_ips: SyncRwLock::new(allowed_ips),
+                            acl: SyncRwLock::new(acl.map(Arc::new)),
+                            ..Default::default()
+                        };
+                        current.insert(key, Arc::new(peer));
+                        added += 1;
+                    }
+                }
+            }
+        }
+
+        tracing::info!(
+            "peers updated: {added} added, {} removed, {} changed",
+            removed.len(),
+            changed.len()
+        );
+
+        let mut connections = self.connections.write().await;
+        for peer in removed {
+            if let Some(conn) = peer.connection() {
+                withdraw(&mut connections, &conn);
+                conn.close(PEER_REMOVED, b"peer removed");
+            }
+        }
+        for peer in changed {
+            if let Some(conn) = peer.connection() {
+                withdraw(&mut connections, &conn);
+                for (ip, cidr) in peer.routes() {
+                    connections.insert(ip, cidr, Route::new(&conn, &peer));
+                }
+            }
+        }
+    }
+
+    /// Routes the allowed IPs of the peer identified by `conn`'s certificate chain to `conn`.
+    /// A peer configured by the whole chain takes precedence over one configured by its key
+    /// fingerprint, which in turn takes precedence over ones configured by a name.
+    ///
+    /// If the peer already has a live connection, `duplicate` decides which one is closed.
+    /// With a pool, the peer is leased its addresses first. Rejected connections are
+    /// dropped, which closes them.
+    pub async fn connect(
+        &self,
+        conn: Connection,
+        duplicate: DuplicatePolicy,
+    ) -> Result<(Arc<Connection>, Arc<Peer>), ConnectError> {
+        let certs = peer_certs(&conn).unwrap_or_default();
+        let (key, peer) = {
+            let peers = self.peers.read().unwrap();
+            Identity::of(&certs)
+                .into_iter()
+                .find_map(|identity| Some((identity.clone(), Arc::clone(peers.get(&identity)?))))
+                .ok_or_else(|| ConnectError::UnknownPeer(Identity::Chain(certs.clone())))?
+        };
+        if let Err(e) = self.lease(&key, &peer) {
+            conn.close(POOL_EXHAUSTED, b"address pool exhausted");
+            return Err(e.into());
+        }
 
-        let Some(certs) = certs else {
-            return conn;
+        let conn = Arc::new(conn);
+        let previous = match duplicate {
+            DuplicatePolicy::NewestWins => peer.link.connected(&conn),
+            DuplicatePolicy::Reject => match peer.link.try_connected(&conn) {
+                Ok(()) => None,
+                Err(existing) => {
+                    conn.close(PEER_DUPLICATE, b"duplicate connection");
+                    return Err(ConnectError::Duplicate(existing.remote_address()));
+                }
+            },
         };
 
         let mut connections = self.connections.write().await;
-        for (_, ip, cidr) in self
-            .allowed_ips
-            .get(&*certs)
-            .into_iter()
-            .flat_map(|ips| ips.iter())
-        {
-            let conn = Arc::downgrade(&conn);
-            connections.insert(ip, cidr, conn);
+        if let Some(previous) = previous {
+            withdraw(&mut connections, &previous);
+            previous.close(PEER_REPLACED, b"replaced by a newer connection");
+        }
+        for (ip, cidr) in peer.routes() {
+            connections.insert(ip, cidr, Route::new(&conn, &peer));
         }
 
-        conn
+        let _ = self.events.send(PeerEvent::Connected {
+            cert_chain: certs,
+            remote: conn.remote_address(),
+        });
+
+        Ok((conn, peer))
     }
 
-    pub async fn lookup(&self, ip: IpAddr) -> Option<Arc<Connection>> {
+    // leases addresses to `peer`, which is configured by `key`, keeping clear of the ones
+    // routed to other peers
+    fn lease(&self, key: &Identity, peer: &Peer) -> Result<(), Exhausted> {
+        let mut pool = self.pool.lock().unwrap();
+        let Some(pool) = pool.as_mut() else {
+            return Ok(());
+        };
+
+        let routed: Vec<_> = self
+            .peers
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(other, _)| *other != key)
+            .flat_map(|(_, peer)| peer.configured_routes())
+            .collect();
+        let leased = pool.lease(key, |ip| {
+            routed
+                .iter()
+                .any(|&(net, prefix)| super::pool::contains(net, prefix, ip))
+        })?;
+        *peer.leased.write().unwrap() = leased;
+        save(pool.unsaved());
+
+        Ok(())
+    }
+
+    /// Withdraws the routes still pointing to `conn` after it has been closed.
+    pub async fn disconnected(&self, conn: &Arc<Connection>) {
+        withdraw(&mut *self.connections.write().await, conn);
+
+        let reason = conn
+            .close_reason()
+            .map(|e| e.to_string())
+            .unwrap_or_default();
+        tracing::info!("peer disconnected: {} ({reason})", conn.remote_address());
+
+        if let Some(cert_chain) = peer_certs(conn) {
+            let _ = self.events.send(PeerEvent::Disconnected {
+                cert_chain,
+                remote: conn.remote_address(),
+                reason,
+            });
+        }
+    }
+
+    /// Pushes `config` to connected and future clients, with the next version if it
+    /// differs from the one pushed before. Its address is ignored.
+    pub fn push(&self, config: SessionConfig) {
+        self.pushed.send_if_modified(|current| {
+            let config = SessionConfig {
+                version: current.version,
+                address: vec![],
+                ..config
+            };
+            if *current == config {
+                return false;
+            }
+            *current = SessionConfig {
+                version: config.version + 1,
+                ..config
+            };
+            tracing::info!("pushing configuration version {}", current.version);
+            true
+        });
+    }
+
+    /// Subscribes to the configuration pushed to clients.
+    pub fn pushed(&self) -> watch::Receiver<SessionConfig> {
+        self.pushed.subscribe()
+    }
+
+    /// Subscribes to [PeerEvent]s.
+    pub fn subscribe(&self) -> broadcast::Receiver<PeerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Closes the connection of the peer identified by `key` with `code` and `reason`.
+    ///
+    /// Returns `false` if the peer is not connected.
+    pub fn disconnect(&self, key: &Identity, code: VarInt, reason: &str) -> bool {
+        let conn = self
+            .peers
+            .read()
+            .unwrap()
+            .get(key)
+            .and_then(|peer| peer.connection());
+
+        match conn {
+            Some(conn) => {
+                conn.close(code, reason.as_bytes());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns `true` if a peer is configured by `key`.
+    pub fn has_peer(&self, key: &Identity) -> bool {
+        self.peers.read().unwrap().contains_key(key)
+    }
+
+    /// Returns a snapshot of every configured peer.
+    pub fn status(&self) -> Vec<PeerStatus> {
+        self.peers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(identity, peer)| PeerStatus {
+                identity: identity.clone(),
+                allowed_ips: peer.configured_routes(),
+                address: peer
+                    .leased
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .map(|&(ip, _)| ip)
+                    .collect(),
+                link: peer.link.status(),
+                dropped: peer.dropped.load(Ordering::Relaxed),
+                acl: peer
+                    .acl
+                    .read()
+                    .unwrap()
+                    .as_ref()
+                    .map(|acl| acl.hits())
+                    .unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// Returns `true` if traffic to `ip` is routed to the live connection of the peer
+    /// configured by `key`.
+    pub async fn routes_to(&self, ip: IpAddr, key: &Identity) -> bool {
+        let Some((_, peer)) = self.lookup(ip).await else {
+            return false;
+        };
+
+        self.peers
+            .read()
+            .unwrap()
+            .get(key)
+            .is_some_and(|configured| Arc::ptr_eq(configured, &peer))
+    }
+
+    /// Returns the connection `ip` is routed to, and the peer it belongs to.
+    pub async fn lookup(&self, ip: IpAddr) -> Option<(Arc<Connection>, Arc<Peer>)> {
         let connections = self.connections.read().await;
 
-        connections.get(ip).and_then(|conn| conn.upgrade())
+        connections
+            .get(ip)
+            .and_then(|route| Some((route.conn.upgrade()?, route.peer.upgrade()?)))
+    }
+}
+
+// where packets to a range are sent
+struct Route {
+    conn: Weak<Connection>,
+    peer: Weak<Peer>,
+}
+
+impl Route {
+    fn new(conn: &Arc<Connection>, peer: &Arc<Peer>) -> Self {
+        Route {
+            conn: Arc::downgrade(conn),
+            peer: Arc::downgrade(peer),
+        }
+    }
+}
+
+fn peer_certs(conn: &Connection) -> Option<Vec<Certificate>> {
+    conn.peer_identity()
+        .and_then(|ident| ident.downcast::<Vec<Certificate>>().ok())
+        .map(|certs| *certs)
+}
+
+// writes changed leases in the background, keeping the file system off the paths that
+// accept connections and reload peers
+fn save(unsaved: Option<Unsaved>) {
+    if let Some(unsaved) = unsaved {
+        tokio::task::spawn_blocking(|| unsaved.save());
+    }
+}
+
+// remove all routes pointing to `conn`
+fn withdraw(connections: &mut AllowedIps<Route>, conn: &Arc<Connection>) {
+    let conn = Arc::downgrade(conn);
+    connections.retain(|route| !route.conn.ptr_eq(&conn));
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::net::Ipv4Addr;
+
+    use quinn::{ConnectionError, Endpoint};
+
+    use super::*;
+    use crate::core::acl::Action;
+    use crate::core::spki_sha256;
+    use crate::pin::{PinnedClients, PinnedServer};
+
+    // a server endpoint on loopback accepting the peers configured in `router`, and the
+    // pin of its certificate
+    fn loopback(router: &Arc<Router>) -> (Endpoint, [u8; 32]) {
+        let server_cert = rcgen::generate_simple_self_signed(["localhost".to_owned()]).unwrap();
+        let server_der = Certificate(server_cert.serialize_der().unwrap());
+        let pin = spki_sha256(&server_der).unwrap();
+        let server_crypto = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(Arc::new(PinnedClients::new(Arc::clone(router))))
+            .with_single_cert(
+                vec![server_der],
+                rustls::PrivateKey(server_cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let server = Endpoint::server(
+            quinn::ServerConfig::with_crypto(Arc::new(server_crypto)),
+            (Ipv4Addr::LOCALHOST, 0).into(),
+        )
+        .unwrap();
+
+        (server, pin)
+    }
+
+    // a client certificate and the identity of its key fingerprint
+    fn client_cert() -> (rcgen::Certificate, Identity) {
+        let cert = rcgen::generate_simple_self_signed([]).unwrap();
+        let der = Certificate(cert.serialize_der().unwrap());
+        let identity = Identity::SpkiSha256(spki_sha256(&der).unwrap());
+
+        (cert, identity)
+    }
+
+    // connects a client with `cert` to `server`, returning the client's end of the
+    // connection and the server's, which is not routed yet
+    async fn handshake(
+        (server, pin): &(Endpoint, [u8; 32]),
+        cert: &rcgen::Certificate,
+    ) -> (Connection, Connection) {
+        let client_crypto = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(PinnedServer::new(*pin)))
+            .with_client_auth_cert(
+                vec![Certificate(cert.serialize_der().unwrap())],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let mut client = Endpoint::client((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(client_crypto)));
+
+        let connecting = client
+            .connect(server.local_addr().unwrap(), "localhost")
+            .unwrap();
+        let (client_conn, server_conn) = tokio::join!(connecting, async {
+            server.accept().await.unwrap().await.unwrap()
+        });
+
+        (client_conn.unwrap(), server_conn)
+    }
+
+    fn closed_with(error: ConnectionError, code: VarInt) -> bool {
+        matches!(error, ConnectionError::ApplicationClosed(close) if close.error_code == code)
+    }
+
+    /// Connects a client over loopback for each of `allowed_ips`, configured in `router`
+    /// by its key fingerprint with those allowed IPs. Returns the identities with the
+    /// clients' ends of the connections and the server's, which the router only holds
+    /// weakly.
+    pub(crate) async fn connect_peers(
+        router: &Arc<Router>,
+        allowed_ips: &[(IpAddr, u8)],
+    ) -> Vec<(Identity, Connection, Arc<Connection>)> {
+        let server = loopback(router);
+
+        let mut connections = vec![];
+        for &(ip, prefix) in allowed_ips {
+            let (cert, identity) = client_cert();
+            router.add_peer(identity.clone(), [(ip, prefix)]);
+            let (client_conn, server_conn) = handshake(&server, &cert).await;
+            let (server_conn, _) = router
+                .connect(server_conn, DuplicatePolicy::NewestWins)
+                .await
+                .unwrap();
+            connections.push((identity, client_conn, server_conn));
+        }
+
+        connections
+    }
+
+    #[test]
+    fn test_push() {
+        let router = Router::default();
+        let mut pushed = router.pushed();
+        let config = |mtu| SessionConfig {
+            mtu: Some(mtu),
+            ..Default::default()
+        };
+
+        router.push(config(1380));
+        assert!(pushed.has_changed().unwrap());
+        assert_eq!(pushed.borrow_and_update().version, 1);
+
+        // pushing the same configuration again is not an update
+        router.push(config(1380));
+        assert!(!pushed.has_changed().unwrap());
+
+        router.push(config(1280));
+        let pushed = pushed.borrow_and_update();
+        assert_eq!((pushed.version, pushed.mtu), (2, Some(1280)));
+    }
+
+    #[tokio::test]
+    async fn test_update_peers() {
+        let router = Router::default();
+        let alice = Identity::CommonName("alice".to_owned());
+        let ip = "10.10.0.2".parse().unwrap();
+        let deny = || Some(Acl::new(vec![], Action::Deny));
+        let packet = [0x45; 20];
+
+        // a new peer comes with its ACL
+        router
+            .update_peers(PeerIps::from([(alice.clone(), (vec![(ip, 32)], deny()))]))
+            .await;
+        let peer = Arc::clone(&router.peers.read().unwrap()[&alice]);
+        assert!(peer.allows(ip));
+        assert!(!peer.filter(&packet, Direction::FromPeer));
+
+        // as does a changed one, keeping the hit counts of rules that did not change
+        router
+            .update_peers(PeerIps::from([(alice.clone(), (vec![], deny()))]))
+            .await;
+        assert!(!peer.allows(ip));
+        assert_eq!(peer.acl.read().unwrap().as_ref().unwrap().hits()[0].1, 1);
+        router
+            .update_peers(PeerIps::from([(alice.clone(), (vec![], None))]))
+            .await;
+        assert!(peer.filter(&packet, Direction::FromPeer));
+    }
+
+    #[tokio::test]
+    async fn test_disconnected() {
+        let router = Arc::new(Router::default());
+        let ip = "10.10.0.2".parse().unwrap();
+        let peers = connect_peers(&router, &[(ip, 32)]).await;
+        let (_, client, conn) = &peers[0];
+        assert!(router.lookup(ip).await.is_some());
+
+        client.close(0u32.into(), b"bye");
+        conn.closed().await;
+        router.disconnected(conn).await;
+        assert!(router.lookup(ip).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_reject() {
+        let router = Arc::new(Router::default());
+        let server = loopback(&router);
+        let (cert, identity) = client_cert();
+        let ip = "10.10.0.2".parse().unwrap();
+        router.add_peer(identity, [(ip, 32)]);
+
+        let (first, conn) = handshake(&server, &cert).await;
+        let (conn, _) = router.connect(conn, DuplicatePolicy::Reject).await.unwrap();
+        let (second, duplicate) = handshake(&server, &cert).await;
+        let result = router.connect(duplicate, DuplicatePolicy::Reject).await;
+        assert!(
+            matches!(result, Err(ConnectError::Duplicate(remote)) if remote == conn.remote_address())
+        );
+
+        assert!(closed_with(second.closed().await, PEER_DUPLICATE));
+        assert!(first.close_reason().is_none());
+        let (routed, _) = router.lookup(ip).await.unwrap();
+        assert!(Arc::ptr_eq(&routed, &conn));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_newest_wins() {
+        let router = Arc::new(Router::default());
+        let server = loopback(&router);
+        let (cert, identity) = client_cert();
+        let ip = "10.10.0.2".parse().unwrap();
+        router.add_peer(identity, [(ip, 32)]);
+
+        let (first, conn) = handshake(&server, &cert).await;
+        let _first = router
+            .connect(conn, DuplicatePolicy::NewestWins)
+            .await
+            .unwrap();
+        let (_second, conn) = handshake(&server, &cert).await;
+        let (conn, _) = router
+            .connect(conn, DuplicatePolicy::NewestWins)
+            .await
+            .unwrap();
+
+        assert!(closed_with(first.closed().await, PEER_REPLACED));
+        let (routed, _) = router.lookup(ip).await.unwrap();
+        assert!(Arc::ptr_eq(&routed, &conn));
     }
 }
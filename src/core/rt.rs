@@ -2,21 +2,20 @@
 //! exposing the option to set `fwmark` on all tunnel traffic managed by `vqn`. This
 //! is the same trick employed by WireGuard to prevent routing loops.
 use std::io;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::fd::AsRawFd;
 
-use nix::sys::socket::setsockopt;
-use nix::sys::socket::sockopt;
+use nix::errno::Errno;
+use nix::sys::socket::{
+    bind, setsockopt, socket, sockopt, AddressFamily, SockFlag, SockType, SockaddrStorage,
+};
 use quinn::{default_runtime, Endpoint, ServerConfig};
 
 // https://docs.rs/quinn/0.10.2/src/quinn/endpoint.rs.html#55-65
 pub fn client_endpoint(addr: SocketAddr, fwmark: Option<u32>) -> io::Result<Endpoint> {
-    let socket = std::net::UdpSocket::bind(addr)?;
-    if let Some(fwmark) = fwmark {
-        setsockopt(&socket, sockopt::Mark, &fwmark)?;
-    }
+    let socket = udp_socket(addr, fwmark, true)?;
 
-    let runtime = default_runtime()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no async runtime found"))?;
+    let runtime = default_runtime().ok_or_else(|| io::Error::other("no async runtime found"))?;
 
     Endpoint::new(quinn::EndpointConfig::default(), None, socket, runtime)
 }
@@ -25,15 +24,12 @@ pub fn client_endpoint(addr: SocketAddr, fwmark: Option<u32>) -> io::Result<Endp
 pub fn server_endpoint(
     config: ServerConfig,
     addr: SocketAddr,
+    dual_stack: bool,
     fwmark: Option<u32>,
 ) -> io::Result<Endpoint> {
-    let socket = std::net::UdpSocket::bind(addr)?;
-    if let Some(fwmark) = fwmark {
-        setsockopt(&socket, sockopt::Mark, &fwmark)?;
-    }
+    let socket = udp_socket(addr, fwmark, dual_stack)?;
 
-    let runtime = default_runtime()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no async runtime found"))?;
+    let runtime = default_runtime().ok_or_else(|| io::Error::other("no async runtime found"))?;
 
     Endpoint::new(
         quinn::EndpointConfig::default(),
@@ -42,3 +38,58 @@ pub fn server_endpoint(
         runtime,
     )
 }
+
+// binds a UDP socket to `addr`. A `dual_stack` IPv6 socket also accepts IPv4 traffic
+// regardless of the `net.ipv6.bindv6only` sysctl, and falls back to IPv4 on a host
+// without IPv6. Other IPv6 sockets are IPv6 only, so an IPv4 socket can share their port.
+fn udp_socket(addr: SocketAddr, fwmark: Option<u32>, dual_stack: bool) -> io::Result<UdpSocket> {
+    match bind_udp(addr, fwmark, dual_stack) {
+        Err(e)
+            if dual_stack
+                && addr.is_ipv6()
+                && matches!(
+                    Errno::from_i32(e.raw_os_error().unwrap_or_default()),
+                    Errno::EAFNOSUPPORT | Errno::EADDRNOTAVAIL
+                ) =>
+        {
+            bind_udp((Ipv4Addr::UNSPECIFIED, addr.port()).into(), fwmark, false)
+        }
+        result => result,
+    }
+}
+
+fn bind_udp(addr: SocketAddr, fwmark: Option<u32>, dual_stack: bool) -> io::Result<UdpSocket> {
+    let family = match addr {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let socket = socket(family, SockType::Datagram, SockFlag::SOCK_CLOEXEC, None)?;
+    if addr.is_ipv6() {
+        setsockopt(&socket, sockopt::Ipv6V6Only, &!dual_stack)?;
+    }
+    if let Some(fwmark) = fwmark {
+        setsockopt(&socket, sockopt::Mark, &fwmark)?;
+    }
+    bind(socket.as_raw_fd(), &SockaddrStorage::from(addr))?;
+
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_udp_socket() {
+        // an explicit IPv6 wildcard leaves the IPv4 one free
+        let v6 = udp_socket("[::]:0".parse().unwrap(), None, false).unwrap();
+        let port = v6.local_addr().unwrap().port();
+        udp_socket((Ipv4Addr::UNSPECIFIED, port).into(), None, false).unwrap();
+
+        // a dual-stack one does not
+        let dual = udp_socket("[::]:0".parse().unwrap(), None, true).unwrap();
+        let port = dual.local_addr().unwrap().port();
+        let e = udp_socket((Ipv4Addr::UNSPECIFIED, port).into(), None, false).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+    }
+}
@@ -0,0 +1,104 @@
+//! The control stream a server opens on every client connection.
+//!
+//! Right after a client has been accepted, the server opens a bidirectional stream and
+//! sends the client its [SessionConfig] as a line of JSON, followed by another line
+//! whenever the configuration it pushes changes. Servers that predate it open no control
+//! stream, which means nothing is pushed.
+use std::io;
+use std::net::IpAddr;
+
+use quinn::{Connection, ConnectionError, RecvStream, SendStream};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+
+/// Settings a server sends to a client when it connects and whenever they change.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionConfig {
+    /// Increases with every change the server pushes while it is running.
+    #[serde(default)]
+    pub version: u64,
+    /// Addresses leased to the client, with the prefix length of the pool they are from.
+    #[serde(default)]
+    pub address: Vec<(IpAddr, u8)>,
+    /// Ranges to route through the tunnel.
+    #[serde(default)]
+    pub routes: Vec<(IpAddr, u8)>,
+    /// DNS servers of the tun interface.
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+    /// DNS search domains of the tun interface.
+    #[serde(default)]
+    pub search: Vec<String>,
+    /// Recommended MTU of the tun interface.
+    #[serde(default)]
+    pub mtu: Option<u16>,
+}
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error(transparent)]
+    Conn(#[from] ConnectionError),
+
+    #[error("error read/write from control stream: {0}")]
+    Stream(#[from] io::Error),
+
+    #[error("invalid session configuration: {0}")]
+    Invalid(#[from] serde_json::Error),
+
+    #[error("control stream closed by the server")]
+    Closed,
+}
+
+/// The server's end of a control stream.
+pub struct Sender {
+    send: SendStream,
+}
+
+impl Sender {
+    /// Opens the control stream of `conn`.
+    pub async fn open(conn: &Connection) -> Result<Self, SessionError> {
+        let (send, _recv) = conn.open_bi().await?;
+        Ok(Sender { send })
+    }
+
+    pub async fn send(&mut self, config: &SessionConfig) -> Result<(), SessionError> {
+        let mut line = serde_json::to_vec(config)?;
+        line.push(b'\n');
+        self.send.write_all(&line).await.map_err(io::Error::from)?;
+
+        Ok(())
+    }
+}
+
+/// The client's end of a control stream, which is accepted once the server opens it.
+pub struct Receiver {
+    conn: Connection,
+    lines: Option<Lines<BufReader<RecvStream>>>,
+}
+
+impl Receiver {
+    pub fn new(conn: &Connection) -> Self {
+        Receiver {
+            conn: conn.clone(),
+            lines: None,
+        }
+    }
+
+    /// Receives the next [SessionConfig] sent by the server, waiting for the server to
+    /// open the control stream first.
+    ///
+    /// Cancel safe, so that it can be awaited in a `select!` loop.
+    pub async fn recv(&mut self) -> Result<SessionConfig, SessionError> {
+        let lines = match &mut self.lines {
+            Some(lines) => lines,
+            None => {
+                let (_send, recv) = self.conn.accept_bi().await?;
+                self.lines.insert(BufReader::new(recv).lines())
+            }
+        };
+        let line = lines.next_line().await?.ok_or(SessionError::Closed)?;
+
+        Ok(serde_json::from_str(&line)?)
+    }
+}
@@ -0,0 +1,357 @@
+//! Expiry of the certificates a tunnel is configured with or presented.
+//!
+//! The certificates named in the configuration are checked on startup, on every reload
+//! and then hourly, with a warning each time one gets within another of the configured
+//! number of days of its `notAfter`. Handshakes failing on an expired certificate name it.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::server::{ClientCertVerified, ClientCertVerifier};
+use rustls::{
+    AlertDescription, Certificate, CertificateError, DigitallySignedStruct, DistinguishedName,
+    ServerName, SignatureScheme,
+};
+use thiserror::Error;
+use time::OffsetDateTime;
+use tokio::sync::watch;
+
+use crate::conf::{Conf, Network};
+use crate::tls;
+
+/// Days before `notAfter` a certificate is warned about, unless configured otherwise.
+pub const DEFAULT_WARN_DAYS: [u32; 3] = [30, 7, 1];
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Subject and expiry of a certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Validity {
+    pub subject: String,
+    pub not_after: OffsetDateTime,
+}
+
+impl Validity {
+    /// Returns `None` if `cert` cannot be parsed.
+    pub fn of(cert: &Certificate) -> Option<Validity> {
+        let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+        Some(Validity {
+            subject: cert.subject().to_string(),
+            not_after: cert.validity().not_after.to_datetime(),
+        })
+    }
+
+    /// Whole days left until `notAfter`, negative once it has passed.
+    pub fn days_left(&self, now: OffsetDateTime) -> i64 {
+        (self.not_after - now).whole_days()
+    }
+}
+
+impl fmt::Display for Validity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = if self.not_after <= OffsetDateTime::now_utc() {
+            "expired"
+        } else {
+            "expires"
+        };
+        write!(
+            f,
+            "{}, which {verb} on {}",
+            self.subject,
+            self.not_after.date()
+        )
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("{0} certificate {1}")]
+struct Expired(&'static str, Validity);
+
+/// Warns about certificates getting close to their expiry, once per threshold.
+#[derive(Default)]
+pub struct Monitor {
+    // the smallest threshold warned about per certificate, 0 once it has expired
+    warned: HashMap<Vec<u8>, u32>,
+}
+
+impl Monitor {
+    /// Checks `cert`, read from `path`, against the `warn_days` thresholds.
+    ///
+    /// Returns the threshold newly crossed, 0 if the certificate has expired.
+    pub fn check(
+        &mut self,
+        path: &Path,
+        cert: &Certificate,
+        warn_days: &[u32],
+        now: OffsetDateTime,
+    ) -> Option<u32> {
+        let validity = Validity::of(cert)?;
+        let days_left = validity.days_left(now);
+        let threshold = if validity.not_after <= now {
+            0
+        } else {
+            warn_days
+                .iter()
+                .copied()
+                .filter(|&days| days_left < days as i64)
+                .min()?
+        };
+        if self
+            .warned
+            .get(&cert.0)
+            .is_some_and(|&warned| warned <= threshold)
+        {
+            return None;
+        }
+        self.warned.insert(cert.0.clone(), threshold);
+
+        let (subject, date, path) = (&validity.subject, validity.not_after.date(), path.display());
+        if threshold == 0 {
+            tracing::error!("certificate {subject} ({path}) expired on {date}");
+        } else {
+            tracing::warn!("certificate {subject} ({path}) expires in {days_left} days, on {date}");
+        }
+
+        Some(threshold)
+    }
+
+    /// Forgets the certificates other than `configured`, so that one configured again
+    /// is warned about afresh.
+    pub fn retain(&mut self, configured: &[Certificate]) {
+        self.warned
+            .retain(|der, _| configured.iter().any(|cert| &cert.0 == der));
+    }
+}
+
+/// Checks the certificates configured for a tunnel on startup, whenever its configuration
+/// changes and every hour.
+pub async fn monitor(mut conf: watch::Receiver<Conf>) {
+    let mut monitor = Monitor::default();
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            changed = conf.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+        }
+
+        let conf = conf.borrow_and_update().clone();
+        let warn_days = conf
+            .tls
+            .expiry_warn_days
+            .as_deref()
+            .unwrap_or(&DEFAULT_WARN_DAYS);
+        let now = OffsetDateTime::now_utc();
+        let mut configured = vec![];
+        for path in cert_files(&conf) {
+            // unreadable files are reported where they are used
+            for cert in crate::certs(&path).unwrap_or_default() {
+                monitor.check(&path, &cert, warn_days, now);
+                configured.push(cert);
+            }
+        }
+        monitor.retain(&configured);
+    }
+}
+
+fn cert_files(conf: &Conf) -> Vec<PathBuf> {
+    let mut files: Vec<_> = [&conf.tls.cert, &conf.tls.ca_cert]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+    if let Network::Server { client, .. } = &conf.network {
+        files.extend(
+            client
+                .iter()
+                .filter_map(|client| client.client_cert.clone()),
+        );
+    }
+
+    files
+}
+
+/// Returns `true` if the peer aborted the handshake because our certificate has expired.
+pub fn rejected_as_expired(e: &quinn::ConnectionError) -> bool {
+    // TLS alerts are sent as the CRYPTO_ERROR range of QUIC transport errors
+    let expired = 0x100 + AlertDescription::CertificateExpired.get_u8() as u64;
+    matches!(e, quinn::ConnectionError::ConnectionClosed(close) if u64::from(close.error_code) == expired)
+}
+
+/// Names the server certificate in the error of a server that failed verification
+/// because of its expiry.
+pub struct ServerExpiry(pub Arc<dyn ServerCertVerifier>);
+
+impl ServerCertVerifier for ServerExpiry {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.0
+            .verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )
+            .map_err(|e| match (&e, expired(end_entity, intermediates)) {
+                (rustls::Error::InvalidCertificate(CertificateError::Expired), Some(validity)) => {
+                    tls::cert_error(Expired("server", validity))
+                }
+                _ => e,
+            })
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<rustls::client::HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<rustls::client::HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+
+    fn request_scts(&self) -> bool {
+        self.0.request_scts()
+    }
+}
+
+/// Logs the client certificates that fail verification because of their expiry, which
+/// clients only learn about as an alert.
+pub struct ClientExpiry(pub Arc<dyn ClientCertVerifier>);
+
+impl ClientCertVerifier for ClientExpiry {
+    fn offer_client_auth(&self) -> bool {
+        self.0.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.0.client_auth_mandatory()
+    }
+
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        self.0.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let result = self.0.verify_client_cert(end_entity, intermediates, now);
+        if let (Err(rustls::Error::InvalidCertificate(CertificateError::Expired)), Some(validity)) =
+            (&result, expired(end_entity, intermediates))
+        {
+            tracing::warn!("rejected client certificate {validity}");
+        }
+
+        result
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<rustls::client::HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<rustls::client::HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+// the first expired certificate of a presented chain, which may be an intermediate one
+fn expired(end_entity: &Certificate, intermediates: &[Certificate]) -> Option<Validity> {
+    let now = OffsetDateTime::now_utc();
+    std::iter::once(end_entity)
+        .chain(intermediates)
+        .filter_map(Validity::of)
+        .find(|validity| validity.not_after <= now)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_monitor() {
+        let now = OffsetDateTime::now_utc();
+        let cert = |days: i64| {
+            let mut params = rcgen::CertificateParams::new(["vqn.example.org".to_owned()]);
+            params.not_before = now - time::Duration::days(400);
+            params.not_after = now + time::Duration::days(days) + time::Duration::hours(1);
+            let cert = rcgen::Certificate::from_params(params).unwrap();
+            Certificate(cert.serialize_der().unwrap())
+        };
+        let warn_days = [30, 7, 1];
+        let path = Path::new("cert.pem");
+        let mut monitor = Monitor::default();
+
+        let cert_20 = cert(20);
+        assert_eq!(Validity::of(&cert_20).unwrap().days_left(now), 20);
+        assert_eq!(monitor.check(path, &cert(40), &warn_days, now), None);
+        assert_eq!(monitor.check(path, &cert_20, &warn_days, now), Some(30));
+        assert_eq!(monitor.check(path, &cert_20, &warn_days, now), None);
+
+        // a day later, the same certificate has crossed no other threshold
+        let later = now + time::Duration::days(1);
+        assert_eq!(monitor.check(path, &cert_20, &warn_days, later), None);
+        let later = now + time::Duration::days(14);
+        assert_eq!(monitor.check(path, &cert_20, &warn_days, later), Some(7));
+        let later = now + time::Duration::days(21);
+        assert_eq!(monitor.check(path, &cert_20, &warn_days, later), Some(0));
+        assert_eq!(monitor.check(path, &cert_20, &warn_days, later), None);
+
+        // a certificate first seen close to its expiry is warned about once
+        let cert_3 = cert(3);
+        assert_eq!(monitor.check(path, &cert_3, &warn_days, now), Some(7));
+        assert_eq!(monitor.check(path, &cert_3, &warn_days, now), None);
+        assert_eq!(monitor.check(path, &cert(-3), &[], now), Some(0));
+
+        // only certificates still configured are remembered
+        monitor.retain(std::slice::from_ref(&cert_3));
+        assert_eq!(monitor.warned.len(), 1);
+        assert_eq!(monitor.check(path, &cert_3, &warn_days, now), None);
+        monitor.retain(&[]);
+        assert_eq!(monitor.check(path, &cert_3, &warn_days, now), Some(7));
+    }
+}
@@ -0,0 +1,671 @@
+//! Routing configuration of the tun interface.
+//!
+//! The configuration is expressed as a list of [Op]s, each of which is applied by a
+//! [Backend]. A batch of ops is applied transactionally: if one of them fails, the ops
+//! applied before it are reverted in reverse order.
+//!
+//! Applied ops are recorded in a [State] file, which is what [dev_down] and [cleanup]
+//! revert.
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::conf::{Address, Cidr, Conf, Network, DEFAULT_TUN_NAME};
+
+mod netlink;
+mod shell;
+mod state;
+
+use state::State;
+
+/// How routes and rules are installed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum BackendKind {
+    /// Talk to the kernel over rtnetlink, falling back to `shell` if that's unavailable.
+    #[default]
+    Netlink,
+    /// Run `ip` from iproute2.
+    Shell,
+}
+
+enum Backend {
+    Netlink(netlink::Netlink),
+    Shell,
+}
+
+static BACKEND: OnceLock<Backend> = OnceLock::new();
+
+/// Selects the backend of all subsequent firewall operations.
+///
+/// Netlink requests are served by a dedicated thread which stays in the network
+/// namespace of the calling thread, so this should be called after entering the
+/// namespace of the tun interface.
+pub fn init(kind: BackendKind) {
+    BACKEND.get_or_init(|| match kind {
+        BackendKind::Netlink => match netlink::Netlink::new() {
+            Ok(netlink) => Backend::Netlink(netlink),
+            Err(e) => {
+                tracing::warn!("rtnetlink unavailable ({e}), falling back to iproute2");
+                Backend::Shell
+            }
+        },
+        BackendKind::Shell => Backend::Shell,
+    });
+}
+
+fn backend() -> &'static Backend {
+    BACKEND.get_or_init(|| Backend::Shell)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Family::V4 => f.write_str("-4"),
+            Family::V6 => f.write_str("-6"),
+        }
+    }
+}
+
+impl From<IpAddr> for Family {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6,
+        }
+    }
+}
+
+/// A single change to the routing configuration, displayed like the arguments of the
+/// equivalent `ip` command.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Op {
+    /// An address assigned to `dev`.
+    Address { dev: String, address: Cidr },
+    /// A route of `dst` through `dev` in `table`.
+    Route { dev: String, dst: Cidr, table: u32 },
+    /// Looks up `table` for all packets not marked with `fwmark`, i.e. everything but
+    /// the tunnel traffic itself.
+    FwmarkRule {
+        family: Family,
+        fwmark: u32,
+        table: u32,
+        priority: u32,
+    },
+    /// Looks up the main table, ignoring its default routes, so more specific routes
+    /// such as the local network take precedence over the tunnel.
+    SuppressRule { family: Family, priority: u32 },
+    /// A kernel parameter set to `value`, and back to `previous` when deleted by the
+    /// last tunnel that needs it.
+    Sysctl {
+        key: String,
+        value: String,
+        previous: String,
+    },
+    /// Accepts forwarding traffic coming in from `dev`. Inserted at the top of the
+    /// `FORWARD` chain, so it overrides the chain's policy and rules for that traffic.
+    ForwardFrom { family: Family, dev: String },
+    /// Accepts forwarding replies to the traffic from `dev`.
+    ForwardReplies { family: Family, dev: String },
+    /// Masquerades traffic from `src` leaving through `egress`, or any interface but
+    /// `dev`.
+    Masquerade {
+        src: Cidr,
+        dev: String,
+        egress: Option<String>,
+    },
+    /// DNS servers and search domains of `dev`, set via `resolvectl`.
+    Dns {
+        dev: String,
+        servers: String,
+        #[serde(default)]
+        domains: Vec<String>,
+    },
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Address { dev, address } => write!(f, "address {address} dev {dev}"),
+            Op::Route { dev, dst, table } => write!(f, "route {dst} dev {dev} table {table}"),
+            Op::FwmarkRule {
+                family,
+                fwmark,
+                table,
+                priority,
+            } => write!(
+                f,
+                "{family} rule not fwmark {fwmark} table {table} priority {priority}"
+            ),
+            Op::SuppressRule { family, priority } => write!(
+                f,
+                "{family} rule table main suppress_prefixlength 0 priority {priority}"
+            ),
+            Op::Sysctl { key, value, .. } => write!(f, "sysctl {key}={value}"),
+            Op::ForwardFrom { family, dev } => {
+                write!(f, "{family} forward accept in {dev}")
+            }
+            Op::ForwardReplies { family, dev } => {
+                write!(f, "{family} forward accept out {dev} established")
+            }
+            Op::Masquerade { src, dev, egress } => match egress {
+                Some(egress) => write!(f, "masquerade {src} out {egress}"),
+                None => write!(f, "masquerade {src} out !{dev}"),
+            },
+            Op::Dns {
+                dev,
+                servers,
+                domains,
+            } => {
+                write!(f, "dns")?;
+                if !servers.is_empty() {
+                    write!(f, " {servers}")?;
+                }
+                write!(f, " dev {dev}")?;
+                if !domains.is_empty() {
+                    write!(f, " domain {}", domains.join(" "))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Add,
+    Delete,
+}
+
+impl Action {
+    fn inverse(self) -> Self {
+        match self {
+            Action::Add => Action::Delete,
+            Action::Delete => Action::Add,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to add {op}: {error}")]
+    Add { op: Op, error: io::Error },
+
+    #[error("failed to delete {op}: {error}")]
+    Delete { op: Op, error: io::Error },
+
+    #[error("failed to record firewall state in {}: {error}", path.display())]
+    State { path: PathBuf, error: io::Error },
+
+    #[error("{tun} is in use by another vqn (pid {pid})")]
+    InUse { tun: String, pid: u32 },
+}
+
+impl Error {
+    fn new(action: Action, op: &Op, error: io::Error) -> Self {
+        let op = op.clone();
+        match action {
+            Action::Add => Error::Add { op, error },
+            Action::Delete => Error::Delete { op, error },
+        }
+    }
+}
+
+fn apply(tun: &str, action: Action, op: &Op) -> Result<(), Error> {
+    // a kernel parameter another tunnel still needs is left for that one to restore
+    if let (Action::Delete, Op::Sysctl { key, .. }) = (action, op) {
+        if State::others(tun)
+            .iter()
+            .any(|state| needs_sysctl(&state.ops, key))
+        {
+            tracing::debug!("keeping {op}, another tunnel needs it");
+            return Ok(());
+        }
+    }
+
+    let result = match backend() {
+        Backend::Netlink(netlink) => netlink.apply(action, op),
+        Backend::Shell => shell::apply(action, op),
+    };
+    tracing::debug!("{action:?} {op}: {result:?}");
+
+    result.map_err(|e| Error::new(action, op, e))
+}
+
+// applies all `steps` and records them in the state of `tun` or, if one of them fails,
+// none of them
+//
+// ops are recorded before they are added and forgotten once they are deleted, so a vqn
+// dying halfway through never leaves an op applied that the record does not know of
+fn transaction(tun: &str, steps: &[(Action, Op)]) -> Result<(), Error> {
+    if steps.is_empty() {
+        return Ok(());
+    }
+    let record = |steps: &[(Action, Op)]| {
+        State::record(tun, steps).map_err(|error| Error::State {
+            path: State::path(tun),
+            error,
+        })
+    };
+    let only = |wanted: Action, steps: &[(Action, Op)]| -> Vec<_> {
+        steps
+            .iter()
+            .filter(|(action, _)| *action == wanted)
+            .cloned()
+            .collect()
+    };
+    // reverts `applied`, returning the deletions of the added ops it removed again
+    let rollback = |applied: &[(Action, Op)]| {
+        let mut removed = vec![];
+        for (action, op) in applied.iter().rev() {
+            match apply(tun, action.inverse(), op) {
+                Ok(()) if *action == Action::Add => removed.push((Action::Delete, op.clone())),
+                Ok(()) => (),
+                Err(e) => tracing::warn!("rollback: {e}"),
+            }
+        }
+        removed
+    };
+
+    record(&only(Action::Add, steps))?;
+    for (i, (action, op)) in steps.iter().enumerate() {
+        if let Err(e) = apply(tun, *action, op) {
+            // ops whose rollback failed are still applied and stay recorded
+            let mut forgotten = rollback(&steps[..i]);
+            forgotten.extend(
+                only(Action::Add, &steps[i..])
+                    .into_iter()
+                    .map(|(_, op)| (Action::Delete, op)),
+            );
+            if let Err(e) = record(&forgotten) {
+                tracing::warn!("rollback: {e}");
+            }
+            return Err(e);
+        }
+    }
+    if let Err(e) = record(&only(Action::Delete, steps)) {
+        rollback(steps);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+pub fn dev_up(conf: &Conf) -> Result<(), Error> {
+    let steps: Vec<_> = up_ops(conf)
+        .into_iter()
+        .map(|op| (Action::Add, op))
+        .collect();
+
+    transaction(tun_name(conf), &steps)
+}
+
+/// Reverts everything this process applied to `tun`.
+pub fn dev_down(tun: &str) {
+    match State::load(tun) {
+        Ok(Some(state)) if !state.in_use() => teardown(tun, &state),
+        Ok(_) => (),
+        Err(e) => tracing::error!("failed to read {}: {e}", State::path(tun).display()),
+    }
+}
+
+/// Reverts the ops left behind for `tun` by a vqn that exited without cleaning up,
+/// returning `false` if there were none.
+pub fn cleanup(tun: &str) -> Result<bool, Error> {
+    let state = State::load(tun).map_err(|error| Error::State {
+        path: State::path(tun),
+        error,
+    })?;
+
+    match state {
+        Some(state) if state.in_use() => Err(Error::InUse {
+            tun: tun.to_owned(),
+            pid: state.pid,
+        }),
+        Some(state) => {
+            teardown(tun, &state);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+fn teardown(tun: &str, state: &State) {
+    // routes and addresses are gone already if the interface is, which is fine
+    for op in state.ops.iter().rev() {
+        if let Err(e) = apply(tun, Action::Delete, op) {
+            tracing::debug!("{e}");
+        }
+    }
+    if let Err(e) = State::remove(tun) {
+        tracing::error!("failed to remove {}: {e}", State::path(tun).display());
+    }
+}
+
+/// Calls [dev_down] when dropped, including while unwinding from a panic.
+pub struct Teardown(String);
+
+impl Teardown {
+    pub fn new(tun: &str) -> Self {
+        Teardown(tun.to_owned())
+    }
+}
+
+impl Drop for Teardown {
+    fn drop(&mut self) {
+        dev_down(&self.0);
+    }
+}
+
+/// Assigns an additional `address` to the interface `tun`.
+pub fn dev_address(tun: &str, address: Cidr) -> Result<(), Error> {
+    let op = Op::Address {
+        dev: tun.to_owned(),
+        address,
+    };
+
+    transaction(tun, &[(Action::Add, op)])
+}
+
+/// Replaces the addresses `old` of the interface `tun`, e.g. ones assigned by a server
+/// the last time, with `new`.
+pub fn dev_readdress(tun: &str, old: &[Cidr], new: &[Cidr]) -> Result<(), Error> {
+    let op = |address: &Cidr| Op::Address {
+        dev: tun.to_owned(),
+        address: *address,
+    };
+    let steps: Vec<_> = old
+        .iter()
+        .filter(|address| !new.contains(address))
+        .map(|address| (Action::Delete, op(address)))
+        .chain(
+            new.iter()
+                .filter(|address| !old.contains(address))
+                .map(|address| (Action::Add, op(address))),
+        )
+        .collect();
+
+    transaction(tun, &steps)
+}
+
+/// Reconciles the routes installed by [dev_up] for `old` with the ones required by `new`.
+pub fn dev_reload(old: &Conf, new: &Conf) -> Result<(), Error> {
+    let tun = tun_name(new);
+    let table = new.network.table();
+    let route = |dst: &Cidr| Op::Route {
+        dev: tun.to_owned(),
+        dst: *dst,
+        table,
+    };
+
+    let old_routes = routes(old);
+    let new_routes = routes(new);
+    let mut steps: Vec<_> = old_routes
+        .difference(&new_routes)
+        .map(|dst| (Action::Delete, route(dst)))
+        .chain(
+            new_routes
+                .difference(&old_routes)
+                .map(|dst| (Action::Add, route(dst))),
+        )
+        .collect();
+
+    let old_dns = dns_op(old);
+    let new_dns = dns_op(new);
+    if old_dns != new_dns {
+        steps.extend(old_dns.map(|op| (Action::Delete, op)));
+        steps.extend(new_dns.map(|op| (Action::Add, op)));
+    }
+
+    transaction(tun, &steps)
+}
+
+fn tun_name(conf: &Conf) -> &str {
+    conf.network.name().unwrap_or(DEFAULT_TUN_NAME)
+}
+
+fn up_ops(conf: &Conf) -> Vec<Op> {
+    let tun = tun_name(conf);
+    let fwmark = conf.network.fwmark();
+    let table = conf.network.table();
+    let priority = conf.network.rule_priority();
+
+    let mut ops: Vec<_> = routes(conf)
+        .into_iter()
+        .map(|dst| Op::Route {
+            dev: tun.to_owned(),
+            dst,
+            table,
+        })
+        .collect();
+    // the main table, minus its default routes, is consulted first
+    for family in [Family::V4, Family::V6] {
+        ops.push(Op::SuppressRule { family, priority });
+        ops.push(Op::FwmarkRule {
+            family,
+            fwmark,
+            table,
+            priority: priority + 1,
+        });
+    }
+    ops.extend(dns_op(conf));
+    ops.extend(nat_ops(conf, |key| sysctl_op(tun, key, "1")));
+
+    ops
+}
+
+// enables forwarding with `forwarding`, which is given the sysctl key of each address
+// family, and masquerades the client networks of a server with [network.nat]
+fn nat_ops(conf: &Conf, forwarding: impl Fn(&'static str) -> Option<Op>) -> Vec<Op> {
+    let Network::Server {
+        address,
+        pool,
+        nat: Some(nat),
+        ..
+    } = &conf.network
+    else {
+        return vec![];
+    };
+    let tun = tun_name(conf);
+    let sources: BTreeSet<Cidr> = match &nat.source {
+        Some(source) => source.values.iter().map(|cidr| cidr.network()).collect(),
+        None => address
+            .iter()
+            .chain(pool.iter().flat_map(Address::iter))
+            .map(Cidr::network)
+            .collect(),
+    };
+
+    let mut ops = vec![];
+    for family in [Family::V4, Family::V6] {
+        if !sources.iter().any(|src| Family::from(src.ip()) == family) {
+            continue;
+        }
+        ops.extend(forwarding(forwarding_key(family)));
+        ops.push(Op::ForwardFrom {
+            family,
+            dev: tun.to_owned(),
+        });
+        ops.push(Op::ForwardReplies {
+            family,
+            dev: tun.to_owned(),
+        });
+    }
+    ops.extend(sources.into_iter().map(|src| Op::Masquerade {
+        src,
+        dev: tun.to_owned(),
+        egress: nat.egress.clone(),
+    }));
+
+    ops
+}
+
+fn forwarding_key(family: Family) -> &'static str {
+    match family {
+        Family::V4 => "net.ipv4.ip_forward",
+        Family::V6 => "net.ipv6.conf.all.forwarding",
+    }
+}
+
+// sets `key` to `value` for `tun` unless it already is, remembering the value to
+// restore. Tunnels share the setting: one setting it while another has it set already
+// takes over the value to restore, which the last of them to go does.
+fn sysctl_op(tun: &str, key: &str, value: &str) -> Option<Op> {
+    let shared = State::others(tun).into_iter().find_map(|state| {
+        state
+            .ops
+            .into_iter()
+            .find(|op| matches!(op, Op::Sysctl { key: k, value: v, .. } if k == key && v == value))
+    });
+    if shared.is_some() {
+        return shared;
+    }
+
+    let path = format!("/proc/sys/{}", key.replace('.', "/"));
+    let previous = match std::fs::read_to_string(&path) {
+        Ok(previous) => previous.trim().to_owned(),
+        Err(e) => {
+            tracing::warn!("failed to read {key}: {e}");
+            return None;
+        }
+    };
+
+    (previous != value).then(|| Op::Sysctl {
+        key: key.to_owned(),
+        value: value.to_owned(),
+        previous,
+    })
+}
+
+// whether the tunnel that applied `ops` relies on `key`, which it may not have set
+// itself if it was set already when it came up
+fn needs_sysctl(ops: &[Op], key: &str) -> bool {
+    ops.iter().any(|op| match op {
+        Op::Sysctl { key: k, .. } => k == key,
+        Op::ForwardFrom { family, .. } => forwarding_key(*family) == key,
+        _ => false,
+    })
+}
+
+// search domains are applied without servers as well, e.g. pushed ones for a client
+// that resolves through DNS servers of its own
+fn dns_op(conf: &Conf) -> Option<Op> {
+    let servers = conf.network.dns().unwrap_or_default();
+    let domains = conf.network.dns_search();
+    if servers.is_empty() && domains.is_empty() {
+        return None;
+    }
+
+    Some(Op::Dns {
+        dev: tun_name(conf).to_owned(),
+        servers: servers.to_owned(),
+        domains: domains.to_vec(),
+    })
+}
+
+fn routes(conf: &Conf) -> BTreeSet<Cidr> {
+    match &conf.network {
+        Network::Server { client, pool, .. } => client
+            .iter()
+            .flat_map(|c| c.allowed_ips.values.iter().copied())
+            .chain(pool.iter().flat_map(Address::iter).map(Cidr::network))
+            .collect(),
+        Network::Client { server, .. } => server
+            .allowed_ips
+            .iter()
+            .flat_map(|allowed_ips| allowed_ips.values.iter().copied())
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn server(nat: &str) -> Conf {
+        let input = format!(
+            r#"
+[tls]
+key = "./key.pem"
+
+[network]
+role = "server"
+name = "tun0"
+address = "10.10.0.1/24"
+pool = "10.10.0.0/24, fd00::/64"
+client = []
+{nat}
+"#
+        );
+        Conf::parse_from(&input).unwrap()
+    }
+
+    #[test]
+    fn test_nat_ops() {
+        let forwarding = |key: &'static str| {
+            Some(Op::Sysctl {
+                key: key.to_owned(),
+                value: "1".to_owned(),
+                previous: "0".to_owned(),
+            })
+        };
+        let ops = |conf: &Conf| {
+            nat_ops(conf, forwarding)
+                .iter()
+                .map(Op::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(ops(&server("")).is_empty());
+        // the networks of `address` and `pool` are masqueraded out of any other interface
+        assert_eq!(
+            ops(&server("[network.nat]")),
+            [
+                "sysctl net.ipv4.ip_forward=1",
+                "-4 forward accept in tun0",
+                "-4 forward accept out tun0 established",
+                "sysctl net.ipv6.conf.all.forwarding=1",
+                "-6 forward accept in tun0",
+                "-6 forward accept out tun0 established",
+                "masquerade 10.10.0.0/24 out !tun0",
+                "masquerade fd00::/64 out !tun0",
+            ]
+        );
+        // only the families of `source` are forwarded, a forwarding setting already in
+        // place is left alone
+        let conf = server("[network.nat]\negress = \"eth0\"\nsource = \"10.20.0.0/16\"");
+        assert_eq!(
+            nat_ops(&conf, |_| None)
+                .iter()
+                .map(Op::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "-4 forward accept in tun0",
+                "-4 forward accept out tun0 established",
+                "masquerade 10.20.0.0/16 out eth0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_needs_sysctl() {
+        let ops = nat_ops(&server("[network.nat]\nsource = \"fd00::/64\""), |_| None);
+        assert!(needs_sysctl(&ops, "net.ipv6.conf.all.forwarding"));
+        assert!(!needs_sysctl(&ops, "net.ipv4.ip_forward"));
+        assert!(!needs_sysctl(&[], "net.ipv4.ip_forward"));
+    }
+}
@@ -0,0 +1,353 @@
+//! Applies [Op]s over rtnetlink.
+//!
+//! `rtnetlink` is asynchronous while the firewall is configured from synchronous code,
+//! some of which runs inside the main tokio runtime. Requests are therefore served by a
+//! dedicated thread running its own runtime.
+use std::io;
+use std::net::IpAddr;
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+
+use futures::TryStreamExt;
+use netlink_packet_route::nlas::address::Nla as AddressNla;
+use netlink_packet_route::nlas::route::Nla as RouteNla;
+use netlink_packet_route::nlas::rule::Nla as RuleNla;
+use netlink_packet_route::{
+    AddressMessage, RouteMessage, RuleMessage, AF_INET, AF_INET6, FIB_RULE_INVERT, FR_ACT_TO_TBL,
+    RTN_UNICAST, RTPROT_BOOT, RTPROT_UNSPEC, RT_SCOPE_LINK, RT_SCOPE_NOWHERE, RT_TABLE_MAIN,
+    RT_TABLE_UNSPEC,
+};
+use rtnetlink::Handle;
+use tokio::sync::mpsc;
+
+use super::{shell, Action, Family, Op};
+use crate::conf::Cidr;
+
+type Request = (Action, Op, std_mpsc::SyncSender<io::Result<()>>);
+
+pub struct Netlink {
+    requests: mpsc::UnboundedSender<Request>,
+}
+
+impl Netlink {
+    /// Opens a rtnetlink socket in the network namespace of the calling thread.
+    pub fn new() -> io::Result<Self> {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Request>();
+        let (ready_tx, ready_rx) = std_mpsc::sync_channel(1);
+
+        thread::Builder::new()
+            .name("vqn-netlink".into())
+            .spawn(move || {
+                let runtime = match tokio::runtime::Builder::new_current_thread()
+                    .enable_io()
+                    .build()
+                {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+
+                runtime.block_on(async move {
+                    let handle = match rtnetlink::new_connection() {
+                        Ok((conn, handle, _)) => {
+                            tokio::spawn(conn);
+                            let _ = ready_tx.send(Ok(()));
+                            handle
+                        }
+                        Err(e) => {
+                            let _ = ready_tx.send(Err(e));
+                            return;
+                        }
+                    };
+
+                    while let Some((action, op, reply)) = rx.recv().await {
+                        let _ = reply.send(execute(&handle, action, &op).await);
+                    }
+                });
+            })?;
+
+        ready_rx
+            .recv()
+            .map_err(|_| io::Error::other("netlink thread exited"))??;
+
+        Ok(Self { requests: tx })
+    }
+
+    pub fn apply(&self, action: Action, op: &Op) -> io::Result<()> {
+        // systemd-resolved is configured over D-Bus, which `resolvectl` takes care of, and
+        // netfilter by `iptables`
+        if let Op::Dns { .. }
+        | Op::Sysctl { .. }
+        | Op::ForwardFrom { .. }
+        | Op::ForwardReplies { .. }
+        | Op::Masquerade { .. } = op
+        {
+            return shell::apply(action, op);
+        }
+
+        let (tx, rx) = std_mpsc::sync_channel(1);
+        self.requests
+            .send((action, op.clone(), tx))
+            .map_err(|_| io::Error::other("netlink thread exited"))?;
+
+        rx.recv()
+            .map_err(|_| io::Error::other("netlink thread exited"))?
+    }
+}
+
+async fn execute(handle: &Handle, action: Action, op: &Op) -> io::Result<()> {
+    let result = match op {
+        Op::Address { dev, address } => {
+            let message = address_message(link_index(handle, dev).await?, address);
+            match action {
+                Action::Add => {
+                    let mut request = handle.address().add(0, address.ip(), address.1);
+                    *request.message_mut() = message;
+                    request.execute().await
+                }
+                Action::Delete => handle.address().del(message).execute().await,
+            }
+        }
+        Op::Route { dev, dst, table } => {
+            let message = route(action, link_index(handle, dev).await?, dst, *table);
+            match action {
+                Action::Add => {
+                    let mut request = handle.route().add();
+                    *request.message_mut() = message;
+                    request.execute().await
+                }
+                Action::Delete => handle.route().del(message).execute().await,
+            }
+        }
+        Op::FwmarkRule {
+            family,
+            fwmark,
+            table,
+            priority,
+        } => {
+            let message = fwmark_rule(*family, *fwmark, *table, *priority);
+            execute_rule(handle, action, message).await
+        }
+        Op::SuppressRule { family, priority } => {
+            execute_rule(handle, action, suppress_rule(*family, *priority)).await
+        }
+        Op::Dns { .. } => unreachable!("dns is configured via resolvectl"),
+        Op::Sysctl { .. }
+        | Op::ForwardFrom { .. }
+        | Op::ForwardReplies { .. }
+        | Op::Masquerade { .. } => unreachable!("netfilter is configured via iptables"),
+    };
+
+    result.map_err(into_io)
+}
+
+// the messages below are the ones `ip` sends for the commands of the shell backend
+
+// `ip addr add $address dev $dev`
+fn address_message(index: u32, address: &Cidr) -> AddressMessage {
+    let mut message = AddressMessage::default();
+    message.header.family = family(address.ip());
+    message.header.prefix_len = address.1;
+    message.header.index = index;
+    // without a peer, the address of the other end of the link is the local one
+    message.nlas.push(AddressNla::Local(octets(address.ip())));
+    message.nlas.push(AddressNla::Address(octets(address.ip())));
+
+    message
+}
+
+// `ip route add $dst dev $dev table $table`
+fn route(action: Action, index: u32, dst: &Cidr, table: u32) -> RouteMessage {
+    let mut message = RouteMessage::default();
+    message.header.address_family = family(dst.ip());
+    message.header.destination_prefix_length = dst.1;
+    message.header.kind = RTN_UNICAST;
+    // a route without a gateway reaches its destination on the link, and is deleted
+    // whatever scope and protocol it was added with
+    (message.header.scope, message.header.protocol) = match action {
+        Action::Add => (RT_SCOPE_LINK, RTPROT_BOOT),
+        Action::Delete => (RT_SCOPE_NOWHERE, RTPROT_UNSPEC),
+    };
+    message.header.table = table_header(table);
+    message.nlas.push(RouteNla::Table(table));
+    message.nlas.push(RouteNla::Destination(octets(dst.ip())));
+    message.nlas.push(RouteNla::Oif(index));
+
+    message
+}
+
+// `ip rule add not fwmark $fwmark table $table priority $priority`
+fn fwmark_rule(family: Family, fwmark: u32, table: u32, priority: u32) -> RuleMessage {
+    let mut message = rule(family, table, priority);
+    message.header.flags |= FIB_RULE_INVERT;
+    message.nlas.push(RuleNla::FwMark(fwmark));
+
+    message
+}
+
+// `ip rule add table main suppress_prefixlength 0 priority $priority`
+fn suppress_rule(family: Family, priority: u32) -> RuleMessage {
+    let mut message = rule(family, RT_TABLE_MAIN.into(), priority);
+    message.nlas.push(RuleNla::SuppressPrefixLen(0));
+
+    message
+}
+
+// a rule with an explicit priority, which also makes deleting it unambiguous
+fn rule(family: Family, table: u32, priority: u32) -> RuleMessage {
+    let mut message = RuleMessage::default();
+    message.header.family = match family {
+        Family::V4 => AF_INET as u8,
+        Family::V6 => AF_INET6 as u8,
+    };
+    message.header.action = FR_ACT_TO_TBL;
+    message.header.table = table_header(table);
+    message.nlas.push(RuleNla::Table(table));
+    message.nlas.push(RuleNla::Priority(priority));
+
+    message
+}
+
+// tables beyond the 8-bit header field are only carried by the attribute
+fn table_header(table: u32) -> u8 {
+    u8::try_from(table).unwrap_or(RT_TABLE_UNSPEC)
+}
+
+fn family(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => AF_INET as u8,
+        IpAddr::V6(_) => AF_INET6 as u8,
+    }
+}
+
+fn octets(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+async fn execute_rule(
+    handle: &Handle,
+    action: Action,
+    message: RuleMessage,
+) -> Result<(), rtnetlink::Error> {
+    match action {
+        Action::Add => {
+            let mut request = handle.rule().add();
+            *request.message_mut() = message;
+            request.execute().await
+        }
+        Action::Delete => handle.rule().del(message).execute().await,
+    }
+}
+
+async fn link_index(handle: &Handle, name: &str) -> io::Result<u32> {
+    handle
+        .link()
+        .get()
+        .match_name(name.to_owned())
+        .execute()
+        .try_next()
+        .await
+        .map_err(into_io)?
+        .map(|link| link.header.index)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no device {name}")))
+}
+
+fn into_io(e: rtnetlink::Error) -> io::Error {
+    match e {
+        rtnetlink::Error::NetlinkError(e) => e.to_io(),
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_address() {
+        // ip addr add 10.10.0.3/24 dev tun0
+        let message = address_message(7, &cidr("10.10.0.3/24"));
+        assert_eq!(message.header.family, AF_INET as u8);
+        assert_eq!(message.header.prefix_len, 24);
+        assert_eq!(message.header.index, 7);
+        assert_eq!(
+            message.nlas,
+            [
+                AddressNla::Local(vec![10, 10, 0, 3]),
+                AddressNla::Address(vec![10, 10, 0, 3])
+            ]
+        );
+
+        let message = address_message(7, &cidr("fd00::3/64"));
+        assert_eq!(message.header.family, AF_INET6 as u8);
+        assert_eq!(message.header.prefix_len, 64);
+    }
+
+    #[test]
+    fn test_route() {
+        // ip -4 route add 10.20.0.0/16 dev tun0 table 19988
+        let message = route(Action::Add, 7, &cidr("10.20.0.0/16"), 19988);
+        assert_eq!(message.header.address_family, AF_INET as u8);
+        assert_eq!(message.header.destination_prefix_length, 16);
+        assert_eq!(message.header.table, RT_TABLE_UNSPEC);
+        assert_eq!(message.header.scope, RT_SCOPE_LINK);
+        assert_eq!(message.header.protocol, RTPROT_BOOT);
+        assert_eq!(message.header.kind, RTN_UNICAST);
+        assert_eq!(
+            message.nlas,
+            [
+                RouteNla::Table(19988),
+                RouteNla::Destination(vec![10, 20, 0, 0]),
+                RouteNla::Oif(7)
+            ]
+        );
+
+        // ip -6 route delete fd20::/48 dev tun0 table 100
+        let message = route(Action::Delete, 7, &cidr("fd20::/48"), 100);
+        assert_eq!(message.header.address_family, AF_INET6 as u8);
+        assert_eq!(message.header.table, 100);
+        assert_eq!(message.header.scope, RT_SCOPE_NOWHERE);
+        assert_eq!(message.header.protocol, RTPROT_UNSPEC);
+    }
+
+    #[test]
+    fn test_rules() {
+        // ip -6 rule add not fwmark 19988 table 19988 priority 32764
+        let message = fwmark_rule(Family::V6, 19988, 19988, 32764);
+        assert_eq!(message.header.family, AF_INET6 as u8);
+        assert_eq!(message.header.flags, FIB_RULE_INVERT);
+        assert_eq!(message.header.action, FR_ACT_TO_TBL);
+        assert_eq!(message.header.table, RT_TABLE_UNSPEC);
+        assert_eq!(
+            message.nlas,
+            [
+                RuleNla::Table(19988),
+                RuleNla::Priority(32764),
+                RuleNla::FwMark(19988)
+            ]
+        );
+
+        // ip -4 rule add table main suppress_prefixlength 0 priority 32765
+        let message = suppress_rule(Family::V4, 32765);
+        assert_eq!(message.header.family, AF_INET as u8);
+        assert_eq!(message.header.flags, 0);
+        assert_eq!(message.header.table, RT_TABLE_MAIN);
+        assert_eq!(
+            message.nlas,
+            [
+                RuleNla::Table(RT_TABLE_MAIN.into()),
+                RuleNla::Priority(32765),
+                RuleNla::SuppressPrefixLen(0)
+            ]
+        );
+    }
+}
@@ -0,0 +1,125 @@
+//! Applies [Op]s by running `ip` from iproute2, and `iptables` for netfilter.
+use std::io;
+
+use cmd_lib::run_cmd;
+
+use super::{Action, Family, Op};
+
+pub fn apply(action: Action, op: &Op) -> io::Result<()> {
+    let cmd = match action {
+        Action::Add => "add",
+        Action::Delete => "delete",
+    };
+
+    match op {
+        Op::Address { dev, address } => {
+            let address = address.to_string();
+            run_cmd! {
+                ip addr $cmd $address dev $dev;
+            }
+        }
+        Op::Route { dev, dst, table } => {
+            let family = Family::from(dst.ip()).to_string();
+            let dst = dst.to_string();
+            run_cmd! {
+                ip $family route $cmd $dst dev $dev table $table;
+            }
+        }
+        Op::FwmarkRule {
+            family,
+            fwmark,
+            table,
+            priority,
+        } => {
+            let family = family.to_string();
+            run_cmd! {
+                ip $family rule $cmd not fwmark $fwmark table $table priority $priority;
+            }
+        }
+        Op::SuppressRule { family, priority } => {
+            let family = family.to_string();
+            run_cmd! {
+                ip $family rule $cmd table main suppress_prefixlength 0 priority $priority;
+            }
+        }
+        Op::Sysctl {
+            key,
+            value,
+            previous,
+        } => {
+            let value = match action {
+                Action::Add => value,
+                Action::Delete => previous,
+            };
+            run_cmd! {
+                sysctl -q -w $key=$value;
+            }
+        }
+        Op::ForwardFrom { family, dev } => {
+            let iptables = iptables(*family);
+            let cmd = iptables_cmd(action);
+            run_cmd! {
+                $iptables $cmd FORWARD -i $dev -j ACCEPT;
+            }
+        }
+        Op::ForwardReplies { family, dev } => {
+            let iptables = iptables(*family);
+            let cmd = iptables_cmd(action);
+            run_cmd! {
+                $iptables $cmd FORWARD -o $dev -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT;
+            }
+        }
+        Op::Masquerade { src, dev, egress } => {
+            let iptables = iptables(Family::from(src.ip()));
+            let cmd = iptables_cmd(action);
+            let src = src.to_string();
+            match egress {
+                Some(egress) => run_cmd! {
+                    $iptables -t nat $cmd POSTROUTING -s $src -o $egress -j MASQUERADE;
+                },
+                None => run_cmd! {
+                    $iptables -t nat $cmd POSTROUTING -s $src ! -o $dev -j MASQUERADE;
+                },
+            }
+        }
+        Op::Dns {
+            dev,
+            servers,
+            domains,
+        } => match action {
+            Action::Add => {
+                let servers: Vec<_> = servers
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|s| !s.is_empty())
+                    .collect();
+                if !servers.is_empty() {
+                    run_cmd!(resolvectl dns $dev $[servers])?;
+                }
+                if domains.is_empty() {
+                    return Ok(());
+                }
+                run_cmd! {
+                    resolvectl domain $dev $[domains];
+                }
+            }
+            Action::Delete => run_cmd! {
+                resolvectl revert $dev;
+            },
+        },
+    }
+}
+
+fn iptables(family: Family) -> &'static str {
+    match family {
+        Family::V4 => "iptables",
+        Family::V6 => "ip6tables",
+    }
+}
+
+// rules are inserted ahead of the ones already there, e.g. a DROP policy's exceptions
+fn iptables_cmd(action: Action) -> &'static str {
+    match action {
+        Action::Add => "-I",
+        Action::Delete => "-D",
+    }
+}
@@ -0,0 +1,189 @@
+//! Record of the [Op]s applied for a tun interface.
+//!
+//! The record is kept in a file under `/run`, so the ops can still be reverted after the
+//! process that applied them crashed.
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Action, Op};
+
+const STATE_DIR: &str = "/run/vqn";
+
+// tests keep their records out of the system's
+fn state_dir() -> PathBuf {
+    if cfg!(test) {
+        std::env::temp_dir().join(format!("vqn-test-{}-state", std::process::id()))
+    } else {
+        PathBuf::from(STATE_DIR)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct State {
+    /// Process that applied the ops.
+    pub pid: u32,
+    pub ops: Vec<Op>,
+}
+
+impl State {
+    pub fn path(tun: &str) -> PathBuf {
+        state_dir().join(format!("{tun}.state"))
+    }
+
+    pub fn load(tun: &str) -> io::Result<Option<Self>> {
+        match fs::read(Self::path(tun)) {
+            Ok(state) => serde_json::from_slice(&state).map(Some).map_err(Into::into),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&self, tun: &str) -> io::Result<()> {
+        fs::create_dir_all(state_dir())?;
+
+        // replace the file atomically, so a crash never leaves a truncated record behind
+        let path = Self::path(tun);
+        let tmp = path.with_extension("state.tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(tmp, path)
+    }
+
+    pub fn remove(tun: &str) -> io::Result<()> {
+        match fs::remove_file(Self::path(tun)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds the `steps` applied by this process to the record of `tun`.
+    pub fn record(tun: &str, steps: &[(Action, Op)]) -> io::Result<()> {
+        let mut state = Self::load(tun)?.unwrap_or_else(|| State {
+            pid: std::process::id(),
+            ops: vec![],
+        });
+        for (action, op) in steps {
+            match action {
+                Action::Add => state.ops.push(op.clone()),
+                Action::Delete => {
+                    if let Some(i) = state.ops.iter().rposition(|applied| applied == op) {
+                        state.ops.remove(i);
+                    }
+                }
+            }
+        }
+
+        state.save(tun)
+    }
+
+    /// Returns the records of the tun interfaces other than `tun` whose ops are still
+    /// applied by a running vqn, this one included.
+    pub fn others(tun: &str) -> Vec<State> {
+        let Ok(entries) = fs::read_dir(state_dir()) else {
+            return vec![];
+        };
+
+        entries
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name();
+                let other = name.to_str()?.strip_suffix(".state")?;
+                (other != tun).then(|| Self::load(other).ok().flatten())?
+            })
+            .filter(|state| state.pid == std::process::id() || state.in_use())
+            .collect()
+    }
+
+    /// Returns `true` if the process that applied the ops is another vqn that is still
+    /// running.
+    pub fn in_use(&self) -> bool {
+        if self.pid == std::process::id() {
+            return false;
+        }
+
+        // the pid may have been reused by an unrelated process since
+        let comm = |pid: &str| fs::read_to_string(format!("/proc/{pid}/comm"));
+        match (comm(&self.pid.to_string()), comm("self")) {
+            (Ok(theirs), Ok(ours)) => theirs == ours,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+
+    use super::*;
+    use crate::firewall::Family;
+
+    fn rule(priority: u32) -> Op {
+        Op::SuppressRule {
+            family: Family::V4,
+            priority,
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let tun = "vqn-record";
+        assert!(State::load(tun).unwrap().is_none());
+
+        State::record(tun, &[(Action::Add, rule(1)), (Action::Add, rule(2))]).unwrap();
+        State::record(tun, &[(Action::Delete, rule(1)), (Action::Add, rule(3))]).unwrap();
+        let state = State::load(tun).unwrap().unwrap();
+        assert_eq!(state.pid, std::process::id());
+        assert_eq!(state.ops, [rule(2), rule(3)]);
+
+        State::remove(tun).unwrap();
+        assert!(State::load(tun).unwrap().is_none());
+        State::remove(tun).unwrap();
+    }
+
+    #[test]
+    fn test_others() {
+        let exited = Command::new("true").spawn().unwrap();
+        let pid = exited.id();
+        exited.wait_with_output().unwrap();
+
+        State::record("vqn-others0", &[(Action::Add, rule(1))]).unwrap();
+        State::record("vqn-others1", &[(Action::Add, rule(2))]).unwrap();
+        let stale = State {
+            pid,
+            ops: vec![rule(3)],
+        };
+        stale.save("vqn-others2").unwrap();
+
+        // records of this process count, but not the one of `tun` itself or of a vqn
+        // that is gone
+        let others: Vec<_> = State::others("vqn-others0")
+            .into_iter()
+            .flat_map(|state| state.ops)
+            .collect();
+        assert!(others.contains(&rule(2)));
+        assert!(!others.contains(&rule(1)));
+        assert!(!others.contains(&rule(3)));
+
+        for tun in ["vqn-others0", "vqn-others1", "vqn-others2"] {
+            State::remove(tun).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_in_use() {
+        let state = |pid| State { pid, ops: vec![] };
+        assert!(!state(std::process::id()).in_use());
+
+        let exited = Command::new("true").spawn().unwrap();
+        let pid = exited.id();
+        exited.wait_with_output().unwrap();
+        assert!(!state(pid).in_use());
+
+        // a reused pid of an unrelated process
+        let mut other = Command::new("sleep").arg("10").spawn().unwrap();
+        assert!(!state(other.id()).in_use());
+        other.kill().unwrap();
+        other.wait().unwrap();
+    }
+}
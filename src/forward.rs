@@ -0,0 +1,618 @@
+//! Relays ports of a server to services of its clients, as configured by the `forward`s
+//! of `[[network.client]]`.
+//!
+//! The server accepts connections and datagrams on the public address of a forward
+//! itself and relays them from sockets of its own to the target, which is routed through
+//! the tunnel like any other traffic of the server. The client sees relayed traffic come
+//! from the server's tunnel address. Nothing is relayed while the client is disconnected,
+//! or while the target is routed to another client.
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+use tokio::task::JoinSet;
+use tokio_util::task::AbortOnDropHandle;
+use tracing::Instrument;
+
+use crate::core;
+
+/// A client that does not answer would otherwise hold the connection for minutes.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a UDP session is kept without any traffic from the client.
+const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Delay after failing to accept a connection, e.g. when out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Open TCP connections per forward, beyond which new ones are refused.
+const MAX_CONNECTIONS: usize = 512;
+
+/// UDP sessions per forward, beyond which datagrams from new addresses are dropped.
+const MAX_SESSIONS: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Proto {
+    Tcp,
+    Udp,
+}
+
+/// A port of the server relayed to a service of a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Forward {
+    pub proto: Proto,
+    pub public: SocketAddr,
+    pub target: SocketAddr,
+}
+
+impl fmt::Display for Forward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let proto = match self.proto {
+            Proto::Tcp => "tcp",
+            Proto::Udp => "udp",
+        };
+        write!(f, "{proto} {} -> {}", self.public, self.target)
+    }
+}
+
+/// A relayed port with the traffic it has relayed so far.
+#[derive(Debug, Clone)]
+pub struct ForwardStatus {
+    pub peer: core::Identity,
+    pub forward: Forward,
+    /// Open TCP connections or UDP sessions.
+    pub active: u64,
+    pub total: u64,
+    /// Bytes relayed in either direction, counted once a connection closes for TCP.
+    pub bytes: u64,
+}
+
+/// The relays of a server's forwarded ports.
+pub struct Forwards {
+    router: Arc<core::Router>,
+    relays: Mutex<HashMap<Forward, Relay>>,
+}
+
+struct Relay {
+    // shared with the relay's task, which only relays to the target while it is routed
+    // to this peer
+    peer: Arc<Mutex<core::Identity>>,
+    stats: Arc<Stats>,
+    task: AbortOnDropHandle<()>,
+}
+
+impl Relay {
+    // stops relaying, returning once the port is closed
+    async fn stop(self) {
+        self.task.abort();
+        let _ = self.task.await;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    active: AtomicU64,
+    total: AtomicU64,
+    bytes: AtomicU64,
+}
+
+// counts a connection or session as active for as long as it is kept
+struct Active(Arc<Stats>);
+
+impl Active {
+    fn new(stats: &Arc<Stats>) -> Self {
+        stats.active.fetch_add(1, Ordering::Relaxed);
+        stats.total.fetch_add(1, Ordering::Relaxed);
+        Active(Arc::clone(stats))
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Forwards {
+    pub fn new(router: Arc<core::Router>) -> Self {
+        Forwards {
+            router,
+            relays: Mutex::default(),
+        }
+    }
+
+    /// Relays the ports of `forwards` to the given peers, starting the relays that are
+    /// missing and stopping the ones left out. Relays that are kept keep their
+    /// connections.
+    ///
+    /// Ports that cannot be bound are reported once all others are relayed.
+    pub async fn update(&self, forwards: Vec<(core::Identity, Forward)>) -> anyhow::Result<()> {
+        // the relays left out release their ports first, which a forward whose target
+        // changed binds again
+        let stopped: Vec<_> = {
+            let mut relays = self.relays.lock().unwrap();
+            let (kept, stopped): (HashMap<_, _>, HashMap<_, _>) = relays
+                .drain()
+                .partition(|(forward, _)| forwards.iter().any(|(_, f)| f == forward));
+            *relays = kept;
+            stopped.into_values().collect()
+        };
+        futures::future::join_all(stopped.into_iter().map(Relay::stop)).await;
+
+        let mut relays = self.relays.lock().unwrap();
+        let mut errors = vec![];
+        for (peer, forward) in forwards {
+            if let Some(relay) = relays.get_mut(&forward) {
+                *relay.peer.lock().unwrap() = peer;
+                continue;
+            }
+            tracing::info!("forwarding {forward} to {peer}");
+            let peer = Arc::new(Mutex::new(peer));
+            match self.start(forward, Arc::clone(&peer)) {
+                Ok((stats, task)) => {
+                    relays.insert(forward, Relay { peer, stats, task });
+                }
+                Err(e) => errors.push(format!("{e:#}")),
+            }
+        }
+        anyhow::ensure!(errors.is_empty(), "{}", errors.join(", "));
+
+        Ok(())
+    }
+
+    /// Stops all relays and closes their connections, returning once their ports are
+    /// released.
+    pub async fn clear(&self) {
+        let relays: Vec<_> = {
+            let mut relays = self.relays.lock().unwrap();
+            relays.drain().map(|(_, relay)| relay).collect()
+        };
+        futures::future::join_all(relays.into_iter().map(Relay::stop)).await;
+    }
+
+    pub fn status(&self) -> Vec<ForwardStatus> {
+        self.relays
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(forward, relay)| ForwardStatus {
+                peer: relay.peer.lock().unwrap().clone(),
+                forward: *forward,
+                active: relay.stats.active.load(Ordering::Relaxed),
+                total: relay.stats.total.load(Ordering::Relaxed),
+                bytes: relay.stats.bytes.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    // binds the public port of `forward` and relays it to `peer` in a task of its own
+    fn start(
+        &self,
+        forward: Forward,
+        peer: Arc<Mutex<core::Identity>>,
+    ) -> anyhow::Result<(Arc<Stats>, AbortOnDropHandle<()>)> {
+        let stats = Arc::new(Stats::default());
+        let target = Target {
+            addr: forward.target,
+            peer,
+            router: Arc::clone(&self.router),
+        };
+        let span = tracing::info_span!("forward", public = %forward.public);
+        let context = || format!("failed to listen at {forward}");
+
+        let task = match forward.proto {
+            Proto::Tcp => {
+                let listener = listen(forward.public).with_context(context)?;
+                tokio::spawn(relay_tcp(listener, target, Arc::clone(&stats)).instrument(span))
+            }
+            Proto::Udp => {
+                let socket = std::net::UdpSocket::bind(forward.public).with_context(context)?;
+                socket.set_nonblocking(true).with_context(context)?;
+                let socket = UdpSocket::from_std(socket).with_context(context)?;
+                tokio::spawn(relay_udp(socket, target, Arc::clone(&stats)).instrument(span))
+            }
+        };
+
+        Ok((stats, AbortOnDropHandle::new(task)))
+    }
+}
+
+// like `TcpListener::bind`, which reuses addresses so that a restart need not wait for
+// the connections of the previous run to time out, but without awaiting
+fn listen(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+// the target of a forward and the peer it is relayed to
+#[derive(Clone)]
+struct Target {
+    addr: SocketAddr,
+    peer: Arc<Mutex<core::Identity>>,
+    router: Arc<core::Router>,
+}
+
+impl Target {
+    // whether the target is routed to the connection of the forward's peer, rather than
+    // to nowhere or to another peer that was given its address
+    async fn connected(&self) -> bool {
+        let peer = self.peer.lock().unwrap().clone();
+        self.router.routes_to(self.addr.ip(), &peer).await
+    }
+}
+
+async fn relay_tcp(listener: TcpListener, target: Target, stats: Arc<Stats>) {
+    let mut connections = JoinSet::new();
+    loop {
+        let (mut inbound, remote) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("failed to accept a connection: {e}");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        while connections.try_join_next().is_some() {}
+        if connections.len() >= MAX_CONNECTIONS {
+            tracing::debug!("refused a connection from {remote}, too many connections");
+            continue;
+        }
+
+        let target = target.clone();
+        let stats = Arc::clone(&stats);
+        connections.spawn(
+            async move {
+                if !target.connected().await {
+                    tracing::debug!(
+                        "refused a connection from {remote}, {} is not connected",
+                        target.addr
+                    );
+                    return;
+                }
+                let target = target.addr;
+                let mut outbound =
+                    match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(target)).await {
+                        Ok(Ok(outbound)) => outbound,
+                        Ok(Err(e)) => {
+                            tracing::debug!("failed to connect to {target}: {e}");
+                            return;
+                        }
+                        Err(_) => {
+                            tracing::debug!("timed out connecting to {target}");
+                            return;
+                        }
+                    };
+
+                let _active = Active::new(&stats);
+                tracing::debug!("relaying a connection from {remote} to {target}");
+                match tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await {
+                    Ok((sent, received)) => {
+                        stats.bytes.fetch_add(sent + received, Ordering::Relaxed);
+                    }
+                    Err(e) => tracing::debug!("connection from {remote} failed: {e}"),
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+// every remote address gets a session with a socket of its own connected to the target,
+// whose replies are sent back to that address
+async fn relay_udp(socket: UdpSocket, target: Target, stats: Arc<Stats>) {
+    let mut sessions: HashMap<SocketAddr, Arc<UdpSocket>> = HashMap::new();
+    // replies are relayed within this task, so `socket` is closed as soon as it stops
+    let mut replies = FuturesUnordered::new();
+    let mut buf = vec![0; u16::MAX as usize];
+
+    loop {
+        let (len, remote) = tokio::select! {
+            received = socket.recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                Err(e) => {
+                    tracing::debug!("failed to receive a datagram: {e}");
+                    continue;
+                }
+            },
+            Some(remote) = replies.next() => {
+                sessions.remove(&remote);
+                continue;
+            }
+        };
+
+        let upstream = match sessions.get(&remote) {
+            Some(upstream) => Arc::clone(upstream),
+            None => {
+                if sessions.len() >= MAX_SESSIONS {
+                    tracing::debug!("dropped a datagram from {remote}, too many sessions");
+                    continue;
+                }
+                if !target.connected().await {
+                    tracing::debug!(
+                        "dropped a datagram from {remote}, {} is not connected",
+                        target.addr
+                    );
+                    continue;
+                }
+                let upstream = match udp_session(target.addr).await {
+                    Ok(upstream) => Arc::new(upstream),
+                    Err(e) => {
+                        tracing::debug!("failed to relay a datagram to {}: {e}", target.addr);
+                        continue;
+                    }
+                };
+                tracing::debug!("relaying datagrams from {remote} to {}", target.addr);
+                replies.push(relay_replies(
+                    Arc::clone(&upstream),
+                    &socket,
+                    remote,
+                    Active::new(&stats),
+                ));
+                sessions.insert(remote, Arc::clone(&upstream));
+                upstream
+            }
+        };
+
+        if let Ok(sent) = upstream.send(&buf[..len]).await {
+            stats.bytes.fetch_add(sent as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+async fn udp_session(target: SocketAddr) -> std::io::Result<UdpSocket> {
+    let unspecified = match target.ip() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let upstream = UdpSocket::bind((unspecified, 0)).await?;
+    upstream.connect(target).await?;
+
+    Ok(upstream)
+}
+
+// sends the target's replies back to `remote` until the session times out, returning
+// `remote`
+async fn relay_replies(
+    upstream: Arc<UdpSocket>,
+    socket: &UdpSocket,
+    remote: SocketAddr,
+    active: Active,
+) -> SocketAddr {
+    let mut buf = vec![0; u16::MAX as usize];
+    while let Ok(Ok(len)) = tokio::time::timeout(UDP_SESSION_TIMEOUT, upstream.recv(&mut buf)).await
+    {
+        if let Ok(sent) = socket.send_to(&buf[..len], remote).await {
+            active.0.bytes.fetch_add(sent as u64, Ordering::Relaxed);
+        }
+    }
+
+    remote
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::core::Router;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    // a loopback port nothing listens on
+    fn free_port(proto: Proto) -> SocketAddr {
+        match proto {
+            Proto::Tcp => std::net::TcpListener::bind((LOCALHOST, 0))
+                .unwrap()
+                .local_addr()
+                .unwrap(),
+            Proto::Udp => std::net::UdpSocket::bind((LOCALHOST, 0))
+                .unwrap()
+                .local_addr()
+                .unwrap(),
+        }
+    }
+
+    // forwards a port to `target`, which is routed to a connected peer, returning the
+    // peer's identity with what keeps it connected
+    async fn forwards(target: SocketAddr) -> (Forwards, core::Identity, impl Sized) {
+        let router = Arc::new(Router::default());
+        let mut peers = core::connect_peers(&router, &[(target.ip(), 32)]).await;
+        let (identity, client, server) = peers.remove(0);
+
+        (Forwards::new(router), identity, (client, server))
+    }
+
+    // answers every connection or datagram with `reply`
+    async fn answer(proto: Proto, reply: &'static [u8]) -> (SocketAddr, AbortOnDropHandle<()>) {
+        match proto {
+            Proto::Tcp => {
+                let listener = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                let task = tokio::spawn(async move {
+                    loop {
+                        let (mut stream, _) = listener.accept().await.unwrap();
+                        let _ = stream.write_all(reply).await;
+                    }
+                });
+                (addr, AbortOnDropHandle::new(task))
+            }
+            Proto::Udp => {
+                let socket = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+                let addr = socket.local_addr().unwrap();
+                let task = tokio::spawn(async move {
+                    let mut buf = [0; 1500];
+                    loop {
+                        let (_, remote) = socket.recv_from(&mut buf).await.unwrap();
+                        socket.send_to(reply, remote).await.unwrap();
+                    }
+                });
+                (addr, AbortOnDropHandle::new(task))
+            }
+        }
+    }
+
+    // what a forwarded port answers
+    async fn request(proto: Proto, public: SocketAddr) -> Vec<u8> {
+        let mut reply = vec![];
+        let received = async {
+            match proto {
+                Proto::Tcp => {
+                    let mut stream = TcpStream::connect(public).await.unwrap();
+                    stream.read_to_end(&mut reply).await.unwrap();
+                }
+                Proto::Udp => {
+                    let socket = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+                    socket.connect(public).await.unwrap();
+                    socket.send(b"ping").await.unwrap();
+                    reply.resize(1500, 0);
+                    let len = socket.recv(&mut reply).await.unwrap();
+                    reply.truncate(len);
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), received)
+            .await
+            .unwrap();
+
+        reply
+    }
+
+    #[tokio::test]
+    async fn test_retarget() {
+        for proto in [Proto::Tcp, Proto::Udp] {
+            let (first, _first) = answer(proto, b"first").await;
+            let (second, _second) = answer(proto, b"second").await;
+            // both targets are on loopback, so the peer is routed to both
+            let (forwards, peer, _connected) = forwards(first).await;
+            let public = free_port(proto);
+
+            let forward = Forward {
+                proto,
+                public,
+                target: first,
+            };
+            forwards
+                .update(vec![(peer.clone(), forward)])
+                .await
+                .unwrap();
+            assert_eq!(request(proto, public).await, b"first");
+
+            // the port is bound again for the new target
+            let forward = Forward {
+                proto,
+                public,
+                target: second,
+            };
+            forwards.update(vec![(peer, forward)]).await.unwrap();
+            assert_eq!(request(proto, public).await, b"second");
+            assert_eq!(forwards.status().len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp() {
+        let echo = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+        let target = echo.local_addr().unwrap();
+        let _echo = AbortOnDropHandle::new(tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = echo.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut read, mut write) = stream.split();
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                });
+            }
+        }));
+
+        let (forwards, peer, _connected) = forwards(target).await;
+        let forward = Forward {
+            proto: Proto::Tcp,
+            public: free_port(Proto::Tcp),
+            target,
+        };
+        forwards.update(vec![(peer, forward)]).await.unwrap();
+
+        let mut stream = TcpStream::connect(forward.public).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ping");
+        drop(stream);
+
+        // the connection ends once the relay sees both sides closed
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while forwards.status()[0].active > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let status = &forwards.status()[0];
+        assert_eq!((status.active, status.total, status.bytes), (0, 1, 8));
+
+        // nothing is relayed to a target routed to another peer than the forward's
+        let other = core::Identity::CommonName("mallory".to_owned());
+        forwards.update(vec![(other, forward)]).await.unwrap();
+        let mut stream = TcpStream::connect(forward.public).await.unwrap();
+        let _ = stream.write_all(b"ping").await;
+        assert_eq!(stream.read(&mut reply).await.unwrap_or(0), 0);
+    }
+
+    #[tokio::test]
+    async fn test_udp() {
+        let echo = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+        let target = echo.local_addr().unwrap();
+        let _echo = AbortOnDropHandle::new(tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            loop {
+                let (len, remote) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..len], remote).await.unwrap();
+            }
+        }));
+
+        let (forwards, peer, _connected) = forwards(target).await;
+        let forward = Forward {
+            proto: Proto::Udp,
+            public: free_port(Proto::Udp),
+            target,
+        };
+        forwards.update(vec![(peer, forward)]).await.unwrap();
+
+        let socket = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+        socket.connect(forward.public).await.unwrap();
+        let mut reply = [0; 4];
+        for _ in 0..2 {
+            socket.send(b"ping").await.unwrap();
+            let len = tokio::time::timeout(Duration::from_secs(1), socket.recv(&mut reply))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&reply[..len], b"ping");
+        }
+        // both datagrams are relayed in one session
+        let status = &forwards.status()[0];
+        assert_eq!((status.active, status.total, status.bytes), (1, 1, 16));
+
+        // nothing is relayed to a target routed to another peer than the forward's
+        let other = core::Identity::CommonName("mallory".to_owned());
+        forwards.update(vec![(other, forward)]).await.unwrap();
+        let socket = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+        socket.connect(forward.public).await.unwrap();
+        socket.send(b"ping").await.unwrap();
+        let received =
+            tokio::time::timeout(Duration::from_millis(200), socket.recv(&mut reply)).await;
+        assert!(received.is_err());
+    }
+}
@@ -1,43 +1,214 @@
 use std::{
-    net::{SocketAddr, ToSocketAddrs},
+    future::Future,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     path::{Path, PathBuf},
-    sync::Arc,
-    time::Duration,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, Context};
-use clap::Parser;
+use anyhow::Context;
+use clap::{Parser, Subcommand};
 use nix::sched::{setns, CloneFlags};
 use quinn::{MtuDiscoveryConfig, TransportConfig};
 use tokio::signal::unix::{signal, SignalKind};
-use tokio::sync::oneshot::{self, Sender};
-use tracing::Level;
+use tokio::sync::{oneshot, watch};
+use tokio_util::task::AbortOnDropHandle;
+use tracing::{Instrument, Level};
 use tun::Device;
+use url::Url;
 
 use core::Iface;
 
+mod backoff;
 mod conf;
+mod connect;
+mod control;
 mod core;
+mod expiry;
 mod firewall;
+mod forward;
+mod keys;
+mod pin;
+mod pki;
+mod supervisor;
+mod tls;
 
-use conf::{ClientPeer, Conf, Network, ServerPeer};
+use backoff::Backoff;
+use conf::{Cidr, ClientPeer, Conf, Network, ServerPeer, DEFAULT_TUN_NAME};
+use control::{ControlSocket, PeerRequest, Request, Response};
+use supervisor::Supervisor;
 
 #[derive(Debug, Parser)]
 #[clap(name = "vqn", version)]
 pub struct Args {
-    #[arg(long)]
-    config: PathBuf,
+    /// Configuration file, or a directory with one configuration file per tunnel
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
 
     #[arg(long)]
     log_level: Option<Level>,
 
-    #[arg(long)]
+    #[arg(long, global = true)]
     netns: Option<String>,
+
+    /// How routes and rules are installed
+    #[arg(long, value_enum, default_value_t, global = true)]
+    firewall: firewall::BackendKind,
+
+    /// Path of the control socket [default: /run/vqn/<interface or directory name>.sock]
+    #[arg(long, global = true)]
+    control: Option<PathBuf>,
+
+    /// Interface of the tunnel a command is about, if vqn runs more than one
+    #[arg(long, short, global = true)]
+    interface: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Show the peers of a running vqn
+    Show,
+
+    /// Print peer connection events of a running server as they happen
+    Events,
+
+    /// Remove routes and rules left behind by a vqn that did not exit cleanly
+    Cleanup,
+
+    /// Restart a tunnel of a running vqn, re-reading its configuration file
+    Restart,
+
+    /// Manage the clients of a running server
+    Peer {
+        #[command(subcommand)]
+        command: PeerCommand,
+    },
+
+    /// Create the certificates and keys of servers and clients
+    Pki {
+        /// Directory of the certificate authority and everything it issues
+        #[arg(long, global = true, default_value = ".")]
+        dir: PathBuf,
+
+        #[command(subcommand)]
+        command: PkiCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum PeerCommand {
+    /// Add a client
+    Add {
+        #[command(flatten)]
+        peer: PeerArgs,
+
+        /// Addresses the client may use besides the ones assigned out of the pool
+        #[arg(long, default_value = "")]
+        allowed_ips: conf::AllowedIps,
+    },
+
+    /// Remove a client and close its connection
+    Remove {
+        #[command(flatten)]
+        peer: PeerArgs,
+    },
+
+    /// Close a client's connection, the client may reconnect
+    Kick {
+        #[command(flatten)]
+        peer: PeerArgs,
+
+        /// Reason sent to the client
+        #[arg(long)]
+        reason: Option<String>,
+    },
+}
+
+/// A client, given by its certificate or by an identity its certificate carries
+#[derive(Debug, clap::Args)]
+#[group(required = true, multiple = false)]
+struct PeerArgs {
+    /// Client certificate chain
+    #[arg(long)]
+    cert: Option<PathBuf>,
+
+    /// spki-sha256:<hex>, cn:<name> or san:<name> of the client certificate
+    #[arg(long)]
+    identity: Option<core::Identity>,
+}
+
+#[derive(Debug, Subcommand)]
+enum PkiCommand {
+    /// Create a certificate authority, ca-cert.pem and ca-key.pem
+    Init {
+        /// Common name of the CA certificate
+        #[arg(long, default_value = "vqn CA")]
+        name: String,
+
+        /// Days the certificate is valid for
+        #[arg(long, default_value_t = pki::DEFAULT_CA_DAYS)]
+        days: u32,
+    },
+
+    /// Issue server-cert.pem and server-key.pem
+    IssueServer {
+        /// DNS name clients connect to, may be repeated
+        #[arg(long)]
+        dns: Vec<String>,
+
+        /// IP address clients connect to, may be repeated
+        #[arg(long)]
+        ip: Vec<IpAddr>,
+
+        /// Days the certificate is valid for
+        #[arg(long, default_value_t = pki::DEFAULT_CERT_DAYS)]
+        days: u32,
+    },
+
+    /// Issue <name>-cert.pem and <name>-key.pem. With the server's --config, also add the
+    /// client to it with the next free address and write <name>.toml for the client
+    IssueClient {
+        #[arg(long)]
+        name: String,
+
+        /// Server url for <name>.toml [default: the name in server-cert.pem]
+        #[arg(long)]
+        url: Option<Url>,
+
+        /// Days the certificate is valid for
+        #[arg(long, default_value_t = pki::DEFAULT_CERT_DAYS)]
+        days: u32,
+    },
+
+    /// Generate <name>-key.pem for a tunnel without a CA and print its fingerprint
+    Genkey {
+        #[arg(long)]
+        name: String,
+    },
+
+    /// Print the key fingerprint of a certificate or private key file
+    Fingerprint { file: PathBuf },
+
+    /// Add <name>-cert.pem to the certificate revocation list crl.pem
+    Revoke {
+        #[arg(long)]
+        name: String,
+    },
 }
 
 const DEFAULT_LISTEN_PORT: u16 = 10086;
 const DEFAULT_MTU: usize = 1434;
-const DEFAULT_TUN_NAME: &str = "tun0";
+const RECONNECT_BASE_DELAY: Duration = Duration::from_millis(500);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+const STABLE_CONNECTION: Duration = Duration::from_secs(30);
+const BIND_RETRIES: usize = 10;
+const BIND_RETRY_DELAY: Duration = Duration::from_millis(100);
+const CRL_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const SESSION_TIMEOUT: Duration = Duration::from_secs(10);
+const LEASE_DIR: &str = "/var/lib/vqn";
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
@@ -48,10 +219,24 @@ fn main() -> anyhow::Result<()> {
     )
     .unwrap();
 
-    let conf = Conf::read(&args.config)?;
+    if let Some(command) = &args.command {
+        return run_command(command, &args);
+    }
+
+    let config_path = args.config.as_deref().context("--config is required")?;
+    let tunnels = Conf::read_all(config_path)?;
+    let control_path = control_path(&args)?;
+
+    // threads inherit the network namespace they are created in, so it is entered
+    // before the runtime starts its worker threads
+    if let Some(netns) = &args.netns {
+        enter_netns(netns)?;
+    }
+    firewall::init(args.firewall);
+
     let code = {
-        if let Err(e) = run(conf, args.netns.as_deref()) {
-            eprintln!("{e}");
+        if let Err(e) = run(tunnels, &control_path) {
+            eprintln!("{e:#}");
             1
         } else {
             0
@@ -61,130 +246,550 @@ fn main() -> anyhow::Result<()> {
     std::process::exit(code);
 }
 
+#[tokio::main(flavor = "current_thread")]
+async fn run_command(command: &Command, args: &Args) -> anyhow::Result<()> {
+    let control_path = control_path(args)?;
+    let interface = args.interface.as_deref();
+
+    let request = match command {
+        Command::Show => Request::Show,
+        Command::Cleanup => return cleanup(args),
+        Command::Pki { dir, command } => return pki(dir, command, args),
+        Command::Restart => Request::Restart,
+        Command::Events => {
+            return control::events(&control_path, interface, |event| println!("{event}")).await;
+        }
+        Command::Peer { command } => Request::Peer(match command {
+            PeerCommand::Add { peer, allowed_ips } => PeerRequest::Add {
+                cert: peer.cert.as_deref().map(absolute).transpose()?,
+                identity: peer.identity.clone(),
+                allowed_ips: allowed_ips.clone(),
+            },
+            PeerCommand::Remove { peer } => PeerRequest::Remove {
+                cert: peer.cert.as_deref().map(absolute).transpose()?,
+                identity: peer.identity.clone(),
+            },
+            PeerCommand::Kick { peer, reason } => PeerRequest::Kick {
+                cert: peer.cert.as_deref().map(absolute).transpose()?,
+                identity: peer.identity.clone(),
+                reason: reason.clone(),
+            },
+        }),
+    };
+
+    match control::request(&control_path, interface, request).await? {
+        Response::Ok => (),
+        Response::Tunnels(tunnels) => control::print_tunnels(&tunnels),
+        Response::Event(event) => println!("{event}"),
+        Response::Error(e) => anyhow::bail!(e),
+    }
+
+    Ok(())
+}
+
+fn cleanup(args: &Args) -> anyhow::Result<()> {
+    if let Some(netns) = &args.netns {
+        enter_netns(netns)?;
+    }
+    firewall::init(args.firewall);
+
+    for name in tun_names(args)? {
+        if firewall::cleanup(&name)? {
+            println!("removed the firewall configuration of {name}");
+        } else {
+            println!("nothing to clean up for {name}");
+        }
+    }
+
+    Ok(())
+}
+
+fn pki(dir: &Path, command: &PkiCommand, args: &Args) -> anyhow::Result<()> {
+    match command {
+        PkiCommand::Init { name, days } => pki::init(dir, name, *days),
+        PkiCommand::IssueServer { dns, ip, days } => pki::issue_server(dir, dns, ip, *days),
+        PkiCommand::IssueClient { name, url, days } => {
+            let server_config = match &args.config {
+                Some(path) => Some(tunnel_config(path, args.interface.as_deref())?),
+                None => None,
+            };
+            pki::issue_client(dir, name, *days, server_config.as_deref(), url.as_ref())
+        }
+        PkiCommand::Genkey { name } => {
+            println!("{}", pki::genkey(dir, name)?);
+            Ok(())
+        }
+        PkiCommand::Fingerprint { file } => {
+            println!("{}", pki::fingerprint(file)?);
+            Ok(())
+        }
+        PkiCommand::Revoke { name } => pki::revoke(dir, name),
+    }
+}
+
+// the configuration file of the tunnel on `interface`, if `path` is a directory
+fn tunnel_config(path: &Path, interface: Option<&str>) -> anyhow::Result<PathBuf> {
+    if !path.is_dir() {
+        return Ok(path.to_path_buf());
+    }
+
+    let mut tunnels = Conf::read_all(path)?;
+    match interface {
+        Some(interface) => tunnels
+            .into_iter()
+            .find(|(_, conf)| conf.network.name().unwrap_or(DEFAULT_TUN_NAME) == interface)
+            .map(|(path, _)| path)
+            .with_context(|| format!("no tunnel on {interface} in {}", path.display())),
+        None if tunnels.len() == 1 => Ok(tunnels.remove(0).0),
+        None => anyhow::bail!(
+            "{} configures several tunnels, select one with --interface",
+            path.display()
+        ),
+    }
+}
+
+// the running vqn resolves paths relative to its own working directory
+fn absolute(path: &Path) -> anyhow::Result<PathBuf> {
+    std::fs::canonicalize(path).with_context(|| format!("failed to read {}", path.display()))
+}
+
+fn control_path(args: &Args) -> anyhow::Result<PathBuf> {
+    if let Some(path) = &args.control {
+        return Ok(path.clone());
+    }
+
+    let name = match &args.config {
+        // all tunnels configured in a directory share the socket of their process
+        Some(config_path) if config_path.is_dir() => absolute(config_path)?
+            .file_name()
+            .context("--config has no directory name")?
+            .to_string_lossy()
+            .into_owned(),
+        Some(config_path) => Conf::read(config_path)?
+            .network
+            .name()
+            .unwrap_or(DEFAULT_TUN_NAME)
+            .to_owned(),
+        None => args
+            .interface
+            .clone()
+            .unwrap_or_else(|| DEFAULT_TUN_NAME.to_owned()),
+    };
+
+    Ok(control::socket_path(&name))
+}
+
+// names of the tun interfaces configured by `--config`, or the one given by `--interface`
+fn tun_names(args: &Args) -> anyhow::Result<Vec<String>> {
+    if let Some(interface) = &args.interface {
+        return Ok(vec![interface.clone()]);
+    }
+
+    match &args.config {
+        Some(config_path) => Ok(Conf::read_all(config_path)?
+            .into_iter()
+            .map(|(_, conf)| conf.network.name().unwrap_or(DEFAULT_TUN_NAME).to_owned())
+            .collect()),
+        None => Ok(vec![DEFAULT_TUN_NAME.to_owned()]),
+    }
+}
+
 #[tokio::main]
-async fn run(conf: Conf, netns: Option<&str>) -> anyhow::Result<()> {
-    let iface = create_tun(&conf.network, netns)?;
+async fn run(tunnels: Vec<(PathBuf, Conf)>, control_path: &Path) -> anyhow::Result<()> {
+    let supervisor = Supervisor::new();
+    for (config_path, conf) in tunnels {
+        supervisor.start(config_path, conf)?;
+    }
+    let _control = control_socket(control_path, Arc::clone(&supervisor));
+
+    let result = tokio::select! {
+        biased;
+        _ = handle_signals(&supervisor) => Ok(()),
+        result = supervisor.wait() => result,
+    };
+
+    supervisor.shutdown().await;
+    result
+}
+
+/// Runs the tunnel configured by `conf` until `shutdown` fires or the tunnel fails.
+async fn run_tunnel(
+    conf: Conf,
+    live_conf: Arc<watch::Sender<Conf>>,
+    tunnel: &supervisor::Handle,
+    shutdown: oneshot::Receiver<()>,
+) -> anyhow::Result<()> {
+    let name = conf.network.name().unwrap_or(DEFAULT_TUN_NAME);
+    if firewall::cleanup(name)? {
+        tracing::warn!("removed firewall configuration left behind by a previous run");
+    }
+    // from here on the firewall is torn down however this function exits
+    let _teardown = firewall::Teardown::new(name);
+
+    let iface = create_tun(&conf.network)?;
 
     firewall::dev_up(&conf).context("failed start up firewall configuration sequence")?;
 
-    let conf2 = conf.clone();
-    let (tx, rx) = oneshot::channel::<()>();
-    let jh = tokio::spawn(async move {
-        handle_signals(tx, &conf2).await;
-    });
+    let _expiry = AbortOnDropHandle::new(tokio::spawn(
+        expiry::monitor(live_conf.subscribe()).in_current_span(),
+    ));
 
+    let shutdown = async {
+        let _ = shutdown.await;
+    };
     match &conf.network {
-        Network::Server {
-            client,
-            port,
-            fwmark,
-            ..
-        } => {
-            tokio::select! {
-                biased;
-                _ = rx => (),
-                _ = run_server(
-                    iface,
-                    port.unwrap_or(DEFAULT_LISTEN_PORT),
-                    *fwmark,
-                    &conf.tls,
-                    client,
-                ) => ()
-            }
-        }
-        Network::Client { server, fwmark, .. } => {
-            tokio::select! {
-                biased;
-                _ = rx => (),
-                _ = run_client(
-                    iface,
-                    *fwmark,
-                    &conf.tls,
-                    server
-                ) => ()
-            }
-        }
+        Network::Server { .. } => run_server(iface, &conf, tunnel, live_conf, shutdown).await,
+        Network::Client { .. } => run_client(iface, &conf, tunnel, live_conf, shutdown).await,
     }
+}
+
+fn enter_netns(netns: &str) -> anyhow::Result<()> {
+    let fd = std::fs::File::options()
+        .read(true)
+        .write(false)
+        .open(format!("/var/run/netns/{netns}"))?;
+    setns(fd, CloneFlags::CLONE_NEWNET)?;
 
-    jh.await?;
     Ok(())
 }
 
-fn create_tun(network: &Network, netns: Option<&str>) -> anyhow::Result<Iface> {
-    if let Some(netns) = netns {
-        let fd = std::fs::File::options()
-            .read(true)
-            .write(false)
-            .open(format!("/var/run/netns/{netns}"))?;
-        setns(fd, CloneFlags::CLONE_NEWNET)?;
-    }
+fn create_tun(network: &Network) -> anyhow::Result<Iface> {
+    let name = network.name().unwrap_or(DEFAULT_TUN_NAME);
+    let address = network.address();
 
     let mut config = core::tun::Configuration::default();
     config
-        .name(network.name().unwrap_or(DEFAULT_TUN_NAME))
-        .address(network.address().ip())
-        .netmask(network.address().netmask())
+        .name(name)
         .mtu(network.mtu().unwrap_or(DEFAULT_MTU) as i32)
         .up();
+    if let Some(v4) = address.and_then(|address| address.v4) {
+        config.address(v4.ip()).netmask(v4.netmask());
+    }
     let iface = Iface::new(config).with_context(|| "failed to create a tun interface")?;
 
+    // the tun crate only configures IPv4 addresses
+    if let Some(v6) = address.and_then(|address| address.v6) {
+        firewall::dev_address(name, v6)
+            .with_context(|| format!("failed to assign {v6} to {name}"))?;
+    }
+
     Ok(iface)
 }
 
 async fn run_server(
     iface: Iface,
-    listen_port: u16,
-    fwmark: Option<u32>,
-    tls_config: &conf::Tls,
-    clients: &[ClientPeer],
+    conf: &Conf,
+    tunnel: &supervisor::Handle,
+    live_conf: Arc<watch::Sender<Conf>>,
+    shutdown: impl Future<Output = ()>,
 ) -> anyhow::Result<()> {
-    let server_key = key(&tls_config.key)?;
-    let cert_chain = certs(&tls_config.cert)?;
-    let roots = roots(tls_config)?;
+    let Network::Server {
+        address,
+        pool,
+        lease_file,
+        client: clients,
+        port,
+        listen,
+        duplicate,
+        client_to_client,
+        ..
+    } = &conf.network
+    else {
+        anyhow::bail!("not a server configuration");
+    };
+    check_identities(conf)?;
 
-    let client_cert_verifier = rustls::server::AllowAnyAuthenticatedClient::new(roots);
-    let server_crypto = rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_client_cert_verifier(client_cert_verifier.boxed())
-        .with_single_cert(cert_chain, server_key)?;
+    let initial_mtu = iface.mtu().unwrap() as u16 + 60;
+    let mut server = core::Server::new(iface);
+    if let Some(pool) = pool {
+        let lease_file = lease_file.clone().unwrap_or_else(|| {
+            let name = conf.network.name().unwrap_or(DEFAULT_TUN_NAME);
+            Path::new(LEASE_DIR).join(format!("{name}.leases"))
+        });
+        let leases = core::Pool::new(
+            pool.iter().map(|cidr| (cidr.ip(), cidr.1)),
+            address.iter().map(Cidr::ip),
+            lease_file.clone(),
+        )
+        .with_context(|| format!("failed to read leases: {}", lease_file.display()))?;
+        tracing::info!("assigning client addresses out of {pool}");
+        server.set_pool(leases);
+    }
+    let endpoint_config = server_config(&conf.tls, initial_mtu, &server.router())?;
+
+    // without `listen`, a single socket accepts both IPv4 and IPv6, or only IPv4 on a host
+    // without IPv6, while the addresses in `listen` are bound exactly
+    let (listen, dual_stack) = match (listen, port) {
+        (Some(_), Some(_)) => anyhow::bail!("`listen` and `port` are mutually exclusive"),
+        (Some(listen), None) if listen.is_empty() => anyhow::bail!("`listen` is empty"),
+        (Some(listen), None) => (listen.clone(), false),
+        (None, port) => (
+            vec![SocketAddr::from((
+                Ipv6Addr::UNSPECIFIED,
+                port.unwrap_or(DEFAULT_LISTEN_PORT),
+            ))],
+            true,
+        ),
+    };
+    // tunnel traffic is marked so that it bypasses the routes to the tun interface
+    let fwmark = conf.network.fwmark();
+    let mut endpoints = Vec::with_capacity(listen.len());
+    for &addr in &listen {
+        let endpoint = server_endpoint(endpoint_config.clone(), addr, dual_stack, fwmark)
+            .await
+            .with_context(|| format!("failed to listen at {addr}"))?;
+        tracing::info!("listening at {}", endpoint.local_addr().unwrap_or(addr));
+        endpoints.push(endpoint);
+    }
+
+    server.set_duplicate_policy(match duplicate {
+        Some(conf::Duplicate::NewestWins) | None => core::DuplicatePolicy::NewestWins,
+        Some(conf::Duplicate::Reject) => core::DuplicatePolicy::Reject,
+    });
+    server.set_client_to_client((*client_to_client).into());
+    for client in clients {
+        let identity = peer_identity(client.client_cert.as_deref(), client.identity.as_ref())?;
+        tracing::info!(
+            "adding client {identity} with allowed ips: {}",
+            &client.allowed_ips
+        );
+        server.add_client(identity, client.allowed_ips.iter())
+    }
+    for (identity, (_, acl)) in client_peers(clients)? {
+        server.router().set_acl(&identity, acl);
+    }
+    server
+        .router()
+        .push(pushed_config(conf).context("invalid [network.push]")?);
+    let forwards = Arc::new(forward::Forwards::new(server.router()));
+    forwards.update(client_forwards(clients, pool)?).await?;
+
+    let mut reload = live_conf.subscribe();
+    tunnel.register(control::Tunnel::Server {
+        router: server.router(),
+        conf: live_conf,
+        forwards: Arc::clone(&forwards),
+    });
+
+    let router = server.router();
+    let tls_endpoints = endpoints.clone();
+    let reload_forwards = Arc::clone(&forwards);
+    let _reload = AbortOnDropHandle::new(tokio::spawn(
+        async move {
+            let mut crl_check = tokio::time::interval(CRL_POLL_INTERVAL);
+            let mut crl_modified = modified(&reload.borrow().tls.crl);
+            loop {
+                tokio::select! {
+                    changed = reload.changed() => {
+                        if changed.is_err() {
+                            break;
+                        }
+                        let conf = reload.borrow_and_update().clone();
+                        let Network::Server { client, pool, .. } = conf.network.clone() else {
+                            continue;
+                        };
+                        // client certificates are read from disk
+                        let (peers, forwards) = tokio::task::spawn_blocking(move || {
+                            (client_peers(&client), client_forwards(&client, &pool))
+                        })
+                        .await
+                        .expect("reading client certificates panicked");
+                        match peers {
+                            Ok(peers) => router.update_peers(peers).await,
+                            Err(e) => tracing::error!("failed to reload clients: {e:#}"),
+                        }
+                        let forwards = match forwards {
+                            Ok(forwards) => reload_forwards.update(forwards).await,
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = forwards {
+                            tracing::error!("failed to reload forwarded ports: {e:#}");
+                        }
+                        match pushed_config(&conf) {
+                            Ok(config) => router.push(config),
+                            Err(e) => tracing::error!("failed to reload [network.push]: {e:#}"),
+                        }
+                    }
+                    _ = crl_check.tick() => {
+                        if modified(&reload.borrow().tls.crl) == crl_modified {
+                            continue;
+                        }
+                        tracing::info!("certificate revocation list changed");
+                    }
+                }
+
+                let tls_config = reload.borrow().tls.clone();
+                crl_modified = modified(&tls_config.crl);
+                let (endpoints, router) = (tls_endpoints.clone(), Arc::clone(&router));
+                // keys, certificates and revocation lists are read from disk, and a
+                // passphrase may be prompted for
+                tokio::task::spawn_blocking(move || {
+                    reload_tls(&tls_config, initial_mtu, &endpoints, &router)
+                })
+                .await
+                .expect("reloading TLS configuration panicked");
+            }
+        }
+        .in_current_span(),
+    ));
+
+    let result = server.run(endpoints.clone(), shutdown).await;
+    // the relays release their ports before a restart binds them again
+    forwards.clear().await;
+    result?;
+
+    // tells clients right away instead of leaving them to time out, and releases the
+    // sockets before a restart binds them again
+    for endpoint in &endpoints {
+        endpoint.close(0_u32.into(), b"shutting down");
+    }
+    for endpoint in &endpoints {
+        endpoint.wait_idle().await;
+    }
+
+    Ok(())
+}
+
+// the TLS and transport configuration of a server's endpoints
+fn server_config(
+    tls_config: &conf::Tls,
+    initial_mtu: u16,
+    peers: &Arc<core::Router>,
+) -> anyhow::Result<quinn::ServerConfig> {
+    let server_crypto = server_crypto(tls_config, peers)?;
 
     let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(server_crypto));
     let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
     transport_config
         .max_idle_timeout(Some(Duration::from_secs(120).try_into()?))
-        .initial_mtu(iface.mtu().unwrap() as u16 + 60)
+        .initial_mtu(initial_mtu)
         .mtu_discovery_config(Some(MtuDiscoveryConfig::default()))
         .max_concurrent_uni_streams(0_u8.into());
 
-    let listen = SocketAddr::from(([0, 0, 0, 0], listen_port));
-    let endpoint = core::rt::server_endpoint(server_config, listen, fwmark)?;
-    tracing::info!("listening at {}", listen);
+    Ok(server_config)
+}
+
+// without a `ca_cert`, clients are accepted by their key fingerprint as configured in `peers`
+fn server_crypto(
+    tls_config: &conf::Tls,
+    peers: &Arc<core::Router>,
+) -> anyhow::Result<rustls::ServerConfig> {
+    let server_key = key(tls_config)?;
+    let cert_chain = cert_chain(tls_config, &server_key)?;
 
-    let mut server = core::Server::new(iface);
-    for client in clients {
-        tracing::info!("adding a client with allowed ips: {}", &client.allowed_ips);
-        server.add_client(certs(&client.client_cert)?, client.allowed_ips.iter())
+    let client_cert_verifier: Arc<dyn rustls::server::ClientCertVerifier> =
+        match &tls_config.ca_cert {
+            Some(ca_cert) => {
+                let crls = crls(&tls_config.crl)?;
+                let verifier = rustls::server::AllowAnyAuthenticatedClient::new(roots(ca_cert)?)
+                    .with_crls(
+                        crls.into_iter()
+                            .map(rustls::server::UnparsedCertRevocationList),
+                    )
+                    .map_err(rustls::Error::from)?;
+                Arc::new(expiry::ClientExpiry(verifier.boxed()))
+            }
+            None if !tls_config.crl.is_empty() => anyhow::bail!("`crl` needs a `ca_cert`"),
+            None => Arc::new(pin::PinnedClients::new(Arc::clone(peers))),
+        };
+
+    Ok(rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_cert_verifier)
+        .with_single_cert(cert_chain, server_key)?)
+}
+
+// applies the certificates and revocation lists of `tls_config` to new handshakes, and
+// closes the connections of clients whose certificate has been revoked since
+fn reload_tls(
+    tls_config: &conf::Tls,
+    initial_mtu: u16,
+    endpoints: &[quinn::Endpoint],
+    router: &Arc<core::Router>,
+) {
+    match server_config(tls_config, initial_mtu, router) {
+        Ok(config) => {
+            for endpoint in endpoints {
+                endpoint.set_server_config(Some(config.clone()));
+            }
+        }
+        Err(e) => {
+            tracing::error!("failed to reload TLS configuration: {e:#}");
+            return;
+        }
     }
 
-    server.run(endpoint).await?;
+    let crls = crls(&tls_config.crl).unwrap_or_default();
+    for peer in router.status() {
+        let Some(cert_chain) = &peer.link.cert_chain else {
+            continue;
+        };
+        if revoked(&crls, cert_chain)
+            && router.disconnect(&peer.identity, core::PEER_REVOKED, "certificate revoked")
+        {
+            tracing::info!("closed the connection of a client with a revoked certificate");
+        }
+    }
+}
 
-    Ok(())
+// the server is verified against the `ca_cert`, or else by the fingerprint of its key
+fn client_crypto(
+    tls_config: &conf::Tls,
+    pin: Option<&core::Identity>,
+) -> anyhow::Result<rustls::ClientConfig> {
+    let client_key = key(tls_config)?;
+    let cert_chain = cert_chain(tls_config, &client_key)?;
+
+    let server_cert_verifier: Arc<dyn rustls::client::ServerCertVerifier> =
+        match (&tls_config.ca_cert, pin) {
+            (Some(_), Some(_)) => anyhow::bail!("`pin` and `ca_cert` are mutually exclusive"),
+            (Some(ca_cert), None) => Arc::new(expiry::ServerExpiry(Arc::new(
+                rustls::client::WebPkiVerifier::new(roots(ca_cert)?, None),
+            ))),
+            (None, Some(core::Identity::SpkiSha256(pin))) => Arc::new(pin::PinnedServer::new(*pin)),
+            (None, Some(_)) => anyhow::bail!("`pin` must be a key fingerprint, spki-sha256:<hex>"),
+            (None, None) => anyhow::bail!("a client needs either `ca_cert` or the server's `pin`"),
+        };
+
+    Ok(rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(server_cert_verifier)
+        .with_client_auth_cert(cert_chain, client_key)?)
+}
+
+// quinn closes the sockets of a shut down endpoint in the background, so the ones of a
+// tunnel that is being restarted may not be released yet
+async fn server_endpoint(
+    config: quinn::ServerConfig,
+    addr: SocketAddr,
+    dual_stack: bool,
+    fwmark: u32,
+) -> std::io::Result<quinn::Endpoint> {
+    for _ in 0..BIND_RETRIES {
+        match core::rt::server_endpoint(config.clone(), addr, dual_stack, Some(fwmark)) {
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                tokio::time::sleep(BIND_RETRY_DELAY).await;
+            }
+            result => return result,
+        }
+    }
+
+    core::rt::server_endpoint(config, addr, dual_stack, Some(fwmark))
 }
 
 async fn run_client(
     iface: Iface,
-    fwmark: Option<u32>,
-    tls_config: &conf::Tls,
-    server: &ServerPeer,
+    conf: &Conf,
+    tunnel: &supervisor::Handle,
+    live_conf: Arc<watch::Sender<Conf>>,
+    shutdown: impl Future<Output = ()>,
 ) -> anyhow::Result<()> {
-    let client_key = key(&tls_config.key)?;
-    let cert_chain = certs(&tls_config.cert)?;
-    let roots = roots(tls_config)?;
-
-    let client_crypto = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(roots)
-        .with_client_auth_cert(cert_chain, client_key)?;
+    let Network::Client { server, .. } = &conf.network else {
+        anyhow::bail!("not a client configuration");
+    };
+    let tls_config = &conf.tls;
+    let fwmark = conf.network.fwmark();
+    let client_crypto = client_crypto(tls_config, server.pin.as_ref())?;
 
     let mut transport_config = TransportConfig::default();
     transport_config
@@ -196,45 +801,487 @@ async fn run_client(
     let mut client_config = quinn::ClientConfig::new(Arc::new(client_crypto));
     client_config.transport_config(Arc::new(transport_config));
 
-    let mut endpoint = core::rt::client_endpoint("[::]:0".parse().unwrap(), fwmark)?;
+    let mut endpoint = core::rt::client_endpoint("[::]:0".parse().unwrap(), Some(fwmark))?;
     endpoint.set_default_client_config(client_config);
 
-    let url = &server.url;
-    let remote = (url.host_str().unwrap(), url.port().unwrap_or(443))
-        .to_socket_addrs()?
-        .next()
-        .ok_or_else(|| anyhow!("couldn't resolve to an address"))?;
-    let host = server
-        .server_name
-        .as_deref()
-        .or(url.host_str())
-        .ok_or_else(|| anyhow!("no hostname specified"))?;
-    tracing::info!("connecting to {host} at {remote}");
-
     let mut client = core::Client::new(iface)?;
+    if conf.network.mtu().is_some() {
+        client.keep_mtu();
+    }
+    let (routes, allowed_ips) = watch::channel(server.allowed_ips.clone().unwrap_or_default());
+    tunnel.register(control::Tunnel::Client {
+        link: client.link(),
+        allowed_ips,
+    });
+
+    let pushed = Arc::new(Mutex::new(Pushed {
+        local: conf.clone(),
+        applied: conf.clone(),
+        session: core::session::SessionConfig::default(),
+        assigned: vec![],
+        routes,
+    }));
+    let _reload = reconcile(&pushed, live_conf.subscribe(), Pushed::reconfigure);
+    let (sessions, pushes) = watch::channel(core::session::SessionConfig::default());
+    let _push = reconcile(&pushed, pushes, |pushed, session| pushed.push(&session));
+
+    tokio::select! {
+        biased;
+        _ = shutdown => (),
+        result = reconnect_loop(
+            &mut client,
+            &endpoint,
+            server,
+            tls_config.cert.as_deref(),
+            &live_conf,
+            &sessions,
+        ) => result?,
+    }
+
+    // tells the server right away instead of leaving it to time out
+    endpoint.close(0_u32.into(), b"shutting down");
+    endpoint.wait_idle().await;
+
+    Ok(())
+}
 
+async fn reconnect_loop(
+    client: &mut core::Client,
+    endpoint: &quinn::Endpoint,
+    server: &ServerPeer,
+    cert: Option<&Path>,
+    conf: &watch::Sender<Conf>,
+    sessions: &watch::Sender<core::session::SessionConfig>,
+) -> anyhow::Result<()> {
+    // the tun device and its routes stay up while reconnecting, so traffic is held
+    // back instead of leaking outside the tunnel
+    let mut backoff = Backoff::new(
+        RECONNECT_BASE_DELAY,
+        server
+            .max_backoff
+            .map_or(DEFAULT_MAX_BACKOFF, Duration::from_secs),
+    );
     loop {
-        let conn = endpoint
-            .connect(remote, host)?
-            .await
-            .map_err(|e| anyhow!("failed to connect: {}", e))?;
+        tracing::info!("connecting (attempt {})", backoff.attempt() + 1);
 
-        tracing::info!("connected to {host} at {remote}");
+        let address = conf.borrow().network.address().is_some();
+        let (conn, control, session) = match connect(endpoint, server, address).await {
+            Ok(connected) => connected,
+            Err(e) => {
+                let delay = backoff.next_delay();
+                tracing::warn!("{e:#}, retrying in {delay:.1?}");
+                if let Some(e) = e.downcast_ref() {
+                    check_rejected_as_expired(e, cert);
+                }
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+        };
 
-        if let Err(core::Error::Conn(_)) = client.run(conn).await {
-            // reconnect
-            continue;
+        tracing::info!("connected to {}", conn.remote_address());
+        if let Some(session) = session {
+            client.configure(&session);
+            sessions.send_replace(session);
+        }
+        let connected_at = Instant::now();
+
+        let on_update = |session: &core::session::SessionConfig| {
+            sessions.send_replace(session.clone());
+        };
+        match client.run(conn, control, on_update).await {
+            Err(core::Error::Conn(e)) => {
+                tracing::warn!("connection lost: {e}");
+                check_rejected_as_expired(&e, cert);
+            }
+            Err(e) => return Err(e.into()),
+            Ok(()) => break,
+        }
+
+        // a server closing connections right after the handshake, e.g. a rejected
+        // duplicate, is backed off like a failed connect
+        if connected_at.elapsed() < STABLE_CONNECTION {
+            let delay = backoff.next_delay();
+            tracing::info!("reconnecting in {delay:.1?}");
+            tokio::time::sleep(delay).await;
         } else {
-            break;
+            backoff.reset();
         }
     }
 
     Ok(())
 }
 
-fn roots(tls_config: &conf::Tls) -> anyhow::Result<rustls::RootCertStore> {
+// applies every change of `changes` to `pushed` with `apply` until dropped, on a blocking
+// thread since reconciling the firewall runs `ip`, `resolvectl` and the like. Errors are
+// logged, leaving the tunnel up with the configuration applied before.
+fn reconcile<T: Clone + Send + Sync + 'static>(
+    pushed: &Arc<Mutex<Pushed>>,
+    mut changes: watch::Receiver<T>,
+    apply: fn(&mut Pushed, T) -> anyhow::Result<()>,
+) -> AbortOnDropHandle<()> {
+    let pushed = Arc::clone(pushed);
+    AbortOnDropHandle::new(tokio::spawn(
+        async move {
+            while changes.changed().await.is_ok() {
+                let change = changes.borrow_and_update().clone();
+                let pushed = Arc::clone(&pushed);
+                let span = tracing::Span::current();
+                let result = tokio::task::spawn_blocking(move || {
+                    span.in_scope(|| apply(&mut pushed.lock().unwrap(), change))
+                })
+                .await
+                .expect("reconfiguring the client panicked");
+                if let Err(e) = result {
+                    tracing::error!("{e:#}");
+                }
+            }
+        }
+        .in_current_span(),
+    ))
+}
+
+// connects to the server and, unless the client has an `address` of its own, receives
+// the configuration it sends on the control stream, which is needed for the address the
+// server assigns. With an `address`, the configuration is applied whenever it arrives,
+// which is never for servers that push nothing.
+async fn connect(
+    endpoint: &quinn::Endpoint,
+    server: &ServerPeer,
+    address: bool,
+) -> anyhow::Result<(
+    quinn::Connection,
+    core::session::Receiver,
+    Option<core::session::SessionConfig>,
+)> {
+    let conn = connect::connect(endpoint, &server.url, server.server_name.as_deref()).await?;
+    let mut control = core::session::Receiver::new(&conn);
+    if address {
+        return Ok((conn, control, None));
+    }
+    let session = tokio::time::timeout(SESSION_TIMEOUT, control.recv())
+        .await
+        .context("timed out waiting for the session configuration")?
+        .context("failed to receive the session configuration")?;
+
+    Ok((conn, control, Some(session)))
+}
+
+// a client's own configuration merged with the one its server pushes, which is what
+// its tun interface, routes and DNS are configured with
+struct Pushed {
+    local: Conf,
+    // the configuration the firewall was last reconciled with
+    applied: Conf,
+    session: core::session::SessionConfig,
+    assigned: Vec<Cidr>,
+    routes: watch::Sender<conf::AllowedIps>,
+}
+
+impl Pushed {
+    // applies the configuration the server sends on connecting and whenever it changes
+    fn push(&mut self, session: &core::session::SessionConfig) -> anyhow::Result<()> {
+        self.assign(&session.address)?;
+        self.session = session.clone();
+        self.apply()
+    }
+
+    // applies a reloaded local configuration
+    fn reconfigure(&mut self, conf: Conf) -> anyhow::Result<()> {
+        self.local = conf;
+        self.apply()
+    }
+
+    fn apply(&mut self) -> anyhow::Result<()> {
+        let effective = self.effective();
+        firewall::dev_reload(&self.applied, &effective)
+            .context("failed to update firewall configuration")?;
+        if let Network::Client { server, .. } = &effective.network {
+            self.routes
+                .send_replace(server.allowed_ips.clone().unwrap_or_default());
+        }
+        self.applied = effective;
+
+        Ok(())
+    }
+
+    // the local configuration with what it leaves out filled in by the server: routes
+    // within `accept_routes` unless `allowed_ips` is set, and DNS unless `dns` and
+    // `dns_search` are set or `accept_dns` is false
+    fn effective(&self) -> Conf {
+        let mut conf = self.local.clone();
+        let Network::Client {
+            server,
+            dns,
+            dns_search,
+            ..
+        } = &mut conf.network
+        else {
+            return conf;
+        };
+
+        if server.allowed_ips.is_none() {
+            let (accepted, rejected): (Vec<_>, Vec<_>) = self
+                .session
+                .routes
+                .iter()
+                .map(|&(ip, prefix)| Cidr(ip, prefix).network())
+                .partition(|route| {
+                    server.accept_routes.as_ref().is_none_or(|accept| {
+                        accept.values.iter().any(|range| range.contains(*route))
+                    })
+                });
+            if !rejected.is_empty() {
+                tracing::warn!(
+                    "ignoring routes pushed by the server outside `accept_routes`: {}",
+                    conf::AllowedIps { values: rejected }
+                );
+            }
+            server.allowed_ips = Some(conf::AllowedIps { values: accepted });
+        }
+
+        if server.accept_dns.unwrap_or(true) {
+            if dns.is_none() && !self.session.dns.is_empty() {
+                let servers: Vec<_> = self.session.dns.iter().map(IpAddr::to_string).collect();
+                *dns = Some(servers.join(" "));
+            }
+            if dns_search.is_none() && !self.session.search.is_empty() {
+                *dns_search = Some(self.session.search.clone());
+            }
+        }
+
+        conf
+    }
+
+    // applies the addresses the server assigned on connecting, which only change if the
+    // server's pool did
+    fn assign(&mut self, leased: &[(IpAddr, u8)]) -> anyhow::Result<()> {
+        let name = self.local.network.name().unwrap_or(DEFAULT_TUN_NAME);
+        let leased: Vec<_> = leased
+            .iter()
+            .map(|&(ip, prefix)| Cidr(ip, prefix))
+            .collect();
+        let list = |cidrs: &[Cidr]| {
+            let cidrs: Vec<_> = cidrs.iter().map(Cidr::to_string).collect();
+            cidrs.join(", ")
+        };
+
+        if let Some(configured) = self.local.network.address() {
+            if leased
+                .iter()
+                .any(|cidr| !configured.iter().any(|c| c == *cidr))
+            {
+                tracing::warn!(
+                    "ignoring the address {} assigned by the server, `address` is configured",
+                    list(&leased)
+                );
+            }
+            return Ok(());
+        }
+        anyhow::ensure!(
+            !leased.is_empty(),
+            "the server assigns no address, set `address` in [network]"
+        );
+        if leased != self.assigned {
+            firewall::dev_readdress(name, &self.assigned, &leased)
+                .context("failed to assign the address from the server")?;
+            tracing::info!("assigned address {}", list(&leased));
+            self.assigned = leased;
+        }
+
+        Ok(())
+    }
+}
+
+// servers tell clients that their certificate expired with nothing but an alert
+fn check_rejected_as_expired(e: &quinn::ConnectionError, cert: Option<&Path>) {
+    if !expiry::rejected_as_expired(e) {
+        return;
+    }
+    let validity = cert
+        .and_then(|cert| certs(cert).ok())
+        .and_then(|chain| chain.first().and_then(expiry::Validity::of));
+    match (cert, validity) {
+        (Some(cert), Some(validity)) => tracing::error!(
+            "the server rejected our certificate {} ({}), which expired on {}",
+            validity.subject,
+            cert.display(),
+            validity.not_after.date()
+        ),
+        _ => tracing::error!("the server rejected our certificate as expired"),
+    }
+}
+
+fn control_socket(path: &Path, supervisor: Arc<Supervisor>) -> Option<ControlSocket> {
+    match control::serve(path, supervisor) {
+        Ok(socket) => {
+            tracing::info!("control socket listening at {}", path.display());
+            Some(socket)
+        }
+        Err(e) => {
+            tracing::warn!("failed to bind control socket {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+// the configuration a server pushes to its clients, from [network.push]
+fn pushed_config(conf: &Conf) -> anyhow::Result<core::session::SessionConfig> {
+    let Network::Server {
+        push: Some(push), ..
+    } = &conf.network
+    else {
+        return Ok(Default::default());
+    };
+    let dns = match &push.dns {
+        Some(dns) => conf::dns_servers(dns).map_err(anyhow::Error::msg)?,
+        None => vec![],
+    };
+
+    Ok(core::session::SessionConfig {
+        routes: push
+            .routes
+            .iter()
+            .flat_map(conf::AllowedIps::iter)
+            .collect(),
+        dns,
+        search: push.dns_search.clone().unwrap_or_default(),
+        mtu: push.mtu,
+        ..Default::default()
+    })
+}
+
+fn client_peers(clients: &[ClientPeer]) -> anyhow::Result<core::PeerIps> {
+    let mut peers = core::PeerIps::new();
+    for client in clients {
+        let identity = peer_identity(client.client_cert.as_deref(), client.identity.as_ref())?;
+        let acl = client_acl(&identity, client)?;
+        let (allowed_ips, current) = peers.entry(identity).or_default();
+        allowed_ips.extend(client.allowed_ips.iter());
+        *current = acl;
+    }
+
+    Ok(peers)
+}
+
+// anyone can put any name in a self-signed certificate, so clients are only recognized by
+// name if their certificates are signed by the `ca_cert`
+fn check_identities(conf: &Conf) -> anyhow::Result<()> {
+    let Network::Server { client, .. } = &conf.network else {
+        return Ok(());
+    };
+    if conf.tls.ca_cert.is_none() {
+        if let Some(identity) = client.iter().find_map(|client| {
+            client.identity.as_ref().filter(|identity| {
+                matches!(
+                    identity,
+                    core::Identity::CommonName(_) | core::Identity::SubjectAltName(_)
+                )
+            })
+        }) {
+            anyhow::bail!("client {identity} can only be recognized by name with a `ca_cert`");
+        }
+    }
+
+    Ok(())
+}
+
+// the ACL filtering the traffic of each client that has one
+fn client_acl(
+    identity: &core::Identity,
+    client: &ClientPeer,
+) -> anyhow::Result<Option<core::acl::Acl>> {
+    let action = |action| match action {
+        conf::AclAction::Allow => core::acl::Action::Allow,
+        conf::AclAction::Deny => core::acl::Action::Deny,
+    };
+    if client.acl.is_empty() && client.acl_default.is_none() {
+        return Ok(None);
+    }
+
+    let mut rules = Vec::with_capacity(client.acl.len());
+    for rule in &client.acl {
+        anyhow::ensure!(
+            rule.port.is_none() || rule.protocol != Some(conf::Protocol::Icmp),
+            "acl rule of {identity} matches a port of icmp traffic"
+        );
+        rules.push(core::acl::Rule {
+            action: action(rule.action),
+            dst: rule.dst.map(|dst| (dst.ip(), dst.1)),
+            protocol: rule.protocol.map(|protocol| match protocol {
+                conf::Protocol::Tcp => core::acl::Protocol::Tcp,
+                conf::Protocol::Udp => core::acl::Protocol::Udp,
+                conf::Protocol::Icmp => core::acl::Protocol::Icmp,
+            }),
+            ports: rule.port.map(|conf::PortRange(first, last)| (first, last)),
+        });
+    }
+    let default = action(client.acl_default.unwrap_or(conf::AclAction::Allow));
+
+    Ok(Some(core::acl::Acl::new(rules, default)))
+}
+
+// the ports forwarded to each client, whose targets must be routed to that client
+fn client_forwards(
+    clients: &[ClientPeer],
+    pool: &Option<conf::Address>,
+) -> anyhow::Result<Vec<(core::Identity, forward::Forward)>> {
+    let mut forwards: Vec<(core::Identity, forward::Forward)> = vec![];
+    for client in clients {
+        let identity = peer_identity(client.client_cert.as_deref(), client.identity.as_ref())?;
+        for f in &client.forward {
+            let proto = match f.proto {
+                Some(conf::Protocol::Tcp) | None => forward::Proto::Tcp,
+                Some(conf::Protocol::Udp) => forward::Proto::Udp,
+                Some(conf::Protocol::Icmp) => {
+                    anyhow::bail!("forward of {identity} at {} can't relay icmp", f.public)
+                }
+            };
+            let forward = forward::Forward {
+                proto,
+                public: f.public,
+                target: f.target,
+            };
+            let target = Cidr(f.target.ip(), if f.target.is_ipv4() { 32 } else { 128 });
+            anyhow::ensure!(
+                client
+                    .allowed_ips
+                    .values
+                    .iter()
+                    .copied()
+                    .chain(pool.iter().flat_map(conf::Address::iter))
+                    .any(|cidr| cidr.contains(target)),
+                "forward {forward} of {identity} targets an address outside its `allowed_ips` and the `pool`"
+            );
+            anyhow::ensure!(
+                !forwards
+                    .iter()
+                    .any(|(_, other)| (other.proto, other.public) == (proto, f.public)),
+                "{} is forwarded more than once",
+                f.public
+            );
+            forwards.push((identity.clone(), forward));
+        }
+    }
+
+    Ok(forwards)
+}
+
+// what a client given by either its certificate file or an identity is recognized by
+fn peer_identity(
+    cert: Option<&Path>,
+    identity: Option<&core::Identity>,
+) -> anyhow::Result<core::Identity> {
+    match (cert, identity) {
+        (Some(_), Some(_)) => anyhow::bail!("`client_cert` and `identity` are mutually exclusive"),
+        (Some(cert), None) => Ok(core::Identity::Chain(certs(cert)?)),
+        (None, Some(identity)) => Ok(identity.clone()),
+        (None, None) => anyhow::bail!("a client needs either `client_cert` or `identity`"),
+    }
+}
+
+fn roots(ca_cert: &Path) -> anyhow::Result<rustls::RootCertStore> {
     let mut roots = rustls::RootCertStore::empty();
-    let ca_certs = certs(&tls_config.ca_cert)?;
+    let ca_certs = certs(ca_cert)?;
     for cert in &ca_certs {
         roots.add(cert)?;
     }
@@ -242,25 +1289,77 @@ fn roots(tls_config: &conf::Tls) -> anyhow::Result<rustls::RootCertStore> {
     Ok(roots)
 }
 
-fn key(key_path: &Path) -> anyhow::Result<rustls::PrivateKey> {
-    let key = std::fs::read(key_path)
-        .with_context(|| format!("failed to read private key: {}", key_path.to_string_lossy()))?;
-    let pkcs8 =
-        rustls_pemfile::pkcs8_private_keys(&mut &*key).context("malformed PKCS #8 private key")?;
-    let key = match pkcs8.into_iter().next() {
-        Some(x) => rustls::PrivateKey(x),
-        None => {
-            let rsa = rustls_pemfile::rsa_private_keys(&mut &*key)
-                .context("malformed PKCS #1 private key")?;
-            match rsa.into_iter().next() {
-                Some(x) => rustls::PrivateKey(x),
-                None => {
-                    anyhow::bail!("no private keys found");
-                }
-            }
+// the certificate revocation lists in `paths`, each file in PEM or DER
+fn crls(paths: &[PathBuf]) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut crls = Vec::new();
+    for path in paths {
+        let crl = std::fs::read(path).with_context(|| {
+            format!(
+                "failed to read certificate revocation list: {}",
+                path.to_string_lossy()
+            )
+        })?;
+        let pem = rustls_pemfile::crls(&mut &*crl)
+            .context("invalid PEM-encoded certificate revocation list")?;
+        let file = if pem.is_empty() { vec![crl] } else { pem };
+        for crl in &file {
+            rustls::server::UnparsedCertRevocationList(crl.clone())
+                .parse()
+                .map_err(rustls::Error::from)
+                .with_context(|| {
+                    format!(
+                        "invalid certificate revocation list: {}",
+                        path.to_string_lossy()
+                    )
+                })?;
         }
+        crls.extend(file);
+    }
+
+    Ok(crls)
+}
+
+// whether the end-entity certificate of `cert_chain` is listed by a revocation list of its
+// issuer. The lists have been checked by the TLS verifier when they were loaded.
+fn revoked(crls: &[Vec<u8>], cert_chain: &[rustls::Certificate]) -> bool {
+    let Some(Ok((_, cert))) = cert_chain
+        .first()
+        .map(|cert| x509_parser::parse_x509_certificate(&cert.0))
+    else {
+        return false;
     };
-    Ok(key)
+
+    crls.iter()
+        .filter_map(|crl| x509_parser::parse_x509_crl(crl).ok())
+        .filter(|(_, crl)| crl.issuer().as_raw() == cert.issuer().as_raw())
+        .any(|(_, crl)| {
+            crl.iter_revoked_certificates()
+                .any(|revoked| revoked.raw_serial() == cert.raw_serial())
+        })
+}
+
+// modification times of `paths`, to notice when they are replaced
+fn modified(paths: &[PathBuf]) -> Vec<Option<std::time::SystemTime>> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+fn key(tls_config: &conf::Tls) -> anyhow::Result<rustls::PrivateKey> {
+    keys::load(&tls_config.key, tls_config.key_passphrase_file.as_deref())
+}
+
+// the configured certificate chain, or a self-signed certificate for `key` if there is none
+fn cert_chain(
+    tls_config: &conf::Tls,
+    key: &rustls::PrivateKey,
+) -> anyhow::Result<Vec<rustls::Certificate>> {
+    match (&tls_config.cert, &tls_config.ca_cert) {
+        (Some(cert), _) => certs(cert),
+        (None, Some(_)) => anyhow::bail!("a `cert` signed by the `ca_cert` is required"),
+        (None, None) => Ok(vec![pin::self_signed(key)?]),
+    }
 }
 
 fn certs(cert_path: &Path) -> anyhow::Result<Vec<rustls::Certificate>> {
@@ -279,26 +1378,94 @@ fn certs(cert_path: &Path) -> anyhow::Result<Vec<rustls::Certificate>> {
     Ok(cert_chain)
 }
 
-async fn handle_signals(shutdown: Sender<()>, conf: &Conf) {
+async fn handle_signals(supervisor: &Supervisor) {
     let mut sigint = signal(SignalKind::interrupt()).expect("failed to register SIGINT handler");
     let mut sigterm = signal(SignalKind::terminate()).expect("failed to register SIGTERM handler");
     let mut sighup = signal(SignalKind::hangup()).expect("failed to register SIGHUP handler");
 
-    tokio::select! {
-        _ = sigint.recv() => {
-            tracing::info!("Received SIGINT");
-            firewall::dev_down(conf);
-            let _ = shutdown.send(());
-        },
-        _ = sigterm.recv() => {
-            tracing::info!("Received SIGTERM");
-            firewall::dev_down(conf);
-            let _ = shutdown.send(());
-        },
+    loop {
+        tokio::select! {
+            _ = sigint.recv() => {
+                tracing::info!("Received SIGINT");
+                break;
+            },
+            _ = sigterm.recv() => {
+                tracing::info!("Received SIGTERM");
+                break;
+            },
             _ = sighup.recv() => {
-            tracing::info!("Received SIGHUP");
-            firewall::dev_down(conf);
-            let _ = shutdown.send(());
-        },
+                tracing::info!("Received SIGHUP");
+                supervisor.reload().await;
+            },
+        }
     }
 }
+
+/// Replaces the live configuration with the one returned by `update`, reconciling the
+/// firewall with the new configuration before it is published to the running tunnel.
+fn update_conf(
+    conf: &watch::Sender<Conf>,
+    update: impl FnOnce(&Conf) -> anyhow::Result<Conf>,
+) -> anyhow::Result<()> {
+    let mut result = Ok(());
+    conf.send_if_modified(|current| {
+        let new = update(current).and_then(|new| {
+            anyhow::ensure!(
+                std::mem::discriminant(&current.network) == std::mem::discriminant(&new.network),
+                "changing network role requires a restart"
+            );
+            anyhow::ensure!(
+                current.network.name() == new.network.name(),
+                "changing the interface name requires a restart"
+            );
+            if let (
+                Network::Server {
+                    pool,
+                    lease_file,
+                    client_to_client,
+                    nat,
+                    ..
+                },
+                Network::Server {
+                    pool: new_pool,
+                    lease_file: new_lease_file,
+                    client_to_client: new_client_to_client,
+                    nat: new_nat,
+                    ..
+                },
+            ) = (&current.network, &new.network)
+            {
+                anyhow::ensure!(
+                    pool == new_pool && lease_file == new_lease_file,
+                    "changing the address pool requires a restart"
+                );
+                anyhow::ensure!(
+                    client_to_client == new_client_to_client,
+                    "changing client_to_client requires a restart"
+                );
+                anyhow::ensure!(nat == new_nat, "changing [network.nat] requires a restart");
+            }
+            check_identities(&new)?;
+            // the routes and DNS of a client also depend on what its server pushes, so
+            // the client reconciles them itself
+            if let Network::Server { .. } = new.network {
+                firewall::dev_reload(current, &new)
+                    .context("failed to update firewall configuration")?;
+            }
+            Ok(new)
+        });
+
+        match new {
+            Ok(new) => {
+                *current = new;
+                true
+            }
+            Err(e) => {
+                result = Err(e);
+                false
+            }
+        }
+    });
+
+    result
+}
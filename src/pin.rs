@@ -0,0 +1,114 @@
+//! Authentication by key fingerprint, for tunnels without a certificate authority.
+//!
+//! Like WireGuard's static keys, each side knows the SHA-256 fingerprint of the other's
+//! SubjectPublicKeyInfo: a server the ones of its clients, a client the one of its
+//! server. Certificates only carry the key; names and validity dates are not checked.
+
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::Context;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::server::{ClientCertVerified, ClientCertVerifier};
+use rustls::{Certificate, CertificateError, DistinguishedName, ServerName};
+use thiserror::Error;
+
+use crate::core::{self, Identity, Router};
+use crate::tls;
+
+#[derive(Debug, Error)]
+#[error("key fingerprint {0} is not pinned")]
+struct NotPinned(Identity);
+
+/// Accepts a server whose key has the pinned fingerprint.
+pub struct PinnedServer {
+    pin: [u8; 32],
+}
+
+impl PinnedServer {
+    pub fn new(pin: [u8; 32]) -> Self {
+        PinnedServer { pin }
+    }
+}
+
+impl ServerCertVerifier for PinnedServer {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match core::spki_sha256(end_entity) {
+            Some(spki) if spki == self.pin => Ok(ServerCertVerified::assertion()),
+            Some(spki) => Err(not_pinned(spki)),
+            None => Err(CertificateError::BadEncoding.into()),
+        }
+    }
+
+    fn request_scts(&self) -> bool {
+        false
+    }
+}
+
+/// Accepts the clients configured in a [Router] by their key fingerprint or their whole
+/// certificate chain.
+///
+/// Clients configured by a name are never accepted, as anyone can make a certificate
+/// with any name without a certificate authority.
+pub struct PinnedClients {
+    peers: Arc<Router>,
+}
+
+impl PinnedClients {
+    pub fn new(peers: Arc<Router>) -> Self {
+        PinnedClients { peers }
+    }
+}
+
+impl ClientCertVerifier for PinnedClients {
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let spki = core::spki_sha256(end_entity).ok_or(CertificateError::BadEncoding)?;
+        let mut chain = vec![end_entity.clone()];
+        chain.extend_from_slice(intermediates);
+
+        if self.peers.has_peer(&Identity::SpkiSha256(spki))
+            || self.peers.has_peer(&Identity::Chain(chain))
+        {
+            Ok(ClientCertVerified::assertion())
+        } else {
+            Err(not_pinned(spki))
+        }
+    }
+}
+
+fn not_pinned(spki: [u8; 32]) -> rustls::Error {
+    tls::cert_error(NotPinned(Identity::SpkiSha256(spki)))
+}
+
+/// Makes a self-signed certificate for `key`, which must be a PKCS #8 key.
+pub fn self_signed(key: &rustls::PrivateKey) -> anyhow::Result<Certificate> {
+    let key_pair = rcgen::KeyPair::from_der(&key.0)
+        .context("a certificate can only be made for a PKCS #8 key, set `cert`")?;
+    let mut params = rcgen::CertificateParams::new(vec![]);
+    params.alg = key_pair.algorithm();
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, "vqn");
+    params.key_pair = Some(key_pair);
+    let cert = rcgen::Certificate::from_params(params)?;
+
+    Ok(Certificate(cert.serialize_der()?))
+}
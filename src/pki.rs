@@ -0,0 +1,788 @@
+//! A small certificate authority issuing the certificates and keys `conf::Tls` refers to.
+//!
+//! Everything lives in one directory: the CA as `ca-cert.pem` and `ca-key.pem`, the
+//! server as `server-cert.pem` and `server-key.pem`, and every client as
+//! `<name>-cert.pem` and `<name>-key.pem`. Keys are ECDSA P-256 in PKCS #8. Revoked
+//! certificates are listed in `crl.pem`.
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use ip_network::IpNetwork;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, CertificateRevocationList,
+    CertificateRevocationListParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyIdMethod, KeyPair, KeyUsagePurpose, RevokedCertParams, SanType, SerialNumber,
+};
+use time::{Duration, OffsetDateTime};
+use url::Url;
+use x509_parser::extensions::GeneralName;
+
+use crate::conf::{Address, Cidr, Conf, Network};
+use crate::core::Identity;
+
+pub const DEFAULT_CA_DAYS: u32 = 3650;
+pub const DEFAULT_CERT_DAYS: u32 = 825;
+
+const CA_CERT: &str = "ca-cert.pem";
+const CA_KEY: &str = "ca-key.pem";
+const SERVER_CERT: &str = "server-cert.pem";
+const SERVER_KEY: &str = "server-key.pem";
+const CRL: &str = "crl.pem";
+// how long a revocation list claims to be current, it is signed again on every change
+const CRL_DAYS: i64 = 365;
+
+/// Creates a certificate authority in `dir`.
+pub fn init(dir: &Path, common_name: &str, days: u32) -> anyhow::Result<()> {
+    let (cert_path, key_path) = (dir.join(CA_CERT), dir.join(CA_KEY));
+    ensure_absent(&[&cert_path, &key_path])?;
+
+    let mut params = params(common_name, days);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    let ca = Certificate::from_params(params)?;
+
+    write_new(&key_path, &ca.serialize_private_key_pem(), 0o600)?;
+    write_new(&cert_path, &ca.serialize_pem()?, 0o644)
+}
+
+/// Issues the server certificate for the `dns` names and `ips` clients connect to.
+pub fn issue_server(dir: &Path, dns: &[String], ips: &[IpAddr], days: u32) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !dns.is_empty() || !ips.is_empty(),
+        "the server certificate needs at least one --dns name or --ip address"
+    );
+    let (cert_path, key_path) = (dir.join(SERVER_CERT), dir.join(SERVER_KEY));
+    ensure_absent(&[&cert_path, &key_path])?;
+    let ca = load_ca(dir)?;
+
+    let common_name = dns.first().cloned().unwrap_or_else(|| ips[0].to_string());
+    let mut params = params(&common_name, days);
+    params.subject_alt_names = dns
+        .iter()
+        .cloned()
+        .map(SanType::DnsName)
+        .chain(ips.iter().copied().map(SanType::IpAddress))
+        .collect();
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+
+    issue(&ca, params, &cert_path, &key_path)
+}
+
+/// Issues the certificate of the client `name`.
+///
+/// With the configuration file of a server, the client is also added to it with the
+/// next free address of the server's network, and a configuration file for the client
+/// is written next to its certificate. The server's url is taken from its certificate
+/// unless `url` is given.
+pub fn issue_client(
+    dir: &Path,
+    name: &str,
+    days: u32,
+    server_config: Option<&Path>,
+    url: Option<&Url>,
+) -> anyhow::Result<()> {
+    check_name(name)?;
+    let cert_path = dir.join(format!("{name}-cert.pem"));
+    let key_path = dir.join(format!("{name}-key.pem"));
+    let client_config = dir.join(format!("{name}.toml"));
+    let mut paths = vec![cert_path.as_path(), key_path.as_path()];
+    if server_config.is_some() {
+        paths.push(&client_config);
+    }
+    ensure_absent(&paths)?;
+    let ca = load_ca(dir)?;
+
+    // everything that can fail is done before the first file is written
+    let enrollment = server_config
+        .map(|server_config| {
+            let cert_path = crate::absolute(dir)?.join(cert_path.file_name().unwrap());
+            enroll(server_config, name, &cert_path, url)
+        })
+        .transpose()?;
+
+    let mut params = params(name, days);
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    issue(&ca, params, &cert_path, &key_path)?;
+
+    if let Some(enrollment) = enrollment {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&enrollment.server_config)
+            .with_context(|| format!("failed to open {}", enrollment.server_config.display()))?;
+        file.write_all(enrollment.server_block.as_bytes())
+            .with_context(|| format!("failed to write {}", enrollment.server_config.display()))?;
+        let address = match &enrollment.address {
+            Some(address) => format!("address {address}"),
+            None => "an address from its pool".to_owned(),
+        };
+        println!(
+            "added {name} to {} with {address}, send SIGHUP to a running server to apply",
+            enrollment.server_config.display(),
+        );
+
+        write_new(&client_config, &enrollment.client_config, 0o644)?;
+    }
+
+    Ok(())
+}
+
+/// Revokes the certificate of the client `name` by adding it to the revocation list.
+pub fn revoke(dir: &Path, name: &str) -> anyhow::Result<()> {
+    check_name(name)?;
+    let cert_path = dir.join(format!("{name}-cert.pem"));
+    let cert = std::fs::read(&cert_path)
+        .with_context(|| format!("failed to read {}", cert_path.display()))?;
+    let (_, cert) = x509_parser::pem::parse_x509_pem(&cert)
+        .with_context(|| format!("invalid PEM-encoded certificate: {}", cert_path.display()))?;
+    let serial = cert
+        .parse_x509()
+        .with_context(|| format!("invalid certificate: {}", cert_path.display()))?
+        .raw_serial()
+        .to_vec();
+    let ca = load_ca(dir)?;
+    let now = OffsetDateTime::now_utc();
+
+    // the entries of the current list are kept
+    let crl_path = dir.join(CRL);
+    let mut crl_number = 0;
+    let mut revoked_certs = Vec::new();
+    if crl_path.exists() {
+        let crl = std::fs::read(&crl_path)
+            .with_context(|| format!("failed to read {}", crl_path.display()))?;
+        let (_, crl) = x509_parser::pem::parse_x509_pem(&crl)
+            .with_context(|| format!("invalid PEM-encoded CRL: {}", crl_path.display()))?;
+        let (_, crl) = x509_parser::parse_x509_crl(&crl.contents)
+            .with_context(|| format!("invalid CRL: {}", crl_path.display()))?;
+
+        crl_number = crl.crl_number().map_or(0, |number| {
+            number
+                .to_bytes_be()
+                .iter()
+                .fold(0_u64, |n, byte| n << 8 | *byte as u64)
+        });
+        for revoked in crl.iter_revoked_certificates() {
+            anyhow::ensure!(revoked.raw_serial() != serial, "{name} is already revoked");
+            revoked_certs.push(RevokedCertParams {
+                serial_number: SerialNumber::from_slice(revoked.raw_serial()),
+                revocation_time: revoked.revocation_date.to_datetime(),
+                reason_code: None,
+                invalidity_date: None,
+            });
+        }
+    }
+    revoked_certs.push(RevokedCertParams {
+        serial_number: SerialNumber::from_slice(&serial),
+        revocation_time: now,
+        reason_code: None,
+        invalidity_date: None,
+    });
+
+    let crl = CertificateRevocationList::from_params(CertificateRevocationListParams {
+        this_update: now,
+        next_update: now + Duration::days(CRL_DAYS),
+        crl_number: (crl_number + 1).into(),
+        issuing_distribution_point: None,
+        revoked_certs,
+        alg: ca.get_params().alg,
+        key_identifier_method: KeyIdMethod::Sha256,
+    })?;
+
+    // replaced in one step, as servers may read it at any time
+    let tmp_path = dir.join(format!(".{CRL}.tmp"));
+    std::fs::write(&tmp_path, crl.serialize_pem_with_signer(&ca)?)
+        .and_then(|()| std::fs::rename(&tmp_path, &crl_path))
+        .with_context(|| format!("failed to write {}", crl_path.display()))?;
+    println!(
+        "revoked {name} in {}, which servers check with `crl` in their [tls] section",
+        crl_path.display()
+    );
+
+    Ok(())
+}
+
+/// Generates the key `<name>-key.pem` for a tunnel without a certificate authority, and
+/// returns the fingerprint the other side pins it by.
+pub fn genkey(dir: &Path, name: &str) -> anyhow::Result<Identity> {
+    check_name(name)?;
+    let key_path = dir.join(format!("{name}-key.pem"));
+    ensure_absent(&[&key_path])?;
+
+    let key = KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)?;
+    write_new(&key_path, &key.serialize_pem(), 0o600)?;
+
+    Ok(spki_fingerprint(&key.public_key_der()))
+}
+
+/// Returns the key fingerprint of a certificate, or of a PKCS #8 private key.
+pub fn fingerprint(path: &Path) -> anyhow::Result<Identity> {
+    if let Some(cert) = crate::certs(path)?.first() {
+        let spki = crate::core::spki_sha256(cert)
+            .with_context(|| format!("invalid certificate: {}", path.display()))?;
+        return Ok(Identity::SpkiSha256(spki));
+    }
+
+    let key = crate::keys::load(path, None)?;
+    let key = KeyPair::from_der(&key.0)
+        .with_context(|| format!("not a PKCS #8 private key: {}", path.display()))?;
+
+    Ok(spki_fingerprint(&key.public_key_der()))
+}
+
+fn spki_fingerprint(spki: &[u8]) -> Identity {
+    Identity::SpkiSha256(crate::core::digest(spki))
+}
+
+// a client to be added to the configuration of a server
+struct Enrollment {
+    server_config: PathBuf,
+    // the `[[network.client]]` table appended to the server configuration
+    server_block: String,
+    // the address picked for the client, unless the server assigns one out of its pool
+    address: Option<String>,
+    client_config: String,
+}
+
+fn enroll(
+    server_config: &Path,
+    name: &str,
+    cert_path: &Path,
+    url: Option<&Url>,
+) -> anyhow::Result<Enrollment> {
+    let conf = Conf::read(server_config)
+        .with_context(|| format!("failed to read {}", server_config.display()))?;
+    let Network::Server {
+        address: server_address,
+        pool,
+        port,
+        listen,
+        client,
+        mtu,
+        ..
+    } = &conf.network
+    else {
+        anyhow::bail!("{} does not configure a server", server_config.display());
+    };
+
+    let taken: Vec<_> = server_address
+        .iter()
+        .map(|cidr| Cidr(cidr.ip(), max_prefix_len(cidr.ip())))
+        .chain(
+            client
+                .iter()
+                .flat_map(|client| client.allowed_ips.values.clone()),
+        )
+        .collect();
+    let mut address = Vec::new();
+    let mut allowed_ips = Vec::new();
+    let mut routes = Vec::new();
+    for subnet in server_address.iter() {
+        // a server with a pool assigns the client its address when it connects
+        if pool.is_none() {
+            let ip = next_free_ip(subnet, &taken)
+                .with_context(|| format!("no free address left in {subnet}"))?;
+            address.push(Cidr(ip, subnet.1).to_string());
+            allowed_ips.push(Cidr(ip, max_prefix_len(ip)).to_string());
+        }
+        routes.push(IpNetwork::new_truncate(subnet.ip(), subnet.1)?.to_string());
+    }
+    for range in pool.iter().flat_map(Address::iter) {
+        let range = range.network().to_string();
+        if !routes.contains(&range) {
+            routes.push(range);
+        }
+    }
+
+    let url = match url {
+        Some(url) => url.clone(),
+        None => {
+            let port = match (port, listen.as_deref()) {
+                (_, Some([addr, ..])) => addr.port(),
+                (port, _) => port.unwrap_or(crate::DEFAULT_LISTEN_PORT),
+            };
+            let cert = (conf.tls.cert.as_deref())
+                .context("no server certificate to take the url from, pass --url")?;
+            server_url(cert, port)?
+        }
+    };
+
+    let mut server_block = format!(
+        "\n[[network.client]]\n\
+         # {name}, issued by `vqn pki issue-client`\n\
+         client_cert = {}\n",
+        quote(&cert_path.to_string_lossy()),
+    );
+    if !allowed_ips.is_empty() {
+        server_block += &format!("allowed_ips = {}\n", quote(&allowed_ips.join(", ")));
+    }
+    // the leading newline also ends a last line that lacks one
+    let text = std::fs::read_to_string(server_config)
+        .with_context(|| format!("failed to read {}", server_config.display()))?;
+    Conf::parse_from(&(text + &server_block))
+        .with_context(|| format!("failed to add {name} to {}", server_config.display()))?;
+
+    let mut client_config = format!(
+        "[tls]\n\
+         key = {}\n\
+         cert = {}\n\
+         ca_cert = {}\n\
+         \n\
+         [network]\n\
+         role = \"client\"\n",
+        quote(&format!("./{name}-key.pem")),
+        quote(&format!("./{name}-cert.pem")),
+        quote(&format!("./{CA_CERT}")),
+    );
+    if !address.is_empty() {
+        client_config += &format!("address = {}\n", quote(&address.join(", ")));
+    }
+    if let Some(mtu) = mtu {
+        client_config += &format!("mtu = {mtu}\n");
+    }
+    client_config += &format!(
+        "\n\
+         [network.server]\n\
+         url = {}\n\
+         allowed_ips = {}\n",
+        quote(url.as_str()),
+        quote(&routes.join(", ")),
+    );
+
+    Ok(Enrollment {
+        server_config: server_config.to_path_buf(),
+        server_block,
+        address: (!address.is_empty()).then(|| address.join(", ")),
+        client_config,
+    })
+}
+
+// the url of the server, by the first name or address in its certificate
+fn server_url(cert_path: &Path, port: u16) -> anyhow::Result<Url> {
+    let pem = std::fs::read(cert_path)
+        .with_context(|| format!("failed to read {}", cert_path.display()))?;
+    let (_, pem) = x509_parser::pem::parse_x509_pem(&pem)
+        .with_context(|| format!("invalid PEM-encoded certificate: {}", cert_path.display()))?;
+    let cert = pem
+        .parse_x509()
+        .with_context(|| format!("invalid certificate: {}", cert_path.display()))?;
+
+    let names = cert.subject_alternative_name().ok().flatten();
+    let names = names.iter().flat_map(|san| &san.value.general_names);
+    let mut hosts = names.clone().filter_map(|name| match name {
+        GeneralName::DNSName(dns) => Some(dns.to_string()),
+        _ => None,
+    });
+    let host = hosts.next().or_else(|| {
+        names.clone().find_map(|name| match name {
+            GeneralName::IPAddress(&[a, b, c, d]) => Some(Ipv4Addr::new(a, b, c, d).to_string()),
+            GeneralName::IPAddress(ip) => {
+                let ip = <[u8; 16]>::try_from(*ip).ok()?;
+                Some(format!("[{}]", Ipv6Addr::from(ip)))
+            }
+            _ => None,
+        })
+    });
+    let host = host.with_context(|| {
+        format!(
+            "{} has no name or address to connect to, pass --url",
+            cert_path.display()
+        )
+    })?;
+
+    Ok(Url::parse(&format!("https://{host}:{port}"))?)
+}
+
+// the first address of `subnet` that is neither its network nor broadcast address nor
+// within any of `taken`
+fn next_free_ip(subnet: Cidr, taken: &[Cidr]) -> Option<IpAddr> {
+    let width = max_prefix_len(subnet.ip()) as u32;
+    let hosts = 1_u128
+        .checked_shl(width - subnet.1 as u32)
+        .unwrap_or(0)
+        .wrapping_sub(1);
+    let network = match subnet.ip() {
+        IpAddr::V4(ip) => u32::from(ip) as u128,
+        IpAddr::V6(ip) => u128::from(ip),
+    } & !hosts;
+    // IPv6 has no broadcast address
+    let last = match subnet.ip() {
+        IpAddr::V4(_) => hosts.saturating_sub(1),
+        IpAddr::V6(_) => hosts,
+    };
+
+    let taken: Vec<_> = taken
+        .iter()
+        .filter_map(|cidr| IpNetwork::new_truncate(cidr.ip(), cidr.1).ok())
+        .collect();
+    (1..=last)
+        .map(|host| match subnet.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from((network | host) as u32)),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(network | host)),
+        })
+        .find(|ip| !taken.iter().any(|network| network.contains(*ip)))
+}
+
+fn max_prefix_len(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn check_name(name: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)),
+        "names may only contain letters, digits, '.', '_' and '-': {name}"
+    );
+
+    Ok(())
+}
+
+fn params(common_name: &str, days: u32) -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, common_name);
+    // leaves some room for clocks that are behind
+    params.not_before = OffsetDateTime::now_utc() - Duration::hours(1);
+    params.not_after = OffsetDateTime::now_utc() + Duration::days(days.into());
+    params
+}
+
+fn load_ca(dir: &Path) -> anyhow::Result<Certificate> {
+    let (cert_path, key_path) = (dir.join(CA_CERT), dir.join(CA_KEY));
+    let key = std::fs::read_to_string(&key_path).with_context(|| {
+        format!(
+            "failed to read {}, create a certificate authority with `vqn pki init`",
+            key_path.display()
+        )
+    })?;
+    let key = KeyPair::from_pem(&key).with_context(|| {
+        format!(
+            "{} is not an unencrypted PKCS #8 private key",
+            key_path.display()
+        )
+    })?;
+    let cert = std::fs::read_to_string(&cert_path)
+        .with_context(|| format!("failed to read {}", cert_path.display()))?;
+    let params = CertificateParams::from_ca_cert_pem(&cert, key)
+        .with_context(|| format!("invalid certificate: {}", cert_path.display()))?;
+
+    Ok(Certificate::from_params(params)?)
+}
+
+fn issue(
+    ca: &Certificate,
+    params: CertificateParams,
+    cert_path: &Path,
+    key_path: &Path,
+) -> anyhow::Result<()> {
+    let cert = Certificate::from_params(params)?;
+    let pem = cert.serialize_pem_with_signer(ca)?;
+
+    write_new(key_path, &cert.serialize_private_key_pem(), 0o600)?;
+    write_new(cert_path, &pem, 0o644)
+}
+
+fn ensure_absent(paths: &[&Path]) -> anyhow::Result<()> {
+    for path in paths {
+        anyhow::ensure!(!path.exists(), "{} already exists", path.display());
+    }
+
+    Ok(())
+}
+
+fn write_new(path: &Path, contents: &str, mode: u32) -> anyhow::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    file.write_all(contents.as_bytes())
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    println!("wrote {}", path.display());
+
+    Ok(())
+}
+
+fn quote(s: &str) -> String {
+    toml::Value::from(s).to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    use crate::conf::Tls;
+    use crate::core::{self, Router};
+
+    #[test]
+    fn test_next_free_ip() {
+        let subnet: Cidr = "10.10.0.1/24".parse().unwrap();
+        let taken = |s: &str| -> Vec<Cidr> { s.split(',').map(|s| s.parse().unwrap()).collect() };
+
+        assert_eq!(
+            next_free_ip(subnet, &taken("10.10.0.1/32")),
+            Some("10.10.0.2".parse().unwrap())
+        );
+        assert_eq!(
+            next_free_ip(subnet, &taken("10.10.0.1/32,10.10.0.2/32,10.10.0.4/30")),
+            Some("10.10.0.3".parse().unwrap())
+        );
+        // neither the network nor the broadcast address
+        assert_eq!(next_free_ip(subnet, &taken("10.10.0.0/25,10.10.0.128/26,10.10.0.192/27,10.10.0.224/28,10.10.0.240/29,10.10.0.248/30,10.10.0.252/31")), Some("10.10.0.254".parse().unwrap()));
+        assert_eq!(
+            next_free_ip(subnet, &taken("10.10.0.0/25,10.10.0.128/25")),
+            None
+        );
+        assert_eq!(next_free_ip("10.10.0.1/32".parse().unwrap(), &[]), None);
+
+        assert_eq!(
+            next_free_ip("fd00::1/64".parse().unwrap(), &taken("fd00::1/128")),
+            Some("fd00::2".parse().unwrap())
+        );
+        assert_eq!(
+            next_free_ip("fd00::1/127".parse().unwrap(), &taken("fd00::1/128")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_issue_client() {
+        let dir = std::env::temp_dir().join(format!("vqn-pki-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+
+        init(&dir, "test CA", 1).unwrap();
+        issue_server(&dir, &["vqn.example.org".to_owned()], &[], 1).unwrap();
+        let server_config = dir.join("server.toml");
+        std::fs::write(
+            &server_config,
+            r#"
+[tls]
+key = "./server-key.pem"
+cert = "./server-cert.pem"
+ca_cert = "./ca-cert.pem"
+
+[network]
+role = "server"
+address = "10.10.0.1/24, fd00::1/64"
+port = 4433
+
+[[network.client]]
+client_cert = "./bob-cert.pem"
+allowed_ips = "10.10.0.2/32""#,
+        )
+        .unwrap();
+
+        issue_client(&dir, "alice", 1, Some(&server_config), None).unwrap();
+        assert!(issue_client(&dir, "alice", 1, None, None).is_err());
+
+        let Network::Server { client, .. } = Conf::read(&server_config).unwrap().network else {
+            panic!("not a server");
+        };
+        assert_eq!(client.len(), 2);
+        assert_eq!(client[1].client_cert, Some(dir.join("alice-cert.pem")));
+        assert_eq!(
+            client[1].allowed_ips.to_string(),
+            "10.10.0.3/32, fd00::2/128"
+        );
+
+        let conf = Conf::read(&dir.join("alice.toml")).unwrap();
+        let Network::Client {
+            address, server, ..
+        } = &conf.network
+        else {
+            panic!("not a client");
+        };
+        assert_eq!(address.unwrap().to_string(), "10.10.0.3/24, fd00::2/64");
+        assert_eq!(server.url[0].as_str(), "https://vqn.example.org:4433/");
+        assert_eq!(
+            server.allowed_ips.as_ref().unwrap().to_string(),
+            "10.10.0.0/24, fd00::/64"
+        );
+
+        // both ends accept the issued certificates
+        handshake(&tls(&dir, "server", None), &conf.tls).unwrap();
+
+        // a server with a pool assigns the address itself
+        let pool_config = dir.join("pool.toml");
+        std::fs::write(
+            &pool_config,
+            r#"
+[tls]
+key = "./server-key.pem"
+cert = "./server-cert.pem"
+ca_cert = "./ca-cert.pem"
+
+[network]
+role = "server"
+address = "10.10.0.1/24"
+pool = "10.10.0.0/24, fd00::/64"
+
+[[network.client]]
+identity = "cn:bob""#,
+        )
+        .unwrap();
+        issue_client(&dir, "carol", 1, Some(&pool_config), None).unwrap();
+        let Network::Server { client, .. } = Conf::read(&pool_config).unwrap().network else {
+            panic!("not a server");
+        };
+        assert!(client[1].allowed_ips.values.is_empty());
+        let Network::Client {
+            address, server, ..
+        } = Conf::read(&dir.join("carol.toml")).unwrap().network
+        else {
+            panic!("not a client");
+        };
+        assert_eq!(address, None);
+        assert_eq!(
+            server.allowed_ips.as_ref().unwrap().to_string(),
+            "10.10.0.0/24, fd00::/64"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_revoke() {
+        let dir = std::env::temp_dir().join(format!("vqn-pki-revoke-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+
+        init(&dir, "test CA", 1).unwrap();
+        issue_server(&dir, &["vqn.example.org".to_owned()], &[], 1).unwrap();
+        for name in ["alice", "bob", "carol"] {
+            issue_client(&dir, name, 1, None, None).unwrap();
+        }
+        revoke(&dir, "alice").unwrap();
+        revoke(&dir, "bob").unwrap();
+        assert!(revoke(&dir, "bob").is_err());
+        // names never reach outside the directory
+        let e = revoke(&dir, "../carol").unwrap_err();
+        assert!(e.to_string().starts_with("names may only contain"), "{e}");
+
+        let server = tls(&dir, "server", Some(CRL));
+        for (name, revoked) in [("alice", true), ("bob", true), ("carol", false)] {
+            let client = tls(&dir, name, None);
+            assert_eq!(handshake(&server, &client).is_err(), revoked, "{name}");
+            assert_eq!(
+                crate::revoked(
+                    &crate::crls(&server.crl).unwrap(),
+                    &crate::certs(client.cert.as_deref().unwrap()).unwrap()
+                ),
+                revoked,
+                "{name}"
+            );
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pinned() {
+        let dir = std::env::temp_dir().join(format!("vqn-pki-pinned-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+
+        let spki = |identity| match identity {
+            Identity::SpkiSha256(spki) => spki,
+            identity => panic!("not a key fingerprint: {identity}"),
+        };
+        let pinned = |name: &str| {
+            let tls = Tls {
+                key: dir.join(format!("{name}-key.pem")),
+                cert: None,
+                ca_cert: None,
+                key_passphrase_file: None,
+                expiry_warn_days: None,
+                crl: vec![],
+            };
+            (genkey(&dir, name).unwrap(), tls)
+        };
+        let (server_pin, server) = pinned("server");
+        let (alice_pin, alice) = pinned("alice");
+        let (mallory_pin, mallory) = pinned("mallory");
+        assert!(genkey(&dir, "alice").is_err());
+        assert_eq!(fingerprint(&alice.key).unwrap(), alice_pin);
+
+        let peers = Arc::new(Router::default());
+        peers.add_peer(core::Identity::SpkiSha256(spki(alice_pin.clone())), []);
+        let server_tls = || crate::server_crypto(&server, &peers).unwrap();
+        let client_tls = |client, pin| crate::client_crypto(client, Some(pin)).unwrap();
+
+        connect(server_tls(), client_tls(&alice, &server_pin)).unwrap();
+        assert!(connect(server_tls(), client_tls(&mallory, &server_pin)).is_err());
+        // a server without the pinned key
+        assert!(connect(server_tls(), client_tls(&alice, &alice_pin)).is_err());
+
+        // clients added later are accepted by the same configuration
+        let server_tls = server_tls();
+        peers.add_peer(core::Identity::SpkiSha256(spki(mallory_pin)), []);
+        connect(server_tls, client_tls(&mallory, &server_pin)).unwrap();
+
+        assert!(crate::client_crypto(&alice, None).is_err());
+        let cn = "cn:server".parse().unwrap();
+        assert!(crate::client_crypto(&alice, Some(&cn)).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn tls(dir: &Path, name: &str, crl: Option<&str>) -> Tls {
+        Tls {
+            key: dir.join(format!("{name}-key.pem")),
+            cert: Some(dir.join(format!("{name}-cert.pem"))),
+            ca_cert: Some(dir.join(CA_CERT)),
+            key_passphrase_file: None,
+            expiry_warn_days: None,
+            crl: crl.into_iter().map(|crl| dir.join(crl)).collect(),
+        }
+    }
+
+    fn handshake(server: &Tls, client: &Tls) -> Result<(), rustls::Error> {
+        let peers = Arc::new(Router::default());
+        connect(
+            crate::server_crypto(server, &peers).unwrap(),
+            crate::client_crypto(client, None).unwrap(),
+        )
+    }
+
+    fn connect(
+        server_tls: rustls::ServerConfig,
+        client_tls: rustls::ClientConfig,
+    ) -> Result<(), rustls::Error> {
+        let mut server = rustls::ServerConnection::new(server_tls.into())?;
+        let mut client = rustls::ClientConnection::new(
+            client_tls.into(),
+            "vqn.example.org".try_into().unwrap(),
+        )?;
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut buf = Vec::new();
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut &buf[..]).unwrap();
+            server.process_new_packets()?;
+            buf.clear();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut &buf[..]).unwrap();
+            client.process_new_packets()?;
+        }
+
+        Ok(())
+    }
+}
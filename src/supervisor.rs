@@ -0,0 +1,374 @@
+//! Runs the tunnels of a vqn process side by side, one per configuration file.
+//!
+//! Every tunnel has its own tun interface, endpoints and firewall configuration and runs
+//! as a task of its own. A tunnel that fails stops without affecting the others and can
+//! be brought back with `vqn restart`, while vqn exits once none of them is left running.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+use crate::conf::{self, Conf, DEFAULT_TUN_NAME};
+use crate::control;
+
+/// What a tunnel is currently doing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelState {
+    /// Setting up its interface and endpoints.
+    Starting,
+    Running,
+    /// Shut down, e.g. while being restarted.
+    Stopped,
+    Failed(String),
+}
+
+impl fmt::Display for TunnelState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TunnelState::Starting => f.write_str("starting"),
+            TunnelState::Running => f.write_str("running"),
+            TunnelState::Stopped => f.write_str("stopped"),
+            TunnelState::Failed(e) => write!(f, "failed: {e}"),
+        }
+    }
+}
+
+/// The tunnels of this process, by interface name.
+#[derive(Default)]
+pub struct Supervisor {
+    tunnels: Mutex<BTreeMap<String, Slot>>,
+    // notified whenever a tunnel task finishes
+    finished: watch::Sender<()>,
+}
+
+struct Slot {
+    config_path: PathBuf,
+    conf: Arc<watch::Sender<Conf>>,
+    state: TunnelState,
+    control: Option<Arc<control::Tunnel>>,
+    // tells apart the runs of a tunnel that is restarted
+    generation: u64,
+    run: Option<Run>,
+    restarting: bool,
+}
+
+impl Slot {
+    fn active(&self) -> bool {
+        self.run.is_some() || self.restarting
+    }
+}
+
+struct Run {
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Run {
+    async fn stop(self) {
+        let _ = self.shutdown.send(());
+        let _ = self.task.await;
+    }
+}
+
+/// Lets a running tunnel report to its [Supervisor].
+pub struct Handle {
+    supervisor: Arc<Supervisor>,
+    name: String,
+    generation: u64,
+}
+
+impl Handle {
+    /// Makes the tunnel available on the control socket, marking it as running.
+    pub fn register(&self, tunnel: control::Tunnel) {
+        self.supervisor.update(self, |slot| {
+            slot.control = Some(Arc::new(tunnel));
+            slot.state = TunnelState::Running;
+        });
+    }
+}
+
+impl Supervisor {
+    pub fn new() -> Arc<Self> {
+        Arc::default()
+    }
+
+    /// Starts the tunnel configured by `conf`, which was read from `config_path`.
+    pub fn start(self: &Arc<Self>, config_path: PathBuf, conf: Conf) -> anyhow::Result<()> {
+        let name = conf.network.name().unwrap_or(DEFAULT_TUN_NAME).to_owned();
+
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let generation = match tunnels.get(&name) {
+            Some(slot) if slot.run.is_some() => anyhow::bail!("{name} is already running"),
+            Some(slot) => slot.generation + 1,
+            None => 0,
+        };
+        let others: Vec<_> = tunnels
+            .iter()
+            .filter(|(other, slot)| **other != name && slot.run.is_some())
+            .map(|(_, slot)| slot.conf.borrow().clone())
+            .collect();
+        conf::check_tunnels(others.iter().chain([&conf]))?;
+
+        let live_conf = Arc::new(watch::channel(conf.clone()).0);
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let handle = Handle {
+            supervisor: Arc::clone(self),
+            name: name.clone(),
+            generation,
+        };
+        let span = tracing::info_span!("tunnel", name = %name);
+        let task = tokio::spawn(
+            {
+                let live_conf = Arc::clone(&live_conf);
+                async move {
+                    let result = crate::run_tunnel(conf, live_conf, &handle, shutdown_rx).await;
+                    handle.supervisor.finished(&handle, result);
+                }
+            }
+            .instrument(span),
+        );
+
+        tunnels.insert(
+            name,
+            Slot {
+                config_path,
+                conf: live_conf,
+                state: TunnelState::Starting,
+                control: None,
+                generation,
+                run: Some(Run { shutdown, task }),
+                restarting: false,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn finished(&self, handle: &Handle, result: anyhow::Result<()>) {
+        self.update(handle, |slot| {
+            slot.control = None;
+            slot.run = None;
+            slot.state = match result {
+                Ok(()) => TunnelState::Stopped,
+                Err(e) => {
+                    tracing::error!("{e:#}");
+                    TunnelState::Failed(format!("{e:#}"))
+                }
+            };
+        });
+        self.finished.send_replace(());
+    }
+
+    // updates the slot of `handle`, unless the tunnel has been restarted since
+    fn update(&self, handle: &Handle, f: impl FnOnce(&mut Slot)) {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        if let Some(slot) = tunnels
+            .get_mut(&handle.name)
+            .filter(|slot| slot.generation == handle.generation)
+        {
+            f(slot);
+        }
+    }
+
+    /// Stops the tunnel on `name` and starts it again, re-reading its configuration file.
+    /// `name` may be omitted if there is only one tunnel.
+    pub async fn restart(self: &Arc<Self>, name: Option<&str>) -> anyhow::Result<()> {
+        let (name, config_path, run) = {
+            let mut tunnels = self.tunnels.lock().unwrap();
+            let name = select(&tunnels, name)?;
+            let slot = tunnels.get_mut(&name).unwrap();
+            anyhow::ensure!(!slot.restarting, "{name} is already being restarted");
+            slot.restarting = true;
+            (name, slot.config_path.clone(), slot.run.take())
+        };
+
+        if let Some(run) = run {
+            tracing::info!("stopping {name}");
+            run.stop().await;
+        }
+
+        let result = Conf::read(&config_path).and_then(|conf| {
+            anyhow::ensure!(
+                conf.network.name().unwrap_or(DEFAULT_TUN_NAME) == name,
+                "the interface name in {} changed, which requires restarting vqn",
+                config_path.display()
+            );
+            tracing::info!("starting {name}");
+            self.start(config_path, conf)
+        });
+
+        if let Some(slot) = self.tunnels.lock().unwrap().get_mut(&name) {
+            slot.restarting = false;
+        }
+        // a tunnel that could not be started again may have been the last one
+        self.finished.send_replace(());
+
+        result
+    }
+
+    /// Stops all tunnels.
+    pub async fn shutdown(&self) {
+        let runs: Vec<_> = {
+            let mut tunnels = self.tunnels.lock().unwrap();
+            tunnels
+                .values_mut()
+                .filter_map(|slot| slot.run.take())
+                .collect()
+        };
+
+        futures::future::join_all(runs.into_iter().map(Run::stop)).await;
+    }
+
+    /// Waits until no tunnel is left running, returning an error if any of them failed.
+    pub async fn wait(&self) -> anyhow::Result<()> {
+        let mut finished = self.finished.subscribe();
+        loop {
+            {
+                let tunnels = self.tunnels.lock().unwrap();
+                if !tunnels.values().any(Slot::active) {
+                    let failed = tunnels
+                        .values()
+                        .any(|slot| matches!(slot.state, TunnelState::Failed(_)));
+                    return match failed {
+                        true => Err(anyhow!("no tunnel left running")),
+                        false => Ok(()),
+                    };
+                }
+            }
+            let _ = finished.changed().await;
+        }
+    }
+
+    /// Re-reads the configuration file of every running tunnel, see [crate::update_conf].
+    pub async fn reload(&self) {
+        let running: Vec<_> = {
+            let tunnels = self.tunnels.lock().unwrap();
+            tunnels
+                .values()
+                .filter(|slot| slot.state == TunnelState::Running)
+                .map(|slot| (slot.config_path.clone(), Arc::clone(&slot.conf)))
+                .collect()
+        };
+
+        for (config_path, conf) in running {
+            tracing::info!("reloading {}", config_path.display());
+            // reads the file and reconciles the firewall
+            let result = tokio::task::spawn_blocking({
+                let config_path = config_path.clone();
+                move || crate::update_conf(&conf, |_| Conf::read(&config_path))
+            })
+            .await
+            .expect("reloading the configuration panicked");
+            if let Err(e) = result {
+                tracing::error!("failed to reload {}: {e:#}", config_path.display());
+            }
+        }
+    }
+
+    /// Returns the running tunnel on `name`, which may be omitted if there is only one
+    /// tunnel.
+    pub fn tunnel(&self, name: Option<&str>) -> anyhow::Result<Arc<control::Tunnel>> {
+        let tunnels = self.tunnels.lock().unwrap();
+        let name = select(&tunnels, name)?;
+        let slot = &tunnels[&name];
+
+        slot.control
+            .clone()
+            .ok_or_else(|| anyhow!("{name} is {}", slot.state))
+    }
+
+    /// Waits until `tunnel` is no longer running, e.g. because it was restarted.
+    pub async fn stopped(&self, tunnel: &Arc<control::Tunnel>) {
+        let mut finished = self.finished.subscribe();
+        loop {
+            let running = self.tunnels.lock().unwrap().values().any(|slot| {
+                slot.control
+                    .as_ref()
+                    .is_some_and(|control| Arc::ptr_eq(control, tunnel))
+            });
+            if !running {
+                return;
+            }
+            let _ = finished.changed().await;
+        }
+    }
+
+    /// Returns every tunnel with its state and, if it is running, its control interface.
+    pub fn tunnels(&self) -> Vec<(String, TunnelState, Option<Arc<control::Tunnel>>)> {
+        let tunnels = self.tunnels.lock().unwrap();
+        tunnels
+            .iter()
+            .map(|(name, slot)| (name.clone(), slot.state.clone(), slot.control.clone()))
+            .collect()
+    }
+}
+
+fn select(tunnels: &BTreeMap<String, Slot>, name: Option<&str>) -> anyhow::Result<String> {
+    match name {
+        Some(name) if tunnels.contains_key(name) => Ok(name.to_owned()),
+        Some(name) => anyhow::bail!("no tunnel on {name}"),
+        None if tunnels.len() == 1 => Ok(tunnels.keys().next().unwrap().clone()),
+        None => {
+            let names: Vec<_> = tunnels.keys().map(String::as_str).collect();
+            anyhow::bail!(
+                "vqn runs several tunnels ({}), select one with --interface",
+                names.join(", ")
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+impl Supervisor {
+    // adds `tunnel`, configured by `conf`, as running without starting it, returning the
+    // handle it reports with
+    pub(crate) fn add_running(
+        self: &Arc<Self>,
+        conf: Arc<watch::Sender<Conf>>,
+        tunnel: control::Tunnel,
+    ) -> Handle {
+        let name = conf
+            .borrow()
+            .network
+            .name()
+            .unwrap_or(DEFAULT_TUN_NAME)
+            .to_owned();
+        let (shutdown, _) = oneshot::channel();
+        let slot = Slot {
+            config_path: PathBuf::new(),
+            conf,
+            state: TunnelState::Starting,
+            control: None,
+            generation: 0,
+            run: Some(Run {
+                shutdown,
+                task: tokio::spawn(async {}),
+            }),
+            restarting: false,
+        };
+        self.tunnels.lock().unwrap().insert(name.clone(), slot);
+
+        let handle = Handle {
+            supervisor: Arc::clone(self),
+            name,
+            generation: 0,
+        };
+        handle.register(tunnel);
+        handle
+    }
+}
+
+#[cfg(test)]
+impl Handle {
+    // reports the tunnel as stopped
+    pub(crate) fn stop(&self) {
+        self.supervisor.finished(self, Ok(()));
+    }
+}
@@ -0,0 +1,27 @@
+//! Errors of the certificate verifiers wrapping those of rustls.
+use std::fmt;
+use std::sync::Arc;
+
+use rustls::CertificateError;
+
+// rustls shows certificate errors with `Debug`, so ours are shown that way as well
+struct CertError<T>(T);
+
+impl<T: fmt::Display> fmt::Debug for CertError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for CertError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<T: fmt::Display> std::error::Error for CertError<T> {}
+
+/// Rejects a certificate for `reason`, which handshake errors show as it is displayed.
+pub fn cert_error(reason: impl fmt::Display + Send + Sync + 'static) -> rustls::Error {
+    CertificateError::Other(Arc::new(CertError(reason))).into()
+}